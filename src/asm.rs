@@ -0,0 +1,548 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::mem;
+
+const START_ADDRESS: usize = 0x200;
+const MAX_CONSTANT_DEPTH: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub struct AsmError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl AsmError {
+    fn new(line: usize, message: String) -> AsmError {
+        AsmError { line, message }
+    }
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for AsmError {}
+
+#[derive(Debug)]
+enum Operand {
+    V(u16),
+    Range(u16, u16),
+    I,
+    IndirectI,
+    Dt,
+    St,
+    K,
+    F,
+    Hf,
+    B,
+    R,
+    Long(String),
+    Value(String),
+}
+
+struct Statement {
+    line: usize,
+    mnemonic: String,
+    operands: Vec<Operand>,
+}
+
+/// Assembles source using the disassembler syntax into a ROM loaded at 0x200. Besides
+/// instructions it accepts `label:` definitions, `NAME = value` constants, `db`/`dw` data
+/// and `;` comments. Values can be decimal, `0x` hex, `0b` binary, symbols, or sums of them.
+pub fn assemble(source: &str) -> Result<Vec<u8>, AsmError> {
+    let mut assembler = Assembler {
+        labels: HashMap::new(),
+        constants: HashMap::new(),
+    };
+    let mut statements = Vec::new();
+    let mut address = START_ADDRESS;
+
+    // First pass: collect symbols and lay out statements
+    for (index, text) in source.lines().enumerate() {
+        let line = index + 1;
+        let mut text = text.split(';').next().unwrap_or("").trim();
+
+        while let Some(colon) = text.find(':') {
+            let name = text[..colon].trim();
+
+            if !is_identifier(name) {
+                break;
+            }
+
+            assembler.define(name, line)?;
+            assembler.labels.insert(name.to_string(), address as i64);
+            text = text[colon + 1..].trim();
+        }
+
+        if text.is_empty() {
+            continue;
+        }
+
+        if let Some(equals) = text.find('=') {
+            let name = text[..equals].trim();
+
+            if !is_identifier(name) {
+                return Err(AsmError::new(
+                    line,
+                    format!("Invalid constant name: {}", name),
+                ));
+            }
+
+            assembler.define(name, line)?;
+            assembler.constants.insert(
+                name.to_string(),
+                (text[equals + 1..].trim().to_string(), line),
+            );
+            continue;
+        }
+
+        let (mnemonic, rest) = match text.find(char::is_whitespace) {
+            Some(space) => (&text[..space], text[space..].trim()),
+            None => (text, ""),
+        };
+        let operands = if rest.is_empty() {
+            Vec::new()
+        } else {
+            rest.split(',')
+                .map(|operand| parse_operand(operand.trim(), line))
+                .collect::<Result<Vec<_>, _>>()?
+        };
+        let statement = Statement {
+            line,
+            mnemonic: mnemonic.to_uppercase(),
+            operands,
+        };
+
+        address += statement_size(&statement);
+        statements.push(statement);
+    }
+
+    // Second pass: encode with every symbol known
+    let mut rom = Vec::new();
+
+    for statement in &statements {
+        assembler.encode(statement, &mut rom)?;
+    }
+
+    Ok(rom)
+}
+
+struct Assembler {
+    labels: HashMap<String, i64>,
+    constants: HashMap<String, (String, usize)>,
+}
+
+impl Assembler {
+    fn define(&self, name: &str, line: usize) -> Result<(), AsmError> {
+        if self.labels.contains_key(name) || self.constants.contains_key(name) {
+            return Err(AsmError::new(line, format!("Duplicate symbol: {}", name)));
+        }
+
+        if parse_keyword(&name.to_uppercase()).is_some() {
+            return Err(AsmError::new(line, format!("Reserved name: {}", name)));
+        }
+
+        Ok(())
+    }
+
+    fn encode(&self, statement: &Statement, rom: &mut Vec<u8>) -> Result<(), AsmError> {
+        let line = statement.line;
+
+        match statement.mnemonic.as_str() {
+            "DB" => {
+                for operand in &statement.operands {
+                    rom.push(self.value(operand, 8, line)? as u8);
+                }
+
+                return Ok(());
+            }
+            "DW" => {
+                for operand in &statement.operands {
+                    let word = self.value(operand, 16, line)?;
+                    rom.extend_from_slice(&word.to_be_bytes());
+                }
+
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let opcode = self.opcode(statement)?;
+        rom.extend_from_slice(&opcode.to_be_bytes());
+
+        if let [_, Operand::Long(expression)] = statement.operands.as_slice() {
+            let address = self.evaluate(expression, line, 0)?;
+            rom.extend_from_slice(&self.check(address, 16, line)?.to_be_bytes());
+        }
+
+        Ok(())
+    }
+
+    fn opcode(&self, statement: &Statement) -> Result<u16, AsmError> {
+        use self::Operand::*;
+
+        let line = statement.line;
+        let addr = |operand: &Operand| self.value(operand, 12, line);
+        let byte = |operand: &Operand| self.value(operand, 8, line);
+        let nibble = |operand: &Operand| self.value(operand, 4, line);
+
+        let opcode = match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+            ("CLS", []) => 0x00E0,
+            ("RET", []) => 0x00EE,
+            ("SCD", [n]) => 0x00C0 | nibble(n)?,
+            ("SCU", [n]) => 0x00D0 | nibble(n)?,
+            ("SCR", []) => 0x00FB,
+            ("SCL", []) => 0x00FC,
+            ("EXIT", []) => 0x00FD,
+            ("LOW", []) => 0x00FE,
+            ("HIGH", []) => 0x00FF,
+            ("SYS", [a]) => addr(a)?,
+            ("JP", [V(0), a]) => 0xB000 | addr(a)?,
+            ("JP", [a]) => 0x1000 | addr(a)?,
+            ("CALL", [a]) => 0x2000 | addr(a)?,
+            ("SE", [V(x), V(y)]) => 0x5000 | x << 8 | y << 4,
+            ("SE", [V(x), b]) => 0x3000 | x << 8 | byte(b)?,
+            ("SNE", [V(x), V(y)]) => 0x9000 | x << 8 | y << 4,
+            ("SNE", [V(x), b]) => 0x4000 | x << 8 | byte(b)?,
+            ("LD", [IndirectI, Range(x, y)]) => 0x5002 | x << 8 | y << 4,
+            ("LD", [Range(x, y), IndirectI]) => 0x5003 | x << 8 | y << 4,
+            ("LD", [V(x), V(y)]) => 0x8000 | x << 8 | y << 4,
+            ("LD", [V(x), Dt]) => 0xF007 | x << 8,
+            ("LD", [V(x), K]) => 0xF00A | x << 8,
+            ("LD", [V(x), IndirectI]) => 0xF065 | x << 8,
+            ("LD", [V(x), R]) => 0xF085 | x << 8,
+            ("LD", [V(x), b]) => 0x6000 | x << 8 | byte(b)?,
+            ("LD", [I, Long(_)]) => 0xF000,
+            ("LD", [I, a]) => 0xA000 | addr(a)?,
+            ("LD", [Dt, V(x)]) => 0xF015 | x << 8,
+            ("LD", [St, V(x)]) => 0xF018 | x << 8,
+            ("LD", [F, V(x)]) => 0xF029 | x << 8,
+            ("LD", [Hf, V(x)]) => 0xF030 | x << 8,
+            ("LD", [B, V(x)]) => 0xF033 | x << 8,
+            ("LD", [IndirectI, V(x)]) => 0xF055 | x << 8,
+            ("LD", [R, V(x)]) => 0xF075 | x << 8,
+            ("ADD", [I, V(x)]) => 0xF01E | x << 8,
+            ("ADD", [V(x), V(y)]) => 0x8004 | x << 8 | y << 4,
+            ("ADD", [V(x), b]) => 0x7000 | x << 8 | byte(b)?,
+            ("OR", [V(x), V(y)]) => 0x8001 | x << 8 | y << 4,
+            ("AND", [V(x), V(y)]) => 0x8002 | x << 8 | y << 4,
+            ("XOR", [V(x), V(y)]) => 0x8003 | x << 8 | y << 4,
+            ("SUB", [V(x), V(y)]) => 0x8005 | x << 8 | y << 4,
+            ("SHR", [V(x)]) => 0x8006 | x << 8,
+            ("SHR", [V(x), V(y)]) => 0x8006 | x << 8 | y << 4,
+            ("SUBN", [V(x), V(y)]) => 0x8007 | x << 8 | y << 4,
+            ("SHL", [V(x)]) => 0x800E | x << 8,
+            ("SHL", [V(x), V(y)]) => 0x800E | x << 8 | y << 4,
+            ("RND", [V(x), b]) => 0xC000 | x << 8 | byte(b)?,
+            ("DRW", [V(x), V(y), n]) => 0xD000 | x << 8 | y << 4 | nibble(n)?,
+            ("SKP", [V(x)]) => 0xE09E | x << 8,
+            ("SKNP", [V(x)]) => 0xE0A1 | x << 8,
+            ("PLANE", [n]) => 0xF001 | self.value(n, 2, line)? << 8,
+            ("AUDIO", []) => 0xF002,
+            ("PITCH", [V(x)]) => 0xF03A | x << 8,
+            (mnemonic, _) if is_mnemonic(mnemonic) => {
+                return Err(AsmError::new(
+                    line,
+                    format!("Invalid operands for {}", mnemonic),
+                ))
+            }
+            (mnemonic, _) => {
+                return Err(AsmError::new(
+                    line,
+                    format!("Unknown instruction: {}", mnemonic),
+                ))
+            }
+        };
+
+        Ok(opcode)
+    }
+
+    fn value(&self, operand: &Operand, bits: u32, line: usize) -> Result<u16, AsmError> {
+        match operand {
+            Operand::Value(expression) => {
+                let value = self.evaluate(expression, line, 0)?;
+                self.check(value, bits, line)
+            }
+            _ => Err(AsmError::new(
+                line,
+                format!("Expected a value, found {:?}", operand),
+            )),
+        }
+    }
+
+    fn check(&self, value: i64, bits: u32, line: usize) -> Result<u16, AsmError> {
+        if value < 0 || value >= 1 << bits {
+            return Err(AsmError::new(
+                line,
+                format!("Value {} does not fit in {} bits", value, bits),
+            ));
+        }
+
+        Ok(value as u16)
+    }
+
+    // Sums and differences of numbers and symbols
+    fn evaluate(&self, expression: &str, line: usize, depth: usize) -> Result<i64, AsmError> {
+        if depth > MAX_CONSTANT_DEPTH {
+            return Err(AsmError::new(
+                line,
+                "Circular constant definition".to_string(),
+            ));
+        }
+
+        let mut total: i64 = 0;
+        let mut sign = 1;
+        let mut term = String::new();
+
+        for c in expression.chars().chain(Some('+')) {
+            match c {
+                '+' | '-' => {
+                    let term = mem::take(&mut term);
+                    let term = term.trim();
+
+                    if term.is_empty() {
+                        return Err(AsmError::new(
+                            line,
+                            format!("Invalid expression: {}", expression),
+                        ));
+                    }
+
+                    let value = self.term(term, line, depth)?;
+
+                    total = value
+                        .checked_mul(sign)
+                        .and_then(|value| total.checked_add(value))
+                        .ok_or_else(|| {
+                            AsmError::new(line, format!("Value out of range: {}", expression))
+                        })?;
+                    sign = if c == '-' { -1 } else { 1 };
+                }
+                _ => term.push(c),
+            }
+        }
+
+        Ok(total)
+    }
+
+    fn term(&self, term: &str, line: usize, depth: usize) -> Result<i64, AsmError> {
+        // Symbols cannot start with a digit, so this is a number even when it does not parse
+        if term.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse_number(term)
+                .ok_or_else(|| AsmError::new(line, format!("Invalid number: {}", term)));
+        }
+
+        if let Some(&address) = self.labels.get(term) {
+            return Ok(address);
+        }
+
+        if let Some((expression, _)) = self.constants.get(term) {
+            return self.evaluate(expression, line, depth + 1);
+        }
+
+        Err(AsmError::new(line, format!("Undefined symbol: {}", term)))
+    }
+}
+
+fn statement_size(statement: &Statement) -> usize {
+    match (statement.mnemonic.as_str(), statement.operands.as_slice()) {
+        ("DB", operands) => operands.len(),
+        ("DW", operands) => operands.len() * 2,
+        (_, [_, Operand::Long(_)]) => 4,
+        _ => 2,
+    }
+}
+
+fn parse_operand(text: &str, line: usize) -> Result<Operand, AsmError> {
+    let upper = text.to_uppercase();
+
+    if let Some(operand) = parse_keyword(&upper) {
+        return Ok(operand);
+    }
+
+    if let Some(dash) = upper.find('-') {
+        if let (Some(x), Some(y)) = (
+            parse_register(upper[..dash].trim()),
+            parse_register(upper[dash + 1..].trim()),
+        ) {
+            return Ok(Operand::Range(x, y));
+        }
+    }
+
+    if upper.starts_with("LONG ") {
+        return Ok(Operand::Long(text[5..].trim().to_string()));
+    }
+
+    if text.is_empty() {
+        return Err(AsmError::new(line, "Missing operand".to_string()));
+    }
+
+    Ok(Operand::Value(text.to_string()))
+}
+
+fn parse_keyword(upper: &str) -> Option<Operand> {
+    match upper {
+        "I" => Some(Operand::I),
+        "[I]" => Some(Operand::IndirectI),
+        "DT" => Some(Operand::Dt),
+        "ST" => Some(Operand::St),
+        "K" => Some(Operand::K),
+        "F" => Some(Operand::F),
+        "HF" => Some(Operand::Hf),
+        "B" => Some(Operand::B),
+        "R" => Some(Operand::R),
+        _ => parse_register(upper).map(Operand::V),
+    }
+}
+
+fn parse_register(upper: &str) -> Option<u16> {
+    if upper.len() == 2 && upper.starts_with('V') {
+        u16::from_str_radix(&upper[1..], 16).ok()
+    } else {
+        None
+    }
+}
+
+fn parse_number(term: &str) -> Option<i64> {
+    let lower = term.to_lowercase();
+
+    if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16).ok()
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2).ok()
+    } else {
+        lower.parse().ok()
+    }
+}
+
+fn is_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    match chars.next() {
+        Some(c) if c.is_ascii_alphabetic() || c == '_' => {
+            chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+        }
+        _ => false,
+    }
+}
+
+fn is_mnemonic(mnemonic: &str) -> bool {
+    [
+        "CLS", "RET", "SCD", "SCU", "SCR", "SCL", "EXIT", "LOW", "HIGH", "SYS", "JP", "CALL", "SE",
+        "SNE", "LD", "ADD", "OR", "AND", "XOR", "SUB", "SHR", "SUBN", "SHL", "RND", "DRW", "SKP",
+        "SKNP", "PLANE", "AUDIO", "PITCH",
+    ]
+    .contains(&mnemonic)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm;
+    use crate::vm::VM;
+
+    #[test]
+    fn instructions() {
+        let source = "
+            CLS
+            LD V1, 0x2A
+            DRW V0, V1, 5
+            LD I, LONG 0x1234
+            LD [I], V1-V3
+            SHR V4
+        ";
+
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                0x00, 0xE0, 0x61, 0x2A, 0xD0, 0x15, 0xF0, 0x00, 0x12, 0x34, 0x51, 0x32, 0x84, 0x06
+            ])
+        );
+    }
+
+    #[test]
+    fn labels_constants_and_data() {
+        let source = "
+            SPEED = 3           ; Pixels per frame
+            start:
+                LD I, sprite + 1
+                ADD V0, SPEED
+                JP start
+            sprite: db 0xF0, 0b10010000
+                    dw 0x1234
+        ";
+
+        assert_eq!(
+            assemble(source),
+            Ok(vec![
+                0xA2, 0x07, 0x70, 0x03, 0x12, 0x00, 0xF0, 0x90, 0x12, 0x34
+            ])
+        );
+    }
+
+    #[test]
+    fn errors_report_line() {
+        assert_eq!(
+            assemble("CLS\nFOO V1").unwrap_err(),
+            AsmError::new(2, "Unknown instruction: FOO".to_string())
+        );
+        assert_eq!(
+            assemble("LD V1, 0x100").unwrap_err().message,
+            "Value 256 does not fit in 8 bits"
+        );
+        assert_eq!(
+            assemble("\n\nJP nowhere").unwrap_err(),
+            AsmError::new(3, "Undefined symbol: nowhere".to_string())
+        );
+        assert_eq!(
+            assemble("a:\na:").unwrap_err(),
+            AsmError::new(2, "Duplicate symbol: a".to_string())
+        );
+        assert_eq!(
+            assemble("SKP 5").unwrap_err().message,
+            "Invalid operands for SKP"
+        );
+        assert_eq!(
+            assemble("CLS\nLD V0, 0x7FFFFFFFFFFFFFFF + 1").unwrap_err(),
+            AsmError::new(2, "Value out of range: 0x7FFFFFFFFFFFFFFF + 1".to_string())
+        );
+        assert_eq!(
+            assemble("LD V0, 0 - 0x7FFFFFFFFFFFFFFF - 2")
+                .unwrap_err()
+                .message,
+            "Value out of range: 0 - 0x7FFFFFFFFFFFFFFF - 2"
+        );
+        assert_eq!(
+            assemble("LD V0, 99999999999999999999").unwrap_err(),
+            AsmError::new(1, "Invalid number: 99999999999999999999".to_string())
+        );
+        assert_eq!(
+            assemble("LD I, 0x12G").unwrap_err().message,
+            "Invalid number: 0x12G"
+        );
+    }
+
+    #[test]
+    fn disassembler_round_trip() {
+        let rom = vec![
+            0x00, 0xE0, 0xA2, 0x0E, 0x60, 0x05, 0x61, 0x0A, 0xD0, 0x15, 0x22, 0x0C, 0x12, 0x0A,
+            0x00, 0xEE, 0xF0, 0x90,
+        ];
+
+        assert_eq!(assemble(&disasm::disassemble(&rom)), Ok(rom));
+    }
+
+    #[test]
+    fn output_loads_in_vm() {
+        let rom = assemble("LD V0, 7\nADD V0, V0").unwrap();
+        let mut vm = VM::initialize(false);
+
+        vm.load_rom(&rom).unwrap();
+        vm.emulate_cycle().unwrap();
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(vm.v()[0x0], 14);
+    }
+}
@@ -0,0 +1,167 @@
+use crate::vm::{Mode, VM};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioSettings {
+    pub volume: f32,    // Amplitude from 0.0 to 1.0
+    pub frequency: f32, // Pitch of the buzzer in Hz
+    pub muted: bool,
+}
+
+impl Default for AudioSettings {
+    fn default() -> AudioSettings {
+        AudioSettings {
+            volume: 0.25,
+            frequency: 440.0,
+            muted: false,
+        }
+    }
+}
+
+// XO-CHIP pattern and the rate its bits are played at
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Pattern {
+    bits: [u8; 16],
+    rate: f64,
+}
+
+/// Generates the buzzer sound, a square wave or the XO-CHIP audio pattern while the sound
+/// timer runs. `update` follows the VM once per frame and `fill` produces the samples
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tone {
+    pub settings: AudioSettings,
+    playing: bool,            // Sound timer was non-zero at the last update
+    pattern: Option<Pattern>, // Played instead of the square wave once a program loads one
+    phase: f64,               // Position in the current period, from 0.0 to 1.0
+}
+
+impl Tone {
+    pub fn new(settings: AudioSettings) -> Tone {
+        Tone {
+            settings,
+            playing: false,
+            pattern: None,
+            phase: 0.0,
+        }
+    }
+
+    pub fn update(&mut self, vm: &VM) {
+        self.playing = vm.sound_active();
+
+        let bits = *vm.audio_pattern();
+        self.pattern = if vm.mode() == Mode::XoChip && bits != [0; 16] {
+            Some(Pattern {
+                bits,
+                rate: vm.playback_rate(),
+            })
+        } else {
+            None
+        };
+    }
+
+    pub fn playing(&self) -> bool {
+        self.playing && !self.settings.muted
+    }
+
+    /// Writes mono samples between -volume and volume, silence when the buzzer is off
+    pub fn fill(&mut self, sample_rate: u32, output: &mut [f32]) {
+        if !self.playing() {
+            self.phase = 0.0;
+            output.iter_mut().for_each(|sample| *sample = 0.0);
+            return;
+        }
+
+        let volume = self.settings.volume.clamp(0.0, 1.0);
+        let step = match self.pattern {
+            Some(pattern) => pattern.rate / 128.0 / sample_rate as f64,
+            None => self.settings.frequency as f64 / sample_rate as f64,
+        };
+
+        for sample in output.iter_mut() {
+            let high = match self.pattern {
+                Some(pattern) => {
+                    let bit = (self.phase * 128.0) as usize;
+                    pattern.bits[bit / 8] & (0x80 >> (bit % 8)) != 0
+                }
+                None => self.phase < 0.5,
+            };
+
+            *sample = if high { volume } else { -volume };
+            self.phase = (self.phase + step).fract();
+        }
+    }
+}
+
+impl Default for Tone {
+    fn default() -> Tone {
+        Tone::new(AudioSettings::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playing_vm(mode: Mode) -> VM {
+        let mut vm = VM::with_mode(mode, false);
+        vm.set_sound_timer(10);
+        vm
+    }
+
+    #[test]
+    fn silent_without_sound_timer() {
+        let mut tone = Tone::default();
+        let mut samples = [1.0; 64];
+        tone.update(&VM::initialize(false));
+        tone.fill(44100, &mut samples);
+
+        assert!(!tone.playing());
+        assert!(samples.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn square_wave() {
+        let mut tone = Tone::new(AudioSettings {
+            volume: 0.5,
+            frequency: 1000.0,
+            muted: false,
+        });
+        let mut samples = [0.0; 8];
+        tone.update(&playing_vm(Mode::Chip8));
+        tone.fill(8000, &mut samples);
+
+        assert_eq!(samples, [0.5, 0.5, 0.5, 0.5, -0.5, -0.5, -0.5, -0.5]);
+    }
+
+    #[test]
+    fn muted() {
+        let mut tone = Tone::new(AudioSettings {
+            muted: true,
+            ..AudioSettings::default()
+        });
+        let mut samples = [1.0; 8];
+        tone.update(&playing_vm(Mode::Chip8));
+        tone.fill(8000, &mut samples);
+
+        assert!(samples.iter().all(|&sample| sample == 0.0));
+    }
+
+    #[test]
+    fn xo_chip_pattern() {
+        let mut vm = playing_vm(Mode::XoChip);
+        vm.load_rom(&[0xF0, 0x02]).unwrap(); // Load the pattern from I
+        vm.set_i(0x300);
+        vm.memory[0x300] = 0xA0;
+        vm.emulate_cycle().unwrap();
+
+        let mut tone = Tone::new(AudioSettings {
+            volume: 1.0,
+            ..AudioSettings::default()
+        });
+        let mut samples = [0.0; 4];
+        tone.update(&vm);
+        // One bit per sample at the default 4000 Hz playback rate
+        tone.fill(4000, &mut samples);
+
+        assert_eq!(samples, [1.0, -1.0, 1.0, -1.0]);
+    }
+}
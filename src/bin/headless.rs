@@ -0,0 +1,513 @@
+extern crate emuchip_8;
+
+use emuchip_8::movie::{Movie, MovieError};
+use emuchip_8::quirks::{self, Quirks, QuirksConfig};
+use emuchip_8::recording::Recorder;
+use emuchip_8::screenshot::{self, Style};
+use emuchip_8::vm::{Mode, VmError, DEFAULT_CLOCK_SPEED, TIMER_FREQUENCY, VM};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::process;
+
+const USAGE: &str = "Usage: emuchip-8-headless ROM [--frames N | --cycles N] \
+                     [--key FRAME:KEY[:FRAMES]]... [--keys FILE] \
+                     [--speed INSTRUCTIONS_PER_SECOND] [--mode chip8|schip|xochip] \
+                     [--quirks FILE|vip|chip48|schip|xochip] [--quirk NAME=0|1]... \
+                     [--ascii] [--png FILE] [--gif FILE] \
+                     [--scale N] [--fg RRGGBB] [--bg RRGGBB] [--seed N] [--movie FILE] \
+                     [--record-movie FILE] [--debug]";
+const DEFAULT_FRAMES: u64 = 10 * TIMER_FREQUENCY as u64;
+
+#[derive(Clone, Copy)]
+enum Limit {
+    Frames(u64),
+    Cycles(u64),
+}
+
+// Holds KEY down for FRAMES frames, starting at FRAME
+#[derive(Debug, PartialEq)]
+struct KeyPress {
+    frame: u64,
+    key: usize,
+    frames: u64,
+}
+
+struct Options {
+    rom: String,
+    debug: bool,
+    clock_speed: u32,
+    mode: Mode,
+    quirks: Option<String>,
+    quirk_flags: Vec<String>, // NAME=0|1 overrides
+    limit: Option<Limit>,
+    keys: Vec<KeyPress>,
+    ascii: bool,
+    png: Option<String>,
+    gif: Option<String>,
+    style: Style,
+    seed: Option<u64>,
+    movie: Option<String>,
+    record_movie: Option<String>,
+}
+
+fn parse_args() -> Options {
+    let mut rom = None;
+    let mut debug = false;
+    let mut clock_speed = DEFAULT_CLOCK_SPEED;
+    let mut mode = Mode::Chip8;
+    let mut quirks = None;
+    let mut quirk_flags = Vec::new();
+    let mut limit = None;
+    let mut keys = Vec::new();
+    let mut ascii = false;
+    let mut png = None;
+    let mut gif = None;
+    let mut style = Style::default();
+    let mut seed = None;
+    let mut movie = None;
+    let mut record_movie = None;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => debug = true,
+            "--ascii" => ascii = true,
+            "--png" => match args.next() {
+                Some(path) => png = Some(path),
+                None => exit_with_usage(),
+            },
+            "--gif" => match args.next() {
+                Some(path) => gif = Some(path),
+                None => exit_with_usage(),
+            },
+            "--seed" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => seed = Some(value),
+                None => exit_with_usage(),
+            },
+            "--movie" => match args.next() {
+                Some(path) => movie = Some(path),
+                None => exit_with_usage(),
+            },
+            "--record-movie" => match args.next() {
+                Some(path) => record_movie = Some(path),
+                None => exit_with_usage(),
+            },
+            "--scale" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) if value > 0 => style.scale = value,
+                _ => exit_with_usage(),
+            },
+            "--fg" => match args
+                .next()
+                .and_then(|value| screenshot::parse_color(&value))
+            {
+                Some(color) => style.palette[1] = color,
+                None => exit_with_usage(),
+            },
+            "--bg" => match args
+                .next()
+                .and_then(|value| screenshot::parse_color(&value))
+            {
+                Some(color) => style.palette[0] = color,
+                None => exit_with_usage(),
+            },
+            "--frames" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => limit = Some(Limit::Frames(value)),
+                None => exit_with_usage(),
+            },
+            "--cycles" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => limit = Some(Limit::Cycles(value)),
+                None => exit_with_usage(),
+            },
+            "--key" => match args.next().and_then(|value| parse_key_press(&value)) {
+                Some(press) => keys.push(press),
+                None => exit_with_usage(),
+            },
+            "--keys" => match args.next() {
+                Some(path) => keys.extend(read_key_script(&path)),
+                None => exit_with_usage(),
+            },
+            "--speed" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => clock_speed = value,
+                None => exit_with_usage(),
+            },
+            "--mode" => match args.next().and_then(|value| Mode::from_name(&value)) {
+                Some(value) => mode = value,
+                None => exit_with_usage(),
+            },
+            "--quirks" => match args.next() {
+                Some(name) => quirks = Some(name),
+                None => exit_with_usage(),
+            },
+            "--quirk" => match args.next() {
+                Some(flag) if Quirks::default().apply(&flag).is_some() => quirk_flags.push(flag),
+                _ => exit_with_usage(),
+            },
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    match rom {
+        Some(rom) => Options {
+            rom,
+            debug,
+            clock_speed,
+            mode,
+            quirks,
+            quirk_flags,
+            limit,
+            keys,
+            ascii,
+            png,
+            gif,
+            style,
+            seed,
+            movie,
+            record_movie,
+        },
+        None => exit_with_usage(),
+    }
+}
+
+// FRAME:KEY[:FRAMES], the key is a hex digit and is held for one frame by default
+fn parse_key_press(text: &str) -> Option<KeyPress> {
+    let parts: Vec<&str> = text.trim().split(':').collect();
+    let (frame, key, frames) = match parts.as_slice() {
+        [frame, key] => (frame, key, "1"),
+        [frame, key, frames] => (frame, key, *frames),
+        _ => return None,
+    };
+    let key = usize::from_str_radix(key, 16)
+        .ok()
+        .filter(|&key| key < 16)?;
+
+    Some(KeyPress {
+        frame: frame.parse().ok()?,
+        key,
+        frames: frames.parse().ok()?,
+    })
+}
+
+fn read_key_script(path: &str) -> Vec<KeyPress> {
+    let script = fs::read_to_string(path).unwrap_or_else(|error| {
+        eprintln!("Cannot read {}: {}", path, error);
+        process::exit(1);
+    });
+
+    parse_key_script(&script).unwrap_or_else(|error| {
+        eprintln!("{}:{}", path, error);
+        process::exit(1);
+    })
+}
+
+// One FRAME:KEY[:FRAMES] entry per line, # starts a comment. Errors start with the line number
+fn parse_key_script(script: &str) -> Result<Vec<KeyPress>, String> {
+    script
+        .lines()
+        .enumerate()
+        .map(|(index, line)| (index, line.split('#').next().unwrap_or("").trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(index, line)| {
+            parse_key_press(line)
+                .ok_or_else(|| format!("{}: invalid key press: {}", index + 1, line))
+        })
+        .collect()
+}
+
+// Runs one frame without going over a cycle limit, false when the limit cut it short
+fn run_frame(vm: &mut VM, limit: Limit, cycles: &mut u64) -> Result<bool, VmError> {
+    vm.run_frame_until(|_| match limit {
+        Limit::Cycles(limit) if *cycles >= limit => true,
+        _ => {
+            *cycles += 1;
+            false
+        }
+    })
+    .map(|stopped| !stopped)
+}
+
+// How the run ended after `frames` frames, the last one is the frame that halted or failed
+fn summary(result: &Result<(), MovieError>, vm: &VM, frames: u64, cycles: u64) -> String {
+    let last = frames.saturating_sub(1);
+
+    match result {
+        Ok(()) if vm.halted() => format!("Halted at frame {}", last),
+        Ok(()) if vm.waiting_for_key() => format!(
+            "Ran {} frames, {} instructions, waiting for a key",
+            frames, cycles
+        ),
+        Ok(()) => format!("Ran {} frames, {} instructions", frames, cycles),
+        Err(error @ MovieError::Desync { .. }) => error.to_string(),
+        Err(error) => format!("Error at frame {}: {}", last, error),
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
+
+fn main() {
+    let options = parse_args();
+    let rom = fs::read(&options.rom).unwrap_or_else(|error| {
+        eprintln!("Cannot read ROM: {}", error);
+        process::exit(1);
+    });
+    let movie = options.movie.as_ref().map(|path| {
+        fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|text| Movie::parse(&text).map_err(|error| error.to_string()))
+            .unwrap_or_else(|error| {
+                eprintln!("{}: {}", path, error);
+                process::exit(1);
+            })
+    });
+
+    // A movie brings the settings it was recorded with
+    let mut vm = match movie {
+        Some(ref movie) => movie.start(&rom, options.debug).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        }),
+        None => {
+            let mut vm = VM::with_mode(options.mode, options.debug);
+            vm.set_clock_speed(options.clock_speed);
+
+            let quirk_config = match options.quirks {
+                Some(ref name) => quirks::load(name).unwrap_or_else(|error| {
+                    eprintln!("{}", error);
+                    process::exit(1);
+                }),
+                None => QuirksConfig::default(),
+            };
+            let mut quirks = quirk_config.quirks(Some(&options.rom), options.mode);
+
+            for flag in options.quirk_flags.iter() {
+                quirks.apply(flag);
+            }
+
+            vm.set_quirks(quirks);
+
+            if let Some(seed) = options.seed {
+                vm.set_seed(seed);
+            }
+
+            vm.load_fontset();
+
+            if let Err(error) = vm.load_rom(&rom) {
+                eprintln!("{}", error);
+                process::exit(1);
+            }
+
+            vm
+        }
+    };
+
+    let mut recording = options.record_movie.as_ref().map(|_| {
+        let seed = options.seed.unwrap_or_else(rand::random);
+        Movie::record(&mut vm, &rom, seed)
+    });
+    let limit = match (options.limit, movie.as_ref()) {
+        (Some(limit), _) => limit,
+        (None, Some(movie)) => Limit::Frames(movie.frames.len() as u64),
+        (None, None) => Limit::Frames(DEFAULT_FRAMES),
+    };
+    let mut recorder = options.gif.as_ref().map(|path| {
+        File::create(path)
+            .map_err(|error| error.to_string())
+            .and_then(|file| {
+                Recorder::new(BufWriter::new(file), &vm, &options.style)
+                    .map_err(|error| error.to_string())
+            })
+            .unwrap_or_else(|error| {
+                eprintln!("Cannot write {}: {}", path, error);
+                process::exit(1);
+            })
+    });
+    let mut frame = 0;
+    let mut cycles = 0;
+    let mut result: Result<(), MovieError> = Ok(());
+
+    loop {
+        match limit {
+            Limit::Frames(limit) if frame >= limit => break,
+            Limit::Cycles(limit) if cycles >= limit => break,
+            _ => {}
+        }
+
+        match movie {
+            Some(ref movie) if frame as usize >= movie.frames.len() => break,
+            Some(ref movie) => movie.set_keys(frame as usize, &mut vm),
+            None => {
+                for key in 0..16 {
+                    let pressed = options.keys.iter().any(|press| {
+                        press.key == key
+                            && frame >= press.frame
+                            && frame < press.frame.saturating_add(press.frames)
+                    });
+
+                    vm.set_key(key, pressed);
+                }
+            }
+        }
+
+        // A frame cut short by the cycle limit does not match the recorded one
+        let complete = match run_frame(&mut vm, limit, &mut cycles) {
+            Ok(complete) => complete,
+            Err(error) => {
+                result = Err(MovieError::from(error));
+                false
+            }
+        };
+
+        if let (Some(ref movie), true) = (&movie, complete) {
+            result = movie.check_frame(frame as usize, &vm);
+        }
+
+        if let Some(recording) = recording.as_mut() {
+            recording.record_frame(&vm);
+        }
+
+        frame += 1;
+
+        if let Some(recorder) = recorder.as_mut() {
+            if let Err(error) = recorder.capture(&vm) {
+                eprintln!("Cannot write {}: {}", options.gif.as_ref().unwrap(), error);
+                process::exit(1);
+            }
+        }
+
+        if result.is_err() || vm.halted() {
+            break;
+        }
+    }
+
+    if result.is_ok() {
+        println!("{}", summary(&result, &vm, frame, cycles));
+    } else {
+        eprintln!("{}", summary(&result, &vm, frame, cycles));
+    }
+
+    if let (Some(path), Some(recording)) = (&options.record_movie, &recording) {
+        if let Err(error) = fs::write(path, recording.to_text()) {
+            eprintln!("Cannot write {}: {}", path, error);
+            process::exit(1);
+        }
+    }
+
+    if options.ascii {
+        print!("{}", screenshot::to_ascii(&vm));
+    }
+
+    if let Some(ref path) = options.png {
+        if let Err(error) = screenshot::save_png(&vm, &options.style, path) {
+            eprintln!("Cannot write {}: {}", path, error);
+            process::exit(1);
+        }
+    }
+
+    if let Some(recorder) = recorder {
+        let written = recorder
+            .finish()
+            .map_err(|error| error.to_string())
+            .and_then(|mut writer| writer.flush().map_err(|error| error.to_string()));
+
+        if let Err(error) = written {
+            eprintln!("Cannot write {}: {}", options.gif.as_ref().unwrap(), error);
+            process::exit(1);
+        }
+    }
+
+    match result {
+        Ok(()) => {}
+        Err(MovieError::Desync { .. }) => process::exit(3),
+        Err(_) => process::exit(2),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_presses() {
+        assert_eq!(
+            parse_key_press("30:a:10"),
+            Some(KeyPress {
+                frame: 30,
+                key: 0xA,
+                frames: 10
+            })
+        );
+        assert_eq!(
+            parse_key_press(" 0:5 "),
+            Some(KeyPress {
+                frame: 0,
+                key: 0x5,
+                frames: 1
+            })
+        );
+        assert_eq!(parse_key_press("30:10"), None);
+        assert_eq!(parse_key_press("30:5:-1"), None);
+        assert_eq!(parse_key_press("30"), None);
+        assert_eq!(parse_key_press("30:5:1:1"), None);
+    }
+
+    #[test]
+    fn key_script() {
+        let script = "# Start the game\n60:5\n\n120:4:30 # Move left\n";
+
+        assert_eq!(
+            parse_key_script(script),
+            Ok(vec![
+                KeyPress {
+                    frame: 60,
+                    key: 0x5,
+                    frames: 1
+                },
+                KeyPress {
+                    frame: 120,
+                    key: 0x4,
+                    frames: 30
+                },
+            ])
+        );
+        assert_eq!(
+            parse_key_script("60:5\n60:g"),
+            Err("2: invalid key press: 60:g".to_string())
+        );
+    }
+
+    #[test]
+    fn cycle_limit_on_frame_boundary() {
+        let mut vm = VM::initialize(false);
+        vm.load_rom(&[0x12, 0x00]).unwrap(); // JP 0x200
+        vm.set_clock_speed(600); // 10 instructions per frame
+        let mut cycles = 0;
+
+        assert!(run_frame(&mut vm, Limit::Cycles(15), &mut cycles).unwrap());
+        assert!(!run_frame(&mut vm, Limit::Cycles(15), &mut cycles).unwrap());
+        assert_eq!(cycles, 15);
+
+        let mut cycles = 0;
+
+        assert!(run_frame(&mut vm, Limit::Cycles(10), &mut cycles).unwrap());
+        assert_eq!(cycles, 10);
+    }
+
+    #[test]
+    fn halted_frame() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.load_rom(&[0x00, 0xE0, 0x00, 0xFD]).unwrap(); // CLS, EXIT
+        let mut cycles = 0;
+
+        assert!(run_frame(&mut vm, Limit::Frames(10), &mut cycles).unwrap());
+        assert!(vm.halted());
+        assert_eq!(summary(&Ok(()), &vm, 1, cycles), "Halted at frame 0");
+
+        let error = Err(MovieError::from(VmError::StackUnderflow { pc: 0x200 }));
+
+        assert!(summary(&error, &vm, 3, cycles).starts_with("Error at frame 2: "));
+    }
+}
@@ -0,0 +1,333 @@
+extern crate crossterm;
+extern crate emuchip_8;
+
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, KeyboardEnhancementFlags,
+    PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+};
+use crossterm::style::Print;
+use crossterm::terminal::{self, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+use crossterm::{cursor, execute, queue};
+use emuchip_8::keymap::{self, Keymap, KeymapConfig};
+use emuchip_8::quirks::{self, Quirks, QuirksConfig};
+use emuchip_8::terminal::{self as tui, HeldKeys};
+use emuchip_8::vm::{Mode, DEFAULT_CLOCK_SPEED, TIMER_FREQUENCY, VM};
+use std::env;
+use std::fs;
+use std::io::{self, Stdout, Write};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const USAGE: &str = "Usage: emuchip-8-tui ROM [--speed INSTRUCTIONS_PER_SECOND] \
+                     [--mode chip8|schip|xochip] [--quirks FILE|vip|chip48|schip|xochip] \
+                     [--quirk NAME=0|1]... [--seed N] \
+                     [--keymap FILE|qwerty|azerty|dvorak] [--braille]";
+const PANEL_WIDTH: usize = 16;
+const MIN_PANEL_HEIGHT: usize = 16;
+
+struct Options {
+    rom: String,
+    clock_speed: u32,
+    mode: Mode,
+    quirks: Option<String>,
+    quirk_flags: Vec<String>, // NAME=0|1 overrides
+    seed: Option<u64>,
+    keymap: Option<String>,
+    braille: bool,
+}
+
+// How the session ended
+enum Exit {
+    Quit,
+    Halted,
+    Error(String),
+}
+
+// Raw mode and the alternate screen, undone on drop so a panic leaves a usable terminal
+struct Screen {
+    stdout: Stdout,
+    releases: bool, // The terminal reports key releases
+}
+
+impl Screen {
+    fn open() -> io::Result<Screen> {
+        let mut stdout = io::stdout();
+
+        terminal::enable_raw_mode()?;
+        execute!(stdout, EnterAlternateScreen, cursor::Hide)?;
+
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+
+        if releases {
+            execute!(
+                stdout,
+                PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES)
+            )?;
+        }
+
+        Ok(Screen { stdout, releases })
+    }
+
+    fn draw(&mut self, vm: &VM, braille: bool, status: &str) -> io::Result<()> {
+        let display = if braille {
+            tui::braille(vm)
+        } else {
+            tui::half_blocks(vm)
+        };
+        let width = display.first().map_or(0, |line| line.chars().count());
+        let height = display.len().max(MIN_PANEL_HEIGHT);
+        let panel = tui::panel(vm);
+
+        for row in 0..height {
+            let mut line = display
+                .get(row)
+                .cloned()
+                .unwrap_or_else(|| " ".repeat(width));
+
+            // The panel wraps into columns next to the display
+            for column in panel.iter().skip(row).step_by(height) {
+                line.push_str(&format!("  {:<width$}", column, width = PANEL_WIDTH));
+            }
+
+            queue!(
+                self.stdout,
+                cursor::MoveTo(0, row as u16),
+                Print(line),
+                terminal::Clear(ClearType::UntilNewLine)
+            )?;
+        }
+
+        queue!(
+            self.stdout,
+            cursor::MoveTo(0, height as u16 + 1),
+            Print(status),
+            terminal::Clear(ClearType::UntilNewLine)
+        )?;
+
+        self.stdout.flush()
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        if self.releases {
+            let _ = execute!(self.stdout, PopKeyboardEnhancementFlags);
+        }
+
+        let _ = execute!(self.stdout, cursor::Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn parse_args() -> Options {
+    let mut rom = None;
+    let mut clock_speed = DEFAULT_CLOCK_SPEED;
+    let mut mode = Mode::Chip8;
+    let mut quirks = None;
+    let mut quirk_flags = Vec::new();
+    let mut seed = None;
+    let mut keymap = None;
+    let mut braille = false;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--braille" => braille = true,
+            "--keymap" => match args.next() {
+                Some(name) => keymap = Some(name),
+                None => exit_with_usage(),
+            },
+            "--seed" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => seed = Some(value),
+                None => exit_with_usage(),
+            },
+            "--speed" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => clock_speed = value,
+                None => exit_with_usage(),
+            },
+            "--mode" => match args.next().and_then(|value| Mode::from_name(&value)) {
+                Some(value) => mode = value,
+                None => exit_with_usage(),
+            },
+            "--quirks" => match args.next() {
+                Some(name) => quirks = Some(name),
+                None => exit_with_usage(),
+            },
+            "--quirk" => match args.next() {
+                Some(flag) if Quirks::default().apply(&flag).is_some() => quirk_flags.push(flag),
+                _ => exit_with_usage(),
+            },
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    match rom {
+        Some(rom) => Options {
+            rom,
+            clock_speed,
+            mode,
+            quirks,
+            quirk_flags,
+            seed,
+            keymap,
+            braille,
+        },
+        None => exit_with_usage(),
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
+
+// Name of a key in keymaps
+fn key_name(code: KeyCode) -> Option<String> {
+    let name = match code {
+        KeyCode::Char(c) => return Some(c.to_string()),
+        KeyCode::Up => "up",
+        KeyCode::Down => "down",
+        KeyCode::Left => "left",
+        KeyCode::Right => "right",
+        KeyCode::Enter => "enter",
+        KeyCode::Tab => "tab",
+        KeyCode::Backspace => "backspace",
+        _ => return None,
+    };
+
+    Some(name.to_string())
+}
+
+fn run(screen: &mut Screen, vm: &mut VM, keymap: &Keymap, braille: bool) -> io::Result<Exit> {
+    let frame_duration = Duration::new(0, 1_000_000_000u32 / TIMER_FREQUENCY);
+    let mut keys = HeldKeys::new();
+
+    loop {
+        let frame_start = Instant::now();
+
+        while event::poll(Duration::from_secs(0))? {
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                _ => continue,
+            };
+
+            match key {
+                KeyEvent {
+                    code: KeyCode::Esc, ..
+                } => return Ok(Exit::Quit),
+                KeyEvent {
+                    code: KeyCode::Char('c'),
+                    modifiers,
+                    ..
+                } if modifiers.contains(KeyModifiers::CONTROL) => return Ok(Exit::Quit),
+                _ => {}
+            }
+
+            if let Some(index) = key_name(key.code).and_then(|name| keymap.key(&name)) {
+                match key.kind {
+                    KeyEventKind::Release => keys.release(index),
+                    KeyEventKind::Press if screen.releases => keys.press(index),
+                    KeyEventKind::Repeat if screen.releases => {}
+                    _ => keys.tap(index),
+                }
+            }
+        }
+
+        keys.update(vm);
+
+        if let Err(error) = vm.run_frame() {
+            return Ok(Exit::Error(error.to_string()));
+        }
+
+        let status = if vm.waiting_for_key() {
+            "Esc to quit - press a key"
+        } else {
+            "Esc to quit"
+        };
+
+        if vm.halted() {
+            // Keep the last screen up until the player has seen it
+            screen.draw(vm, braille, "Halted, press a key to exit")?;
+
+            loop {
+                if let Event::Key(KeyEvent {
+                    kind: KeyEventKind::Press,
+                    ..
+                }) = event::read()?
+                {
+                    return Ok(Exit::Halted);
+                }
+            }
+        }
+
+        screen.draw(vm, braille, status)?;
+
+        let elapsed = frame_start.elapsed();
+
+        if elapsed < frame_duration {
+            thread::sleep(frame_duration - elapsed);
+        }
+    }
+}
+
+fn main() {
+    let options = parse_args();
+    let rom = fs::read(&options.rom).unwrap_or_else(|error| {
+        eprintln!("Cannot read ROM: {}", error);
+        process::exit(1);
+    });
+    let keymaps = match options.keymap {
+        Some(ref name) => keymap::load(name).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        }),
+        None => KeymapConfig::default(),
+    };
+    let keymap = keymaps.keymap(Some(&options.rom));
+    let mut vm = VM::with_mode(options.mode, false);
+    vm.set_clock_speed(options.clock_speed);
+
+    let quirk_config = match options.quirks {
+        Some(ref name) => quirks::load(name).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        }),
+        None => QuirksConfig::default(),
+    };
+    let mut quirks = quirk_config.quirks(Some(&options.rom), options.mode);
+
+    for flag in options.quirk_flags.iter() {
+        quirks.apply(flag);
+    }
+
+    vm.set_quirks(quirks);
+
+    if let Some(seed) = options.seed {
+        vm.set_seed(seed);
+    }
+
+    vm.load_fontset();
+
+    if let Err(error) = vm.load_rom(&rom) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+
+    let exit =
+        Screen::open().and_then(|mut screen| run(&mut screen, &mut vm, &keymap, options.braille));
+
+    // The screen is closed here, so messages end up in the normal terminal
+    match exit {
+        Ok(Exit::Quit) | Ok(Exit::Halted) => {}
+        Ok(Exit::Error(error)) => {
+            eprintln!("{}", error);
+            process::exit(2);
+        }
+        Err(error) => {
+            eprintln!("Terminal error: {}", error);
+            process::exit(1);
+        }
+    }
+}
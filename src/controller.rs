@@ -0,0 +1,309 @@
+use crate::vm::VM;
+use std::collections::{HashMap, HashSet};
+
+/// Stick and trigger positions past this, about half way, count as a press
+pub const AXIS_THRESHOLD: i16 = 16384;
+
+// Inputs named like in SDL game controller mappings. Sticks are split into one input per
+// direction, `leftx-` is the left stick pushed left and `lefty-` pushed up
+const INPUTS: [&str; 23] = [
+    "a",
+    "b",
+    "x",
+    "y",
+    "back",
+    "guide",
+    "start",
+    "leftstick",
+    "rightstick",
+    "leftshoulder",
+    "rightshoulder",
+    "dpup",
+    "dpdown",
+    "dpleft",
+    "dpright",
+    "leftx-",
+    "leftx+",
+    "lefty-",
+    "lefty+",
+    "rightx-",
+    "rightx+",
+    "righty-",
+    "righty+",
+];
+const TRIGGERS: [&str; 2] = ["lefttrigger", "righttrigger"];
+
+// Directions on 2/4/6/8 like the keypad arrows most games use, 5 to fire
+const DEFAULT_BUTTONS: [(&str, usize); 11] = [
+    ("dpup", 0x2),
+    ("dpdown", 0x8),
+    ("dpleft", 0x4),
+    ("dpright", 0x6),
+    ("lefty-", 0x2),
+    ("lefty+", 0x8),
+    ("leftx-", 0x4),
+    ("leftx+", 0x6),
+    ("a", 0x5),
+    ("b", 0x0),
+    ("start", 0xF),
+];
+
+/// Whether `name` is a button, stick direction or trigger a controller map can bind
+pub fn is_input(name: &str) -> bool {
+    let name = name.to_lowercase();
+
+    INPUTS.contains(&name.as_str()) || TRIGGERS.contains(&name.as_str())
+}
+
+/// Controller inputs bound to the hex keypad
+#[derive(Clone, Debug, PartialEq)]
+pub struct ControllerMap {
+    buttons: HashMap<String, usize>,
+}
+
+impl ControllerMap {
+    /// Binds an input to a hex keypad key, `None` removes the binding
+    pub fn bind(&mut self, input: &str, key: Option<usize>) {
+        match key {
+            Some(key) => self.buttons.insert(input.to_lowercase(), key),
+            None => self.buttons.remove(&input.to_lowercase()),
+        };
+    }
+
+    /// The hex keypad key an input is bound to
+    pub fn key(&self, input: &str) -> Option<usize> {
+        self.buttons.get(&input.to_lowercase()).cloned()
+    }
+}
+
+impl Default for ControllerMap {
+    fn default() -> ControllerMap {
+        ControllerMap {
+            buttons: DEFAULT_BUTTONS
+                .iter()
+                .map(|&(input, key)| (input.to_string(), key))
+                .collect(),
+        }
+    }
+}
+
+/// What a frontend reports about its controllers, ids tell connected controllers apart
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ControllerEvent<'a> {
+    Added(i32),
+    Removed(i32),
+    Button {
+        id: i32,
+        button: &'a str,
+        pressed: bool,
+    },
+    Axis {
+        id: i32,
+        axis: &'a str,
+        value: i16,
+    },
+}
+
+/// Turns controller events into hex keypad presses. A key stays down while the keyboard or
+/// any input bound to it on any controller holds it, and a controller that goes away
+/// releases its keys
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Controllers {
+    pub map: ControllerMap,
+    held: HashMap<i32, HashSet<String>>, // Inputs down on each connected controller
+    keyboard: [bool; 16],                // Keys down on the keyboard
+}
+
+impl Controllers {
+    pub fn new(map: ControllerMap) -> Controllers {
+        Controllers {
+            map,
+            held: HashMap::new(),
+            keyboard: [false; 16],
+        }
+    }
+
+    pub fn connected(&self) -> usize {
+        self.held.len()
+    }
+
+    pub fn handle(&mut self, event: ControllerEvent, vm: &mut VM) {
+        match event {
+            ControllerEvent::Added(id) => {
+                self.held.entry(id).or_default();
+            }
+            ControllerEvent::Removed(id) => {
+                for input in self.held.remove(&id).unwrap_or_default() {
+                    self.refresh(&input, vm);
+                }
+            }
+            ControllerEvent::Button {
+                id,
+                button,
+                pressed,
+            } => self.set(id, button, pressed, vm),
+            ControllerEvent::Axis { id, axis, value } if TRIGGERS.contains(&axis) => {
+                self.set(id, axis, value > AXIS_THRESHOLD, vm)
+            }
+            ControllerEvent::Axis { id, axis, value } => {
+                self.set(id, &format!("{}-", axis), value < -AXIS_THRESHOLD, vm);
+                self.set(id, &format!("{}+", axis), value > AXIS_THRESHOLD, vm);
+            }
+        }
+    }
+
+    /// Presses or releases a key from the keyboard, the key stays down while a controller
+    /// holds it
+    pub fn keyboard(&mut self, key: usize, pressed: bool, vm: &mut VM) {
+        self.keyboard[key] = pressed;
+        vm.set_key(key, pressed || self.controller_holds(key));
+    }
+
+    fn set(&mut self, id: i32, input: &str, pressed: bool, vm: &mut VM) {
+        let inputs = self.held.entry(id).or_default();
+        let changed = if pressed {
+            inputs.insert(input.to_string())
+        } else {
+            inputs.remove(input)
+        };
+
+        if changed {
+            self.refresh(input, vm);
+        }
+    }
+
+    // Updates the key bound to `input` from everything held
+    fn refresh(&self, input: &str, vm: &mut VM) {
+        if let Some(key) = self.map.key(input) {
+            vm.set_key(key, self.keyboard[key] || self.controller_holds(key));
+        }
+    }
+
+    fn controller_holds(&self, key: usize) -> bool {
+        self.held
+            .values()
+            .flatten()
+            .any(|input| self.map.key(input) == Some(key))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn button(id: i32, button: &str, pressed: bool) -> ControllerEvent<'_> {
+        ControllerEvent::Button {
+            id,
+            button,
+            pressed,
+        }
+    }
+
+    #[test]
+    fn buttons_press_keys() {
+        let mut vm = VM::initialize(false);
+        let mut controllers = Controllers::default();
+        controllers.handle(ControllerEvent::Added(0), &mut vm);
+
+        controllers.handle(button(0, "dpleft", true), &mut vm);
+        controllers.handle(button(0, "a", true), &mut vm);
+        assert!(vm.key[0x4]);
+        assert!(vm.key[0x5]);
+
+        controllers.handle(button(0, "dpleft", false), &mut vm);
+        assert!(!vm.key[0x4]);
+        assert!(vm.key[0x5]);
+    }
+
+    #[test]
+    fn sticks_and_triggers() {
+        let mut vm = VM::initialize(false);
+        let mut controllers = Controllers::default();
+        controllers.map.bind("righttrigger", Some(0xB));
+
+        let axis = |axis, value| ControllerEvent::Axis { id: 0, axis, value };
+
+        controllers.handle(axis("lefty", -30000), &mut vm);
+        assert!(vm.key[0x2]);
+
+        controllers.handle(axis("lefty", 30000), &mut vm);
+        assert!(!vm.key[0x2]);
+        assert!(vm.key[0x8]);
+
+        controllers.handle(axis("lefty", 1000), &mut vm);
+        assert!(!vm.key[0x8]);
+
+        controllers.handle(axis("righttrigger", 32767), &mut vm);
+        assert!(vm.key[0xB]);
+
+        controllers.handle(axis("righttrigger", 0), &mut vm);
+        assert!(!vm.key[0xB]);
+    }
+
+    #[test]
+    fn key_held_by_any_input() {
+        let mut vm = VM::initialize(false);
+        let mut controllers = Controllers::default();
+
+        controllers.handle(button(0, "dpup", true), &mut vm);
+        controllers.handle(button(1, "dpup", true), &mut vm);
+        controllers.handle(button(0, "dpup", false), &mut vm);
+        assert!(vm.key[0x2]);
+
+        controllers.handle(button(1, "dpup", false), &mut vm);
+        assert!(!vm.key[0x2]);
+    }
+
+    #[test]
+    fn keyboard_and_controllers_share_keys() {
+        let mut vm = VM::initialize(false);
+        let mut controllers = Controllers::default();
+
+        controllers.keyboard(0x5, true, &mut vm);
+        controllers.handle(button(0, "a", true), &mut vm);
+        controllers.handle(button(0, "a", false), &mut vm);
+        assert!(vm.key[0x5]);
+
+        controllers.keyboard(0x5, false, &mut vm);
+        assert!(!vm.key[0x5]);
+
+        controllers.handle(button(0, "a", true), &mut vm);
+        controllers.keyboard(0x5, true, &mut vm);
+        controllers.keyboard(0x5, false, &mut vm);
+        assert!(vm.key[0x5]);
+
+        controllers.handle(ControllerEvent::Removed(0), &mut vm);
+        assert!(!vm.key[0x5]);
+    }
+
+    #[test]
+    fn unplugging_releases_keys() {
+        let mut vm = VM::initialize(false);
+        let mut controllers = Controllers::default();
+        controllers.handle(ControllerEvent::Added(3), &mut vm);
+        controllers.handle(button(3, "start", true), &mut vm);
+        assert_eq!(controllers.connected(), 1);
+        assert!(vm.key[0xF]);
+
+        controllers.handle(ControllerEvent::Removed(3), &mut vm);
+        assert_eq!(controllers.connected(), 0);
+        assert!(!vm.key[0xF]);
+    }
+
+    #[test]
+    fn unbound_inputs_leave_keys_alone() {
+        let mut vm = VM::initialize(false);
+        let mut controllers = Controllers::default();
+        controllers.map.bind("a", None);
+        vm.set_key(0x5, true); // Held on the keyboard
+
+        controllers.handle(button(0, "a", true), &mut vm);
+        controllers.handle(button(0, "a", false), &mut vm);
+        controllers.handle(button(0, "guide", true), &mut vm);
+
+        assert!(vm.key[0x5]);
+        assert!(is_input("DPUP"));
+        assert!(is_input("lefttrigger"));
+        assert!(!is_input("leftx"));
+    }
+}
@@ -0,0 +1,476 @@
+use crate::vm::{VmError, VM};
+use crate::watch::Watchpoint;
+use std::collections::BTreeSet;
+
+pub const HELP: &str = "\
+c, continue         resume execution
+p, pause            pause execution
+s, step             execute one instruction
+n, next             step over CALL instructions
+u, until ADDR       run to ADDR
+b, break [ADDR]     set a breakpoint, or list them without ADDR
+d, delete ADDR      remove a breakpoint
+w, watch [WHAT]     pause when WHAT changes, or list watchpoints without WHAT. WHAT is a
+                    V register, I, DT, ST, or ADDR [LEN] to watch reads and writes of memory
+uw, unwatch WHAT    remove a watchpoint
+r, regs             show registers and timers
+bt, stack           show the call stack
+m, mem [ADDR] [LEN] dump LEN bytes of memory from ADDR, defaults to 16 bytes at I
+h, help             show this help
+q, quit             exit the emulator
+";
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Continue,
+    Pause,
+    Step,
+    StepOver,
+    RunTo(u16),
+    Break(Option<u16>),
+    Delete(u16),
+    Watch(Option<Watchpoint>),
+    Unwatch(Watchpoint),
+    Registers,
+    Stack,
+    Memory(Option<u16>, usize),
+    Help,
+    Quit,
+}
+
+impl Command {
+    /// Parses a prompt line, addresses are hexadecimal with an optional 0x prefix
+    pub fn parse(line: &str) -> Result<Command, String> {
+        let mut words = line.split_whitespace();
+        let name = words.next().unwrap_or("");
+        let arguments: Vec<&str> = words.collect();
+
+        let command = match (name, arguments.as_slice()) {
+            ("c", []) | ("continue", []) => Command::Continue,
+            ("p", []) | ("pause", []) => Command::Pause,
+            ("s", []) | ("step", []) => Command::Step,
+            ("n", []) | ("next", []) => Command::StepOver,
+            ("u", [address]) | ("until", [address]) => Command::RunTo(parse_address(address)?),
+            ("b", []) | ("break", []) => Command::Break(None),
+            ("b", [address]) | ("break", [address]) => {
+                Command::Break(Some(parse_address(address)?))
+            }
+            ("d", [address]) | ("delete", [address]) => Command::Delete(parse_address(address)?),
+            ("w", []) | ("watch", []) => Command::Watch(None),
+            ("w", what) | ("watch", what) => Command::Watch(Some(parse_watchpoint(what)?)),
+            ("uw", what) | ("unwatch", what) => Command::Unwatch(parse_watchpoint(what)?),
+            ("r", []) | ("regs", []) => Command::Registers,
+            ("bt", []) | ("stack", []) => Command::Stack,
+            ("m", []) | ("mem", []) => Command::Memory(None, 16),
+            ("m", [address]) | ("mem", [address]) => {
+                Command::Memory(Some(parse_address(address)?), 16)
+            }
+            ("m", [address, length]) | ("mem", [address, length]) => Command::Memory(
+                Some(parse_address(address)?),
+                length
+                    .parse()
+                    .map_err(|_| format!("Invalid length: {}", length))?,
+            ),
+            ("h", []) | ("help", []) => Command::Help,
+            ("q", []) | ("quit", []) => Command::Quit,
+            _ => {
+                return Err(format!(
+                    "Unknown command: {}, type help for a list",
+                    line.trim()
+                ))
+            }
+        };
+
+        Ok(command)
+    }
+}
+
+fn parse_watchpoint(arguments: &[&str]) -> Result<Watchpoint, String> {
+    let watchpoint = match arguments {
+        [what] if what.eq_ignore_ascii_case("i") => Watchpoint::I,
+        [what] if what.eq_ignore_ascii_case("dt") => Watchpoint::DelayTimer,
+        [what] if what.eq_ignore_ascii_case("st") => Watchpoint::SoundTimer,
+        [what] if what.len() == 2 && what.to_uppercase().starts_with('V') => {
+            match usize::from_str_radix(&what[1..], 16) {
+                Ok(x) => Watchpoint::Register(x),
+                Err(_) => return Err(format!("Invalid register: {}", what)),
+            }
+        }
+        [address] => Watchpoint::Memory {
+            address: parse_address(address)? as usize,
+            length: 1,
+        },
+        [address, length] => Watchpoint::Memory {
+            address: parse_address(address)? as usize,
+            length: match length.parse() {
+                Ok(length) if length > 0 => length,
+                _ => return Err(format!("Invalid length: {}", length)),
+            },
+        },
+        _ => return Err("Expected a register, I, DT, ST or ADDR [LEN]".to_string()),
+    };
+
+    Ok(watchpoint)
+}
+
+fn parse_address(text: &str) -> Result<u16, String> {
+    let digits = text.trim_start_matches("0x").trim_start_matches("0X");
+
+    u16::from_str_radix(digits, 16).map_err(|_| format!("Invalid address: {}", text))
+}
+
+// Pauses execution on breakpoints and on the temporary stops set by step over and run to
+pub struct Debugger {
+    paused: bool,
+    breakpoints: BTreeSet<u16>,
+    target: Option<(u16, u16)>, // Address and deepest stack pointer it applies to
+    resuming: bool,             // Don't stop again on the instruction execution resumed from
+}
+
+impl Debugger {
+    /// Starts paused, so breakpoints can be set before the first instruction runs
+    pub fn new() -> Debugger {
+        Debugger {
+            paused: true,
+            breakpoints: BTreeSet::new(),
+            target: None,
+            resuming: false,
+        }
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn pause(&mut self) {
+        self.paused = true;
+        self.target = None;
+    }
+
+    /// Runs a command and returns the text to show on the prompt
+    pub fn execute(&mut self, command: Command, vm: &mut VM) -> Result<String, VmError> {
+        let output = match command {
+            Command::Continue => {
+                self.resume(None);
+                String::new()
+            }
+            Command::Pause => {
+                self.pause();
+                location(vm)
+            }
+            Command::Step => {
+                self.pause();
+                vm.emulate_cycle()?;
+                location(vm)
+            }
+            Command::StepOver => {
+                if vm.memory[vm.pc() as usize] & 0xF0 == 0x20 {
+                    self.resume(Some((vm.pc().wrapping_add(2), vm.sp())));
+                    String::new()
+                } else {
+                    return self.execute(Command::Step, vm);
+                }
+            }
+            Command::RunTo(address) => {
+                self.resume(Some((address, u16::MAX)));
+                String::new()
+            }
+            Command::Break(Some(address)) => {
+                self.breakpoints.insert(address);
+                format!("Breakpoint at 0x{:03X}\n", address)
+            }
+            Command::Break(None) => self
+                .breakpoints
+                .iter()
+                .map(|address| format!("0x{:03X}\n", address))
+                .collect(),
+            Command::Delete(address) => {
+                if self.breakpoints.remove(&address) {
+                    format!("Deleted breakpoint at 0x{:03X}\n", address)
+                } else {
+                    format!("No breakpoint at 0x{:03X}\n", address)
+                }
+            }
+            Command::Watch(Some(watchpoint)) => {
+                vm.add_watchpoint(watchpoint);
+                format!("Watching {}\n", watchpoint)
+            }
+            Command::Watch(None) => vm
+                .watchpoints()
+                .iter()
+                .map(|watchpoint| format!("{}\n", watchpoint))
+                .collect(),
+            Command::Unwatch(watchpoint) => {
+                if vm.remove_watchpoint(watchpoint) {
+                    format!("Stopped watching {}\n", watchpoint)
+                } else {
+                    format!("Not watching {}\n", watchpoint)
+                }
+            }
+            Command::Registers => registers(vm),
+            Command::Stack => stack(vm),
+            Command::Memory(address, length) => {
+                memory(vm, address.unwrap_or_else(|| vm.i()) as usize, length)
+            }
+            Command::Help => HELP.to_string(),
+            Command::Quit => String::new(),
+        };
+
+        Ok(output)
+    }
+
+    /// Runs one frame unless paused, returns a message when a breakpoint or a watchpoint
+    /// paused execution
+    pub fn run_frame(&mut self, vm: &mut VM) -> Result<Option<String>, VmError> {
+        if self.paused {
+            return Ok(None);
+        }
+
+        let stopped = vm.run_frame_until(|vm| self.should_stop(vm))?;
+
+        if !stopped {
+            return Ok(None);
+        }
+
+        let reason = if let Some(hit) = vm.watch_hit() {
+            format!("Watchpoint: {}\nPaused", hit)
+        } else if self.breakpoints.contains(&vm.pc()) {
+            "Breakpoint".to_string()
+        } else {
+            "Stopped".to_string()
+        };
+
+        self.pause();
+        Ok(Some(format!("{} at {}", reason, location(vm))))
+    }
+
+    fn resume(&mut self, target: Option<(u16, u16)>) {
+        self.paused = false;
+        self.resuming = true;
+        self.target = target;
+    }
+
+    fn should_stop(&mut self, vm: &VM) -> bool {
+        if self.resuming {
+            self.resuming = false;
+            return false;
+        }
+
+        let at_target = match self.target {
+            Some((address, sp)) => vm.pc() == address && vm.sp() <= sp,
+            None => false,
+        };
+
+        at_target || self.breakpoints.contains(&vm.pc())
+    }
+}
+
+impl Default for Debugger {
+    fn default() -> Debugger {
+        Debugger::new()
+    }
+}
+
+/// Address and disassembly of the next instruction
+pub fn location(vm: &VM) -> String {
+    format!(
+        "0x{:03X}: {}\n",
+        vm.pc(),
+        vm.disassemble_at(vm.pc() as usize)
+    )
+}
+
+fn registers(vm: &VM) -> String {
+    let mut output = format!(
+        "PC: 0x{:03X}  I: 0x{:03X}  SP: {}  DT: {}  ST: {}\n",
+        vm.pc(),
+        vm.i(),
+        vm.sp(),
+        vm.delay_timer(),
+        vm.sound_timer()
+    );
+
+    for (index, value) in vm.v().iter().enumerate() {
+        output.push_str(&format!("V{:X}: {:02X}", index, value));
+        output.push_str(if index % 8 == 7 { "\n" } else { "  " });
+    }
+
+    output
+}
+
+fn stack(vm: &VM) -> String {
+    if vm.sp() == 0 {
+        return "Stack is empty\n".to_string();
+    }
+
+    vm.stack()[..vm.sp() as usize]
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(depth, address)| format!("#{} 0x{:03X}\n", depth, address))
+        .collect()
+}
+
+fn memory(vm: &VM, address: usize, length: usize) -> String {
+    let end = address.saturating_add(length).min(vm.memory_size());
+
+    (address..end)
+        .step_by(8)
+        .map(|row| {
+            let bytes: Vec<String> = vm.memory[row..(row + 8).min(end)]
+                .iter()
+                .map(|byte| format!("{:02X}", byte))
+                .collect();
+
+            format!("{:03X}: {}\n", row, bytes.join(" "))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD V0, 1; CALL 0x208; ADD V0, 1; JP 0x206; ADD V0, 2; RET
+    fn vm() -> VM {
+        let mut vm = VM::initialize(false);
+        vm.load_rom(&[
+            0x60, 0x01, 0x22, 0x08, 0x70, 0x01, 0x12, 0x06, 0x70, 0x02, 0x00, 0xEE,
+        ])
+        .unwrap();
+        vm
+    }
+
+    #[test]
+    fn parse_commands() {
+        assert_eq!(Command::parse("c"), Ok(Command::Continue));
+        assert_eq!(Command::parse("b 2a4"), Ok(Command::Break(Some(0x2A4))));
+        assert_eq!(Command::parse("until 0x300"), Ok(Command::RunTo(0x300)));
+        assert_eq!(
+            Command::parse("mem 200 4"),
+            Ok(Command::Memory(Some(0x200), 4))
+        );
+        assert!(Command::parse("b nowhere").is_err());
+        assert!(Command::parse("fly").is_err());
+    }
+
+    #[test]
+    fn starts_paused() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+
+        assert_eq!(debugger.run_frame(&mut vm).unwrap(), None);
+        assert_eq!(vm.pc(), 0x200);
+    }
+
+    #[test]
+    fn step() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+
+        let output = debugger.execute(Command::Step, &mut vm).unwrap();
+
+        assert_eq!(output, "0x202: CALL 0x208\n");
+        assert_eq!(vm.v()[0x0], 1);
+    }
+
+    #[test]
+    fn step_over_call() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+        debugger.execute(Command::Step, &mut vm).unwrap();
+        debugger.execute(Command::StepOver, &mut vm).unwrap();
+
+        let message = debugger.run_frame(&mut vm).unwrap();
+
+        assert_eq!(
+            message,
+            Some("Stopped at 0x204: ADD V0, 0x01\n".to_string())
+        );
+        assert_eq!(vm.v()[0x0], 3);
+        assert!(debugger.paused());
+    }
+
+    #[test]
+    fn breakpoint_and_resume() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+        debugger
+            .execute(Command::Break(Some(0x208)), &mut vm)
+            .unwrap();
+        debugger.execute(Command::Continue, &mut vm).unwrap();
+
+        let message = debugger.run_frame(&mut vm).unwrap();
+
+        assert_eq!(
+            message,
+            Some("Breakpoint at 0x208: ADD V0, 0x02\n".to_string())
+        );
+
+        // Continuing runs the instruction under the breakpoint instead of stopping again
+        debugger.execute(Command::Continue, &mut vm).unwrap();
+        debugger.execute(Command::RunTo(0x206), &mut vm).unwrap();
+
+        assert!(debugger.run_frame(&mut vm).unwrap().is_some());
+        assert_eq!(vm.pc(), 0x206);
+        assert_eq!(vm.v()[0x0], 4);
+    }
+
+    #[test]
+    fn watchpoint() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+        let command = Command::parse("watch v0").unwrap();
+        debugger.execute(command, &mut vm).unwrap();
+        debugger.execute(Command::Continue, &mut vm).unwrap();
+
+        let message = debugger.run_frame(&mut vm).unwrap();
+
+        assert_eq!(
+            message,
+            Some(
+                "Watchpoint: V0 changed from 0x0 to 0x1 by 6001 at 0x200\n\
+                 Paused at 0x202: CALL 0x208\n"
+                    .to_string()
+            )
+        );
+        assert_eq!(
+            Command::parse("w 2a0 16"),
+            Ok(Command::Watch(Some(Watchpoint::Memory {
+                address: 0x2A0,
+                length: 16
+            })))
+        );
+    }
+
+    #[test]
+    fn inspect() {
+        let mut vm = vm();
+        let mut debugger = Debugger::new();
+
+        assert!(debugger
+            .execute(Command::Registers, &mut vm)
+            .unwrap()
+            .starts_with("PC: 0x200  I: 0x000  SP: 0"));
+        assert_eq!(
+            debugger
+                .execute(Command::Memory(Some(0x200), 4), &mut vm)
+                .unwrap(),
+            "200: 60 01 22 08\n"
+        );
+        assert_eq!(
+            debugger
+                .execute(Command::Memory(Some(0xFFF), usize::MAX), &mut vm)
+                .unwrap(),
+            "FFF: 00\n"
+        );
+
+        debugger.execute(Command::Step, &mut vm).unwrap();
+        debugger.execute(Command::Step, &mut vm).unwrap();
+
+        assert_eq!(
+            debugger.execute(Command::Stack, &mut vm).unwrap(),
+            "#0 0x202\n"
+        );
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::{BTreeSet, HashSet};
+
+const START_ADDRESS: usize = 0x200;
+
+/// Decodes one instruction into Cowgod-style text, `next` is the word following it (only
+/// used by the 4 bytes long XO-CHIP `LD I, LONG`). Returns None for unknown opcodes.
+pub fn instruction(opcode: u16, next: u16) -> Option<String> {
+    format_instruction(opcode, next, &|address| format!("0x{:03X}", address))
+}
+
+/// Size in bytes of the instruction starting with `opcode`
+pub fn instruction_length(opcode: u16) -> usize {
+    if opcode == 0xF000 {
+        4
+    } else {
+        2
+    }
+}
+
+fn format_instruction(opcode: u16, next: u16, label: &dyn Fn(u16) -> String) -> Option<String> {
+    let x = (opcode & 0x0F00) >> 8;
+    let y = (opcode & 0x00F0) >> 4;
+    let n = opcode & 0x000F;
+    let byte = opcode & 0x00FF;
+    let addr = opcode & 0x0FFF;
+
+    let text = match ((opcode & 0xF000) >> 12, x, y, n) {
+        (0x0, 0x0, 0xC, _) => format!("SCD {}", n),
+        (0x0, 0x0, 0xD, _) => format!("SCU {}", n),
+        (0x0, 0x0, 0xE, 0x0) => "CLS".to_string(),
+        (0x0, 0x0, 0xE, 0xE) => "RET".to_string(),
+        (0x0, 0x0, 0xF, 0xB) => "SCR".to_string(),
+        (0x0, 0x0, 0xF, 0xC) => "SCL".to_string(),
+        (0x0, 0x0, 0xF, 0xD) => "EXIT".to_string(),
+        (0x0, 0x0, 0xF, 0xE) => "LOW".to_string(),
+        (0x0, 0x0, 0xF, 0xF) => "HIGH".to_string(),
+        (0x0, _, _, _) => format!("SYS 0x{:03X}", addr),
+        (0x1, _, _, _) => format!("JP {}", label(addr)),
+        (0x2, _, _, _) => format!("CALL {}", label(addr)),
+        (0x3, _, _, _) => format!("SE V{:X}, 0x{:02X}", x, byte),
+        (0x4, _, _, _) => format!("SNE V{:X}, 0x{:02X}", x, byte),
+        (0x5, _, _, 0x0) => format!("SE V{:X}, V{:X}", x, y),
+        (0x5, _, _, 0x2) => format!("LD [I], V{:X}-V{:X}", x, y),
+        (0x5, _, _, 0x3) => format!("LD V{:X}-V{:X}, [I]", x, y),
+        (0x6, _, _, _) => format!("LD V{:X}, 0x{:02X}", x, byte),
+        (0x7, _, _, _) => format!("ADD V{:X}, 0x{:02X}", x, byte),
+        (0x8, _, _, 0x0) => format!("LD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x1) => format!("OR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x2) => format!("AND V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x3) => format!("XOR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x4) => format!("ADD V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x5) => format!("SUB V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x6) => format!("SHR V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0x7) => format!("SUBN V{:X}, V{:X}", x, y),
+        (0x8, _, _, 0xE) => format!("SHL V{:X}, V{:X}", x, y),
+        (0x9, _, _, 0x0) => format!("SNE V{:X}, V{:X}", x, y),
+        (0xA, _, _, _) => format!("LD I, {}", label(addr)),
+        (0xB, _, _, _) => format!("JP V0, {}", label(addr)),
+        (0xC, _, _, _) => format!("RND V{:X}, 0x{:02X}", x, byte),
+        (0xD, _, _, _) => format!("DRW V{:X}, V{:X}, {}", x, y, n),
+        (0xE, _, 0x9, 0xE) => format!("SKP V{:X}", x),
+        (0xE, _, 0xA, 0x1) => format!("SKNP V{:X}", x),
+        (0xF, 0x0, 0x0, 0x0) => format!("LD I, LONG {}", label(next)),
+        (0xF, _, 0x0, 0x1) => format!("PLANE {}", x),
+        (0xF, 0x0, 0x0, 0x2) => "AUDIO".to_string(),
+        (0xF, _, 0x0, 0x7) => format!("LD V{:X}, DT", x),
+        (0xF, _, 0x0, 0xA) => format!("LD V{:X}, K", x),
+        (0xF, _, 0x1, 0x5) => format!("LD DT, V{:X}", x),
+        (0xF, _, 0x1, 0x8) => format!("LD ST, V{:X}", x),
+        (0xF, _, 0x1, 0xE) => format!("ADD I, V{:X}", x),
+        (0xF, _, 0x2, 0x9) => format!("LD F, V{:X}", x),
+        (0xF, _, 0x3, 0x0) => format!("LD HF, V{:X}", x),
+        (0xF, _, 0x3, 0x3) => format!("LD B, V{:X}", x),
+        (0xF, _, 0x3, 0xA) => format!("PITCH V{:X}", x),
+        (0xF, _, 0x5, 0x5) => format!("LD [I], V{:X}", x),
+        (0xF, _, 0x6, 0x5) => format!("LD V{:X}, [I]", x),
+        (0xF, _, 0x7, 0x5) => format!("LD R, V{:X}", x),
+        (0xF, _, 0x8, 0x5) => format!("LD V{:X}, R", x),
+        _ => return None,
+    };
+
+    Some(text)
+}
+
+/// Disassembles a ROM loaded at 0x200 into a listing the assembler accepts. Code is found by
+/// following the control flow from the entry point, every other byte is emitted as data.
+pub fn disassemble(rom: &[u8]) -> String {
+    let end = START_ADDRESS + rom.len();
+    let word = |address: usize| -> Option<u16> {
+        if address >= START_ADDRESS && address + 1 < end {
+            let offset = address - START_ADDRESS;
+            Some(((rom[offset] as u16) << 8) | rom[offset + 1] as u16)
+        } else {
+            None
+        }
+    };
+
+    let mut code = BTreeSet::new();
+    let mut labels = HashSet::new();
+    let mut pending = vec![START_ADDRESS];
+
+    while let Some(address) = pending.pop() {
+        if code.contains(&address) {
+            continue;
+        }
+
+        let opcode = match word(address) {
+            Some(opcode) => opcode,
+            None => continue,
+        };
+        let next_word = word(address + 2).unwrap_or(0);
+
+        if format_instruction(opcode, next_word, &|_| String::new()).is_none() {
+            continue;
+        }
+
+        code.insert(address);
+
+        let length = instruction_length(opcode);
+        let next = address + length;
+        let target = (opcode & 0x0FFF) as usize;
+
+        match opcode & 0xF000 {
+            0x0000 if opcode == 0x00EE || opcode == 0x00FD => {}
+            0x1000 => {
+                labels.insert(target);
+                pending.push(target);
+            }
+            0x2000 | 0xB000 => {
+                labels.insert(target);
+                pending.push(target);
+
+                if opcode & 0xF000 == 0x2000 {
+                    pending.push(next);
+                }
+            }
+            // 5XY2 and 5XY3 are XO-CHIP register range loads and stores, not skips
+            0x5000 if opcode & 0x000F != 0 => pending.push(next),
+            0x3000 | 0x4000 | 0x5000 | 0x9000 | 0xE000 => {
+                let skipped = word(next).map(instruction_length).unwrap_or(2);
+
+                pending.push(next);
+                pending.push(next + skipped);
+            }
+            0xA000 => {
+                labels.insert(target);
+                pending.push(next);
+            }
+            0xF000 if opcode == 0xF000 => {
+                labels.insert(next_word as usize);
+                pending.push(next);
+            }
+            _ => pending.push(next),
+        }
+    }
+
+    labels.retain(|&address| address >= START_ADDRESS && address < end);
+
+    let label = |address: u16| -> String {
+        if labels.contains(&(address as usize)) {
+            format!("L{:03X}", address)
+        } else {
+            format!("0x{:03X}", address)
+        }
+    };
+
+    let mut listing = String::new();
+    let mut data = Vec::new();
+    let mut address = START_ADDRESS;
+
+    while address < end {
+        if labels.contains(&address) {
+            flush_data(&mut listing, &mut data);
+            listing.push_str(&format!("L{:03X}:\n", address));
+        }
+
+        let instruction = word(address).and_then(|opcode| {
+            let length = instruction_length(opcode);
+            let hides_label = (address + 1..address + length).any(|inner| labels.contains(&inner));
+
+            if !code.contains(&address) || hides_label || address + length > end {
+                return None;
+            }
+
+            let next_word = word(address + 2).unwrap_or(0);
+            format_instruction(opcode, next_word, &label).map(|text| (text, length))
+        });
+
+        match instruction {
+            Some((text, length)) => {
+                flush_data(&mut listing, &mut data);
+                listing.push_str(&format!("    {:<23} ; {:03X}\n", text, address));
+                address += length;
+            }
+            None => {
+                if data.len() == 8 {
+                    flush_data(&mut listing, &mut data);
+                }
+
+                data.push((address, rom[address - START_ADDRESS]));
+                address += 1;
+            }
+        }
+    }
+
+    flush_data(&mut listing, &mut data);
+    listing
+}
+
+fn flush_data(listing: &mut String, data: &mut Vec<(usize, u8)>) {
+    if let Some(&(address, _)) = data.first() {
+        let bytes: Vec<String> = data
+            .iter()
+            .map(|(_, byte)| format!("0x{:02X}", byte))
+            .collect();
+
+        listing.push_str(&format!(
+            "    {:<23} ; {:03X}\n",
+            format!("db {}", bytes.join(", ")),
+            address
+        ));
+        data.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn instructions() {
+        assert_eq!(instruction(0x00E0, 0), Some("CLS".to_string()));
+        assert_eq!(instruction(0x0123, 0), Some("SYS 0x123".to_string()));
+        assert_eq!(instruction(0x12A4, 0), Some("JP 0x2A4".to_string()));
+        assert_eq!(instruction(0x612A, 0), Some("LD V1, 0x2A".to_string()));
+        assert_eq!(instruction(0x8AB7, 0), Some("SUBN VA, VB".to_string()));
+        assert_eq!(instruction(0xD015, 0), Some("DRW V0, V1, 5".to_string()));
+        assert_eq!(instruction(0xF265, 0), Some("LD V2, [I]".to_string()));
+        assert_eq!(instruction(0x00C4, 0), Some("SCD 4".to_string()));
+        assert_eq!(instruction(0x5132, 0), Some("LD [I], V1-V3".to_string()));
+        assert_eq!(
+            instruction(0xF000, 0x1234),
+            Some("LD I, LONG 0x1234".to_string())
+        );
+        assert_eq!(instruction(0xFFFF, 0), None);
+    }
+
+    #[test]
+    fn labels_and_data() {
+        let rom = [
+            0xA2, 0x08, // LD I, L208
+            0x22, 0x06, // CALL L206
+            0x12, 0x04, // JP L204
+            0x00, 0xEE, // RET
+            0xF0, 0x90, // Sprite data
+        ];
+
+        assert_eq!(
+            disassemble(&rom),
+            "    LD I, L208              ; 200\n\
+             \x20   CALL L206               ; 202\n\
+             L204:\n\
+             \x20   JP L204                 ; 204\n\
+             L206:\n\
+             \x20   RET                     ; 206\n\
+             L208:\n\
+             \x20   db 0xF0, 0x90           ; 208\n"
+        );
+    }
+
+    #[test]
+    fn skips_follow_both_paths() {
+        let rom = [
+            0x30, 0x00, // SE V0, 0x00
+            0x00, 0xEE, // RET
+            0x00, 0xEE, // RET
+            0xFF, 0xFF, // Unreachable
+        ];
+        let listing = disassemble(&rom);
+
+        assert_eq!(listing.matches("RET").count(), 2);
+        assert!(listing.contains("db 0xFF, 0xFF"));
+    }
+
+    #[test]
+    fn register_ranges_do_not_skip() {
+        for &opcode in [0x12, 0x13].iter() {
+            let rom = [
+                0x50, opcode, // LD [I], V0-V1 or LD V0-V1, [I]
+                0x00, 0xEE, // RET
+                0x00, 0xE0, // Data
+            ];
+            let listing = disassemble(&rom);
+
+            assert!(listing.contains("V0-V1"));
+            assert!(listing.contains("db 0x00, 0xE0"), "{}", listing);
+            assert!(!listing.contains("CLS"));
+        }
+    }
+
+    #[test]
+    fn long_load() {
+        let rom = [
+            0xF0, 0x00, 0x02, 0x06, // LD I, LONG L206
+            0x00, 0xFD, // EXIT
+            0x42, // Data
+        ];
+        let listing = disassemble(&rom);
+
+        assert!(listing.contains("LD I, LONG L206"));
+        assert!(listing.contains("L206:\n    db 0x42"));
+    }
+}
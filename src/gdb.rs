@@ -0,0 +1,602 @@
+use crate::debugger::{Command, Debugger};
+use crate::vm::VM;
+use crate::watch::{Access, Watchpoint};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Register file exposed to the debugger, multi byte registers are little endian
+const REGISTERS: [(&str, usize); 21] = [
+    ("v0", 1),
+    ("v1", 1),
+    ("v2", 1),
+    ("v3", 1),
+    ("v4", 1),
+    ("v5", 1),
+    ("v6", 1),
+    ("v7", 1),
+    ("v8", 1),
+    ("v9", 1),
+    ("va", 1),
+    ("vb", 1),
+    ("vc", 1),
+    ("vd", 1),
+    ("ve", 1),
+    ("vf", 1),
+    ("i", 2),
+    ("pc", 2),
+    ("sp", 1),
+    ("dt", 1),
+    ("st", 1),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Incoming {
+    Packet(String),
+    Interrupt, // Ctrl-C sent while the game runs
+    BadChecksum,
+}
+
+/// Takes the first complete packet out of `input`, skipping acknowledgements
+pub fn take_packet(input: &mut Vec<u8>) -> Option<Incoming> {
+    loop {
+        match input.first() {
+            Some(b'$') => break,
+            Some(0x03) => {
+                input.remove(0);
+                return Some(Incoming::Interrupt);
+            }
+            Some(_) => {
+                input.remove(0);
+            }
+            None => return None,
+        }
+    }
+
+    let end = input.iter().position(|&byte| byte == b'#')?;
+
+    if input.len() < end + 3 {
+        return None;
+    }
+
+    let packet: Vec<u8> = input.drain(..end + 3).collect();
+    let data = &packet[1..end];
+    let checksum = std::str::from_utf8(&packet[end + 1..])
+        .ok()
+        .and_then(|digits| u8::from_str_radix(digits, 16).ok());
+
+    if checksum != Some(checksum_of(data)) {
+        return Some(Incoming::BadChecksum);
+    }
+
+    Some(Incoming::Packet(String::from_utf8_lossy(data).into_owned()))
+}
+
+pub fn frame_packet(data: &str) -> String {
+    format!("${}#{:02x}", data, checksum_of(data.as_bytes()))
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+// Protocol logic, kept apart from the socket so it can be tested
+pub struct GdbStub {
+    debugger: Debugger,
+    detached: bool,
+}
+
+impl GdbStub {
+    pub fn new() -> GdbStub {
+        GdbStub {
+            debugger: Debugger::new(),
+            detached: false,
+        }
+    }
+
+    pub fn detached(&self) -> bool {
+        self.detached
+    }
+
+    /// Returns the reply to send, None when the target resumed and will reply once it stops
+    pub fn handle_packet(&mut self, packet: &str, vm: &mut VM) -> Option<String> {
+        let (kind, arguments) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+
+        let reply = match kind {
+            "?" => stop_reply(vm),
+            "g" => encode_hex(&read_registers(vm)),
+            "G" => match decode_hex(arguments) {
+                Some(ref bytes) if bytes.len() == register_file_size() => {
+                    write_registers(vm, bytes);
+                    "OK".to_string()
+                }
+                _ => "E01".to_string(),
+            },
+            "p" => match usize::from_str_radix(arguments, 16) {
+                Ok(index) if index < REGISTERS.len() => {
+                    let offset = register_offset(index);
+                    encode_hex(&read_registers(vm)[offset..offset + REGISTERS[index].1])
+                }
+                _ => "E01".to_string(),
+            },
+            "P" => self.write_register(arguments, vm),
+            "m" => match parse_range(arguments, vm) {
+                Some((address, length)) => encode_hex(&vm.memory[address..address + length]),
+                None => "E01".to_string(),
+            },
+            "M" => self.write_memory(arguments, vm),
+            "c" => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    vm.set_pc(address);
+                }
+
+                self.execute(Command::Continue, vm);
+                return None;
+            }
+            "s" => {
+                if let Ok(address) = u16::from_str_radix(arguments, 16) {
+                    vm.set_pc(address);
+                }
+
+                match self.debugger.execute(Command::Step, vm) {
+                    Ok(_) => stop_reply(vm),
+                    Err(_) => "S04".to_string(),
+                }
+            }
+            "Z" | "z" => self.breakpoint(kind == "Z", arguments, vm),
+            "D" => {
+                self.detached = true;
+                "OK".to_string()
+            }
+            "k" => {
+                self.detached = true;
+                return None;
+            }
+            "H" => "OK".to_string(),
+            "q" => query(arguments),
+            _ => String::new(),
+        };
+
+        Some(reply)
+    }
+
+    /// Pauses a running target, returns the stop reply to send
+    pub fn interrupt(&mut self) -> String {
+        self.debugger.pause();
+        "S02".to_string()
+    }
+
+    /// Runs a frame unless stopped, returns the stop reply once a breakpoint or watchpoint hits
+    pub fn run_frame(&mut self, vm: &mut VM) -> Option<String> {
+        if self.debugger.paused() {
+            return None;
+        }
+
+        match self.debugger.run_frame(vm) {
+            Ok(Some(_)) => Some(stop_reply(vm)),
+            Ok(None) if vm.halted() => {
+                self.debugger.pause();
+                Some("W00".to_string())
+            }
+            Ok(None) => None,
+            Err(_) => {
+                self.debugger.pause();
+                Some("S04".to_string())
+            }
+        }
+    }
+
+    fn execute(&mut self, command: Command, vm: &mut VM) {
+        // Only stepping runs instructions, the other commands can't fail
+        let _ = self.debugger.execute(command, vm);
+    }
+
+    fn write_register(&mut self, arguments: &str, vm: &mut VM) -> String {
+        let mut parts = arguments.splitn(2, '=');
+        let index = parts
+            .next()
+            .and_then(|index| usize::from_str_radix(index, 16).ok());
+        let value = parts.next().and_then(decode_hex);
+
+        match (index, value) {
+            (Some(index), Some(value))
+                if index < REGISTERS.len() && value.len() == REGISTERS[index].1 =>
+            {
+                let mut registers = read_registers(vm);
+                let offset = register_offset(index);
+
+                registers[offset..offset + value.len()].copy_from_slice(&value);
+                write_registers(vm, &registers);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, arguments: &str, vm: &mut VM) -> String {
+        let mut parts = arguments.splitn(2, ':');
+        let range = parts.next().and_then(|range| parse_range(range, vm));
+        let data = parts.next().and_then(decode_hex);
+
+        match (range, data) {
+            (Some((address, length)), Some(ref data)) if data.len() == length => {
+                vm.memory[address..address + length].copy_from_slice(data);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    // Z0/Z1 are breakpoints, Z2/Z3/Z4 watchpoints. The VM watches reads and writes together.
+    fn breakpoint(&mut self, insert: bool, arguments: &str, vm: &mut VM) -> String {
+        let parts: Vec<&str> = arguments.split(',').collect();
+        let (kind, address, length) = match parts.as_slice() {
+            [kind, address, length] => (
+                *kind,
+                usize::from_str_radix(address, 16),
+                usize::from_str_radix(length, 16),
+            ),
+            _ => return "E01".to_string(),
+        };
+        let (address, length) = match (address, length) {
+            (Ok(address), Ok(length)) => (address, length),
+            _ => return "E01".to_string(),
+        };
+
+        match kind {
+            "0" | "1" => {
+                let command = if insert {
+                    Command::Break(Some(address as u16))
+                } else {
+                    Command::Delete(address as u16)
+                };
+
+                self.execute(command, vm);
+            }
+            "2" | "3" | "4" => {
+                let watchpoint = Watchpoint::Memory {
+                    address,
+                    length: length.max(1),
+                };
+
+                if insert {
+                    vm.add_watchpoint(watchpoint);
+                } else {
+                    vm.remove_watchpoint(watchpoint);
+                }
+            }
+            _ => return String::new(),
+        }
+
+        "OK".to_string()
+    }
+}
+
+impl Default for GdbStub {
+    fn default() -> GdbStub {
+        GdbStub::new()
+    }
+}
+
+fn query(query: &str) -> String {
+    if query.starts_with("Supported") {
+        return "PacketSize=1000;qXfer:features:read+".to_string();
+    }
+
+    if let Some(range) = query.strip_prefix("Xfer:features:read:target.xml:") {
+        let xml = target_description();
+        let mut parts = range.split(',');
+        let offset = parts
+            .next()
+            .and_then(|value| usize::from_str_radix(value, 16).ok());
+        let length = parts
+            .next()
+            .and_then(|value| usize::from_str_radix(value, 16).ok());
+
+        return match (offset, length) {
+            (Some(offset), Some(length)) if offset <= xml.len() => {
+                let end = offset.saturating_add(length).min(xml.len());
+                let marker = if end == xml.len() { 'l' } else { 'm' };
+
+                format!("{}{}", marker, &xml[offset..end])
+            }
+            _ => "E01".to_string(),
+        };
+    }
+
+    match query {
+        "Attached" => "1".to_string(),
+        "C" => "QC1".to_string(),
+        "fThreadInfo" => "m1".to_string(),
+        "sThreadInfo" => "l".to_string(),
+        _ => String::new(),
+    }
+}
+
+fn target_description() -> String {
+    let registers: String = REGISTERS
+        .iter()
+        .map(|(name, size)| {
+            let kind = match *name {
+                "pc" => "code_ptr",
+                "i" => "data_ptr",
+                _ => "int",
+            };
+
+            format!(
+                "<reg name=\"{}\" bitsize=\"{}\" type=\"{}\"/>",
+                name,
+                size * 8,
+                kind
+            )
+        })
+        .collect();
+
+    format!(
+        "<?xml version=\"1.0\"?><!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+         <target version=\"1.0\"><feature name=\"org.emuchip8.cpu\">{}</feature></target>",
+        registers
+    )
+}
+
+fn stop_reply(vm: &VM) -> String {
+    match vm.watch_hit().map(|hit| hit.access) {
+        Some(Access::Read(address)) => format!("T05rwatch:{:x};", address),
+        Some(Access::Write(address)) => format!("T05watch:{:x};", address),
+        _ => "S05".to_string(),
+    }
+}
+
+fn register_file_size() -> usize {
+    REGISTERS.iter().map(|(_, size)| size).sum()
+}
+
+fn register_offset(index: usize) -> usize {
+    REGISTERS[..index].iter().map(|(_, size)| size).sum()
+}
+
+fn read_registers(vm: &VM) -> Vec<u8> {
+    let mut registers = vm.v().to_vec();
+
+    registers.extend_from_slice(&vm.i().to_le_bytes());
+    registers.extend_from_slice(&vm.pc().to_le_bytes());
+    registers.push(vm.sp() as u8);
+    registers.push(vm.delay_timer());
+    registers.push(vm.sound_timer());
+    registers
+}
+
+// The stack pointer is read only, changing it would desync the call stack
+fn write_registers(vm: &mut VM, registers: &[u8]) {
+    for (x, &value) in registers[..16].iter().enumerate() {
+        vm.set_v(x, value);
+    }
+
+    vm.set_i(u16::from_le_bytes([registers[16], registers[17]]));
+    vm.set_pc(u16::from_le_bytes([registers[18], registers[19]]));
+    vm.set_delay_timer(registers[21]);
+    vm.set_sound_timer(registers[22]);
+}
+
+// "ADDR,LENGTH" in hex, validated against the VM address space
+fn parse_range(range: &str, vm: &VM) -> Option<(usize, usize)> {
+    let mut parts = range.split(',');
+    let address = usize::from_str_radix(parts.next()?, 16).ok()?;
+    let length = usize::from_str_radix(parts.next()?, 16).ok()?;
+
+    address
+        .checked_add(length)
+        .filter(|&end| end <= vm.memory_size())?;
+
+    Some((address, length))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 == 1 {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+// Serves a single debugger connection without blocking the emulator loop
+pub struct GdbServer {
+    stream: TcpStream,
+    input: Vec<u8>,
+    stub: GdbStub,
+}
+
+impl GdbServer {
+    /// Blocks until a debugger connects to the port on localhost
+    pub fn listen(port: u16) -> io::Result<GdbServer> {
+        let listener = TcpListener::bind(("127.0.0.1", port))?;
+        let (stream, _) = listener.accept()?;
+
+        stream.set_nodelay(true)?;
+        stream.set_nonblocking(true)?;
+
+        Ok(GdbServer {
+            stream,
+            input: Vec::new(),
+            stub: GdbStub::new(),
+        })
+    }
+
+    /// Handles the pending packets, then runs a frame unless the target is stopped.
+    /// Returns false once the debugger detached or disconnected.
+    pub fn update(&mut self, vm: &mut VM) -> io::Result<bool> {
+        let mut buffer = [0; 4096];
+
+        loop {
+            match self.stream.read(&mut buffer) {
+                Ok(0) => return Ok(false),
+                Ok(count) => self.input.extend_from_slice(&buffer[..count]),
+                Err(ref error) if error.kind() == io::ErrorKind::WouldBlock => break,
+                Err(error) => return Err(error),
+            }
+        }
+
+        while let Some(incoming) = take_packet(&mut self.input) {
+            match incoming {
+                Incoming::Packet(packet) => {
+                    self.send(b"+")?;
+
+                    if let Some(reply) = self.stub.handle_packet(&packet, vm) {
+                        self.send(frame_packet(&reply).as_bytes())?;
+                    }
+                }
+                Incoming::Interrupt => {
+                    let reply = self.stub.interrupt();
+                    self.send(frame_packet(&reply).as_bytes())?;
+                }
+                Incoming::BadChecksum => self.send(b"-")?,
+            }
+
+            if self.stub.detached() {
+                return Ok(false);
+            }
+        }
+
+        if let Some(reply) = self.stub.run_frame(vm) {
+            self.send(frame_packet(&reply).as_bytes())?;
+        }
+
+        Ok(true)
+    }
+
+    // Replies are written in blocking mode, they are small and the debugger is waiting for them
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.set_nonblocking(false)?;
+        self.stream.write_all(data)?;
+        self.stream.set_nonblocking(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // LD V0, 0x2A; ADD V0, 1; JP 0x202
+    fn vm() -> VM {
+        let mut vm = VM::initialize(false);
+        vm.load_rom(&[0x60, 0x2A, 0x70, 0x01, 0x12, 0x02]).unwrap();
+        vm
+    }
+
+    #[test]
+    fn packets() {
+        let mut input = b"+$g#67$m200,2#".to_vec();
+
+        assert_eq!(
+            take_packet(&mut input),
+            Some(Incoming::Packet("g".to_string()))
+        );
+        assert_eq!(take_packet(&mut input), None);
+
+        input.extend_from_slice(b"00\x03$s#00");
+
+        assert_eq!(take_packet(&mut input), Some(Incoming::BadChecksum));
+        assert_eq!(take_packet(&mut input), Some(Incoming::Interrupt));
+        assert_eq!(take_packet(&mut input), Some(Incoming::BadChecksum));
+        assert_eq!(frame_packet("OK"), "$OK#9a");
+    }
+
+    #[test]
+    fn registers() {
+        let mut vm = vm();
+        let mut stub = GdbStub::new();
+        vm.set_i(0x123);
+
+        let registers = stub.handle_packet("g", &mut vm).unwrap();
+
+        assert_eq!(registers.len(), 46);
+        assert_eq!(&registers[32..40], "23010002");
+        assert_eq!(stub.handle_packet("P0=ff", &mut vm), Some("OK".to_string()));
+        assert_eq!(vm.v()[0x0], 0xFF);
+        assert_eq!(stub.handle_packet("p11", &mut vm), Some("0002".to_string()));
+    }
+
+    #[test]
+    fn memory() {
+        let mut vm = vm();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(
+            stub.handle_packet("m200,2", &mut vm),
+            Some("602a".to_string())
+        );
+        assert_eq!(
+            stub.handle_packet("M300,2:beef", &mut vm),
+            Some("OK".to_string())
+        );
+        assert_eq!(vm.memory[0x301], 0xEF);
+        assert_eq!(
+            stub.handle_packet("mfff,2", &mut vm),
+            Some("E01".to_string())
+        );
+        assert_eq!(
+            stub.handle_packet("m1,ffffffffffffffff", &mut vm),
+            Some("E01".to_string())
+        );
+        assert_eq!(
+            stub.handle_packet("M300,1:é", &mut vm),
+            Some("E01".to_string())
+        );
+        assert_eq!(stub.handle_packet("é", &mut vm), Some(String::new()));
+        assert_eq!(
+            stub.handle_packet("Z2,1,ffffffffffffffff", &mut vm),
+            Some("OK".to_string())
+        );
+        assert!(vm.watchpoints()[0].contains(usize::MAX));
+    }
+
+    #[test]
+    fn step_and_continue() {
+        let mut vm = vm();
+        let mut stub = GdbStub::new();
+
+        assert_eq!(stub.handle_packet("s", &mut vm), Some("S05".to_string()));
+        assert_eq!(vm.pc(), 0x202);
+
+        assert_eq!(
+            stub.handle_packet("Z0,204,2", &mut vm),
+            Some("OK".to_string())
+        );
+        assert_eq!(stub.handle_packet("c", &mut vm), None);
+        assert_eq!(stub.run_frame(&mut vm), Some("S05".to_string()));
+        assert_eq!(vm.pc(), 0x204);
+        assert_eq!(vm.v()[0x0], 0x2B);
+
+        assert_eq!(
+            stub.handle_packet("z0,204,2", &mut vm),
+            Some("OK".to_string())
+        );
+        assert_eq!(stub.handle_packet("c", &mut vm), None);
+        assert_eq!(stub.run_frame(&mut vm), None);
+        assert_eq!(stub.interrupt(), "S02");
+    }
+
+    #[test]
+    fn target_xml() {
+        let reply = query("Xfer:features:read:target.xml:0,ffff");
+
+        assert!(reply.starts_with("l<?xml"));
+        assert!(reply.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>"));
+
+        let mut stub = GdbStub::new();
+        let reply = stub
+            .handle_packet(
+                "qXfer:features:read:target.xml:1,ffffffffffffffff",
+                &mut vm(),
+            )
+            .unwrap();
+
+        assert!(reply.starts_with("l?xml"));
+    }
+}
@@ -0,0 +1,439 @@
+use crate::controller::{self, ControllerMap};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+// Hex keypad keys in the order of the presets' entries, row by row:
+// 1 2 3 C / 4 5 6 D / 7 8 9 E / A 0 B F
+const KEYPAD: [usize; 16] = [
+    0x1, 0x2, 0x3, 0xC, 0x4, 0x5, 0x6, 0xD, 0x7, 0x8, 0x9, 0xE, 0xA, 0x0, 0xB, 0xF,
+];
+
+// The keyboard keys at the top left of each layout, alternatives separated by spaces
+const PRESETS: [(&str, [&str; 16]); 3] = [
+    (
+        "qwerty",
+        [
+            "1", "2", "3", "4", "q", "w", "e", "r", "a", "s", "d", "f", "z", "x", "c", "v",
+        ],
+    ),
+    (
+        // The number row reports its unshifted symbols on AZERTY
+        "azerty",
+        [
+            "1 &", "2 é", "3 \"", "4 '", "a", "z", "e", "r", "q", "s", "d", "f", "w", "x", "c", "v",
+        ],
+    ),
+    (
+        "dvorak",
+        [
+            "1", "2", "3", "4", "'", ",", ".", "p", "a", "o", "e", "u", ";", "q", "j", "k",
+        ],
+    ),
+];
+
+#[derive(Debug, PartialEq)]
+pub struct KeymapError {
+    pub message: String,
+}
+
+impl KeymapError {
+    fn new(message: String) -> KeymapError {
+        KeymapError { message }
+    }
+}
+
+impl fmt::Display for KeymapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for KeymapError {}
+
+/// Names of the built-in layouts
+pub fn presets() -> Vec<&'static str> {
+    PRESETS.iter().map(|(name, _)| *name).collect()
+}
+
+/// A preset name, or the path of a keymap file
+pub fn load(name: &str) -> Result<KeymapConfig, KeymapError> {
+    if Keymap::preset(name).is_some() {
+        return Ok(KeymapConfig {
+            base: Profile {
+                preset: Some(name.to_string()),
+                ..Profile::default()
+            },
+            roms: Vec::new(),
+        });
+    }
+
+    fs::read_to_string(name)
+        .map_err(|error| KeymapError::new(error.to_string()))
+        .and_then(|text| KeymapConfig::parse(&text))
+        .map_err(|error| KeymapError::new(format!("{}: {}", name, error)))
+}
+
+/// Turns the key names of SDL, browsers and terminals into the ones used by keymaps:
+/// lowercase characters, and words like `up`, `space` or `enter` for the other keys
+pub fn key_name(name: &str) -> String {
+    let lower = name.to_lowercase();
+    let lower = lower.strip_prefix("arrow").unwrap_or(&lower);
+
+    match lower {
+        " " => "space",
+        "return" => "enter",
+        "esc" => "escape",
+        "left shift" | "right shift" => "shift",
+        "left ctrl" | "right ctrl" | "control" => "ctrl",
+        "left alt" | "right alt" => "alt",
+        other => other,
+    }
+    .to_string()
+}
+
+/// Keyboard keys bound to the hex keypad
+#[derive(Clone, Debug, PartialEq)]
+pub struct Keymap {
+    keys: HashMap<String, usize>,
+}
+
+impl Keymap {
+    pub fn preset(name: &str) -> Option<Keymap> {
+        let (_, names) = PRESETS
+            .iter()
+            .find(|(preset, _)| preset.eq_ignore_ascii_case(name))?;
+        let mut keymap = Keymap {
+            keys: HashMap::new(),
+        };
+
+        for (alternatives, &key) in names.iter().zip(KEYPAD.iter()) {
+            for name in alternatives.split(' ') {
+                keymap.bind(name, Some(key));
+            }
+        }
+
+        Some(keymap)
+    }
+
+    /// Binds a keyboard key to a hex keypad key, `None` removes the binding
+    pub fn bind(&mut self, name: &str, key: Option<usize>) {
+        match key {
+            Some(key) => self.keys.insert(key_name(name), key),
+            None => self.keys.remove(&key_name(name)),
+        };
+    }
+
+    /// The hex keypad key a keyboard key is bound to
+    pub fn key(&self, name: &str) -> Option<usize> {
+        self.keys.get(&key_name(name)).cloned()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap::preset("qwerty").unwrap()
+    }
+}
+
+// A preset and the bindings changed on top of it
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Profile {
+    preset: Option<String>,
+    keys: Vec<(String, Option<usize>)>,
+    buttons: Vec<(String, Option<usize>)>, // Game controller inputs
+}
+
+/// A keymap file: a preset, extra bindings, game controller bindings, and profiles for
+/// single ROMs that apply on top. Both the native and the web frontends read this JSON format:
+///
+/// ```json
+/// {
+///   "preset": "azerty",
+///   "keys": { "space": "5" },
+///   "buttons": { "x": "7" },
+///   "roms": { "PONG": { "keys": { "up": "1", "down": "4", "a": null } } }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeymapConfig {
+    base: Profile,
+    roms: Vec<(String, Profile)>,
+}
+
+impl KeymapConfig {
+    pub fn parse(text: &str) -> Result<KeymapConfig, KeymapError> {
+        let value: Value =
+            serde_json::from_str(text).map_err(|error| KeymapError::new(error.to_string()))?;
+        let base = parse_profile(&value, "keymap")?;
+        let roms = match value.get("roms") {
+            None => Vec::new(),
+            Some(Value::Object(roms)) => roms
+                .iter()
+                .map(|(rom, profile)| Ok((rom.clone(), parse_profile(profile, rom)?)))
+                .collect::<Result<_, KeymapError>>()?,
+            Some(_) => return Err(KeymapError::new("roms must be an object".to_string())),
+        };
+
+        Ok(KeymapConfig { base, roms })
+    }
+
+    /// The keymap for `rom`, a file name or path matched against the ROM profiles with or
+    /// without its extension, ignoring case
+    pub fn keymap(&self, rom: Option<&str>) -> Keymap {
+        let profile = rom.and_then(|rom| self.profile(rom));
+        let preset = profile
+            .and_then(|profile| profile.preset.as_ref())
+            .or(self.base.preset.as_ref())
+            .and_then(|name| Keymap::preset(name));
+        let mut keymap = preset.unwrap_or_default();
+
+        for profile in Some(&self.base).into_iter().chain(profile) {
+            for (name, key) in profile.keys.iter() {
+                keymap.bind(name, *key);
+            }
+        }
+
+        keymap
+    }
+
+    /// The game controller bindings for `rom`, matched like in `keymap`
+    pub fn controller_map(&self, rom: Option<&str>) -> ControllerMap {
+        let profile = rom.and_then(|rom| self.profile(rom));
+        let mut map = ControllerMap::default();
+
+        for profile in Some(&self.base).into_iter().chain(profile) {
+            for (input, key) in profile.buttons.iter() {
+                map.bind(input, *key);
+            }
+        }
+
+        map
+    }
+
+    fn profile(&self, rom: &str) -> Option<&Profile> {
+        self.roms
+            .iter()
+            .find(|(name, _)| rom_matches(name, rom))
+            .map(|(_, profile)| profile)
+    }
+}
+
+/// Whether a ROM profile `name` applies to `rom`, a file name or path matched with or without
+/// its extension, ignoring case
+pub fn rom_matches(name: &str, rom: &str) -> bool {
+    let path = Path::new(rom);
+
+    path.file_name()
+        .into_iter()
+        .chain(path.file_stem())
+        .any(|candidate| candidate.to_string_lossy().eq_ignore_ascii_case(name))
+}
+
+fn parse_profile(value: &Value, context: &str) -> Result<Profile, KeymapError> {
+    let invalid = |message: &str| KeymapError::new(format!("{}: {}", context, message));
+    let object = value
+        .as_object()
+        .ok_or_else(|| invalid("expected an object"))?;
+    let preset = match object.get("preset") {
+        None => None,
+        Some(Value::String(name)) if Keymap::preset(name).is_some() => Some(name.clone()),
+        Some(preset) => return Err(invalid(&format!("unknown preset {}", preset))),
+    };
+    let keys = match object.get("keys") {
+        None => Vec::new(),
+        Some(Value::Object(keys)) => keys
+            .iter()
+            .map(|(name, key)| match parse_key(key) {
+                Some(key) => Ok((name.clone(), key)),
+                None => Err(invalid(&format!("invalid key {} for {}", key, name))),
+            })
+            .collect::<Result<_, KeymapError>>()?,
+        Some(_) => return Err(invalid("keys must be an object")),
+    };
+    let buttons = match object.get("buttons") {
+        None => Vec::new(),
+        Some(Value::Object(buttons)) => buttons
+            .iter()
+            .map(|(input, key)| match parse_key(key) {
+                _ if !controller::is_input(input) => {
+                    Err(invalid(&format!("unknown controller input {}", input)))
+                }
+                Some(key) => Ok((input.clone(), key)),
+                None => Err(invalid(&format!("invalid key {} for {}", key, input))),
+            })
+            .collect::<Result<_, KeymapError>>()?,
+        Some(_) => return Err(invalid("buttons must be an object")),
+    };
+
+    Ok(Profile {
+        preset,
+        keys,
+        buttons,
+    })
+}
+
+// A hex digit like "A" or a number from 0 to 15, null for no key
+fn parse_key(value: &Value) -> Option<Option<usize>> {
+    let key = match value {
+        Value::Null => return Some(None),
+        Value::String(digit) if digit.len() == 1 => usize::from_str_radix(digit, 16).ok()?,
+        Value::Number(number) => number.as_u64()? as usize,
+        _ => return None,
+    };
+
+    if key < 16 {
+        Some(Some(key))
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qwerty_is_default() {
+        let keymap = Keymap::default();
+
+        assert_eq!(keymap.key("1"), Some(0x1));
+        assert_eq!(keymap.key("4"), Some(0xC));
+        assert_eq!(keymap.key("Q"), Some(0x4));
+        assert_eq!(keymap.key("x"), Some(0x0));
+        assert_eq!(keymap.key("v"), Some(0xF));
+        assert_eq!(keymap.key("Up"), None);
+    }
+
+    #[test]
+    fn presets_cover_keypad() {
+        for name in presets() {
+            let keymap = Keymap::preset(name).unwrap();
+            let mut keys: Vec<usize> = keymap.keys.values().cloned().collect();
+            keys.sort_unstable();
+            keys.dedup();
+
+            assert_eq!(keys, (0..16).collect::<Vec<usize>>(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn azerty_and_dvorak() {
+        let azerty = Keymap::preset("AZERTY").unwrap();
+        let dvorak = Keymap::preset("dvorak").unwrap();
+
+        assert_eq!(azerty.key("a"), Some(0x4));
+        assert_eq!(azerty.key("&"), Some(0x1));
+        assert_eq!(azerty.key("w"), Some(0xA));
+        assert_eq!(dvorak.key("'"), Some(0x4));
+        assert_eq!(dvorak.key("o"), Some(0x8));
+        assert_eq!(Keymap::preset("colemak"), None);
+    }
+
+    #[test]
+    fn key_names() {
+        assert_eq!(key_name("ArrowUp"), "up");
+        assert_eq!(key_name("Up"), "up");
+        assert_eq!(key_name(" "), "space");
+        assert_eq!(key_name("Space"), "space");
+        assert_eq!(key_name("Return"), "enter");
+        assert_eq!(key_name("Left Shift"), "shift");
+        assert_eq!(key_name("Q"), "q");
+    }
+
+    #[test]
+    fn rom_profiles() {
+        let config = KeymapConfig::parse(
+            r#"{
+                "preset": "dvorak",
+                "keys": { "space": "5" },
+                "roms": {
+                    "PONG": { "keys": { "ArrowUp": "1", "down": 4, "a": null } },
+                    "brix.ch8": { "preset": "qwerty" }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let other = config.keymap(Some("roms/TETRIS"));
+        assert_eq!(other.key("o"), Some(0x8));
+        assert_eq!(other.key("space"), Some(0x5));
+        assert_eq!(other.key("up"), None);
+
+        let pong = config.keymap(Some("www/roms/pong"));
+        assert_eq!(pong.key("up"), Some(0x1));
+        assert_eq!(pong.key("down"), Some(0x4));
+        assert_eq!(pong.key("a"), None);
+        assert_eq!(pong.key(" "), Some(0x5));
+
+        let brix = config.keymap(Some("BRIX.ch8"));
+        assert_eq!(brix.key("s"), Some(0x8));
+        assert_eq!(brix.key("space"), Some(0x5));
+
+        assert_eq!(config.keymap(None), other);
+    }
+
+    #[test]
+    fn controller_profiles() {
+        let config = KeymapConfig::parse(
+            r#"{
+                "buttons": { "x": "7", "b": null },
+                "roms": { "PONG": { "buttons": { "dpup": "1", "dpdown": 4 } } }
+            }"#,
+        )
+        .unwrap();
+
+        let other = config.controller_map(Some("BRIX"));
+        assert_eq!(other.key("x"), Some(0x7));
+        assert_eq!(other.key("b"), None);
+        assert_eq!(other.key("dpup"), Some(0x2));
+
+        let pong = config.controller_map(Some("roms/PONG"));
+        assert_eq!(pong.key("dpup"), Some(0x1));
+        assert_eq!(pong.key("dpdown"), Some(0x4));
+        assert_eq!(pong.key("a"), Some(0x5));
+    }
+
+    #[test]
+    fn load_preset() {
+        let config = load("dvorak").unwrap();
+
+        assert_eq!(config.keymap(Some("PONG")).key("o"), Some(0x8));
+        assert_eq!(config.controller_map(None), ControllerMap::default());
+    }
+
+    #[test]
+    fn web_keymap() {
+        let config = KeymapConfig::parse(include_str!("../www/keymap.json")).unwrap();
+
+        assert_eq!(config.keymap(Some("PONG")).key("ArrowUp"), Some(0x1));
+        assert_eq!(config.keymap(Some("PONG")).key("q"), Some(0x4));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| KeymapConfig::parse(text).unwrap_err().to_string();
+
+        assert_eq!(
+            error(r#"{ "preset": "colemak" }"#),
+            "keymap: unknown preset \"colemak\""
+        );
+        assert_eq!(
+            error(r#"{ "keys": { "up": "G" } }"#),
+            "keymap: invalid key \"G\" for up"
+        );
+        assert_eq!(
+            error(r#"{ "roms": { "PONG": { "keys": { "up": 16 } } } }"#),
+            "PONG: invalid key 16 for up"
+        );
+        assert_eq!(
+            error(r#"{ "buttons": { "turbo": "5" } }"#),
+            "keymap: unknown controller input turbo"
+        );
+        assert_eq!(error(r#"[]"#), "keymap: expected an object");
+        assert!(error("{").starts_with("EOF while parsing"));
+    }
+}
@@ -1,13 +1,36 @@
 extern crate wasm_bindgen;
 
-mod vm;
+pub mod asm;
+pub mod audio;
+pub mod controller;
+pub mod debugger;
+pub mod disasm;
+pub mod gdb;
+pub mod keymap;
+pub mod movie;
+pub mod quirks;
+pub mod random;
+pub mod recording;
+pub mod rewind;
+pub mod screenshot;
+pub mod state;
+pub mod terminal;
+pub mod vm;
+pub mod watch;
 
-use vm::VM;
+use audio::Tone;
+use keymap::{Keymap, KeymapConfig};
+use quirks::{Quirks, QuirksConfig};
+use rewind::Rewind;
+use vm::{Mode, TIMER_FREQUENCY, VM};
 use wasm_bindgen::prelude::*;
 
 #[wasm_bindgen]
 pub struct Emulator {
   vm: vm::VM,
+  rewind: Rewind,
+  tone: Tone,
+  keymap: Keymap,
 }
 
 #[wasm_bindgen]
@@ -15,13 +38,65 @@ impl Emulator {
   pub fn new() -> Emulator {
     Emulator {
       vm: VM::initialize(false),
+      rewind: Rewind::default(),
+      tone: Tone::default(),
+      keymap: Keymap::default(),
     }
   }
 
+  pub fn set_mode(&mut self, name: &str) -> Result<(), JsValue> {
+    match Mode::from_name(name) {
+      Some(mode) => {
+        self.vm = VM::with_mode(mode, false);
+        self.rewind.clear();
+        Ok(())
+      }
+      None => Err(JsValue::from_str(&format!("Unknown mode: {}", name))),
+    }
+  }
+
+  pub fn set_quirks(&mut self, name: &str) -> Result<(), JsValue> {
+    match Quirks::from_name(name) {
+      Some(quirks) => {
+        self.vm.set_quirks(quirks);
+        Ok(())
+      }
+      None => Err(JsValue::from_str(&format!("Unknown quirks: {}", name))),
+    }
+  }
+
+  /// Uses a quirks file, with the profile of `rom` when it has one
+  pub fn set_quirks_config(&mut self, config: &str, rom: &str) -> Result<(), JsValue> {
+    let config =
+      QuirksConfig::parse(config).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    let quirks = config.quirks(Some(rom), self.vm.mode());
+    self.vm.set_quirks(quirks);
+    Ok(())
+  }
+
+  /// Changes a single quirk, like `clip_sprites`
+  pub fn set_quirk(&mut self, name: &str, value: bool) -> Result<(), JsValue> {
+    let mut quirks = self.vm.quirks();
+
+    if !quirks.set(name, value) {
+      return Err(JsValue::from_str(&format!("Unknown quirk: {}", name)));
+    }
+
+    self.vm.set_quirks(quirks);
+    Ok(())
+  }
+
   pub fn load_fontset(&mut self) {
     self.vm.load_fontset();
   }
 
+  pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), JsValue> {
+    self
+      .vm
+      .load_rom(rom)
+      .map_err(|error| JsValue::from_str(&error.to_string()))
+  }
+
   pub fn get_memory(&mut self) -> *const u8 {
     self.vm.memory.as_ptr()
   }
@@ -30,19 +105,132 @@ impl Emulator {
     self.vm.gfx.as_ptr()
   }
 
+  pub fn width(&self) -> usize {
+    self.vm.width()
+  }
+
+  pub fn height(&self) -> usize {
+    self.vm.height()
+  }
+
+  pub fn halted(&self) -> bool {
+    self.vm.halted()
+  }
+
+  pub fn waiting_for_key(&self) -> bool {
+    self.vm.waiting_for_key()
+  }
+
   pub fn get_keys(&mut self) -> *const bool {
     self.vm.key.as_ptr()
   }
 
-  pub fn tick(&mut self) {
-    self.vm.emulate_cycle();
+  /// Uses a keymap file, with the profile of `rom` when it has one
+  pub fn set_keymap(&mut self, config: &str, rom: &str) -> Result<(), JsValue> {
+    let config =
+      KeymapConfig::parse(config).map_err(|error| JsValue::from_str(&error.to_string()))?;
+    self.keymap = config.keymap(Some(rom));
+    Ok(())
+  }
+
+  /// Presses the key bound to a `KeyboardEvent.key`, false when it is not bound
+  pub fn key_down(&mut self, name: &str) -> bool {
+    self.set_key(name, true)
+  }
+
+  /// Releases the key bound to a `KeyboardEvent.key`, false when it is not bound
+  pub fn key_up(&mut self, name: &str) -> bool {
+    self.set_key(name, false)
+  }
+
+  pub fn tick(&mut self) -> Result<(), JsValue> {
+    self
+      .vm
+      .emulate_cycle()
+      .map_err(|error| JsValue::from_str(&error.to_string()))
+  }
+
+  pub fn run_frame(&mut self) -> Result<(), JsValue> {
+    self
+      .vm
+      .run_frame()
+      .map_err(|error| JsValue::from_str(&error.to_string()))?;
+    self.rewind.record(&self.vm);
+    Ok(())
+  }
+
+  pub fn rewind(&mut self) -> bool {
+    self.rewind.step_back(&mut self.vm)
+  }
+
+  pub fn set_clock_speed(&mut self, instructions_per_second: u32) {
+    self.vm.set_clock_speed(instructions_per_second);
   }
 
   pub fn draw_flag(&mut self) -> bool {
     self.vm.draw_flag
   }
 
+  pub fn save_state(&self) -> Vec<u8> {
+    self.vm.save_state()
+  }
+
+  pub fn load_state(&mut self, state: &[u8]) -> Result<(), JsValue> {
+    self
+      .vm
+      .load_state(state)
+      .map_err(|error| JsValue::from_str(&error.to_string()))
+  }
+
+  pub fn set_volume(&mut self, volume: f32) {
+    self.tone.settings.volume = volume;
+  }
+
+  pub fn set_tone_frequency(&mut self, frequency: f32) {
+    self.tone.settings.frequency = frequency;
+  }
+
+  pub fn set_muted(&mut self, muted: bool) {
+    self.tone.settings.muted = muted;
+  }
+
+  pub fn sound_active(&self) -> bool {
+    self.vm.sound_active() && !self.tone.settings.muted
+  }
+
+  /// Samples for one 60 Hz frame of the buzzer at the given sample rate
+  pub fn audio_frame(&mut self, sample_rate: u32) -> Vec<f32> {
+    let mut samples = vec![0.0; (sample_rate / TIMER_FREQUENCY) as usize];
+    self.tone.update(&self.vm);
+    self.tone.fill(sample_rate, &mut samples);
+    samples
+  }
+
   pub fn reset(&mut self) {
     self.vm.reset();
+    self.rewind.clear();
   }
 }
+
+impl Default for Emulator {
+  fn default() -> Emulator {
+    Emulator::new()
+  }
+}
+
+impl Emulator {
+  fn set_key(&mut self, name: &str, pressed: bool) -> bool {
+    match self.keymap.key(name) {
+      Some(key) => {
+        self.vm.set_key(key, pressed);
+        true
+      }
+      None => false,
+    }
+  }
+}
+
+#[wasm_bindgen]
+pub fn disassemble(rom: &[u8]) -> String {
+  disasm::disassemble(rom)
+}
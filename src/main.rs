@@ -1,32 +1,280 @@
+extern crate emuchip_8;
 extern crate sdl2;
 
-mod vm;
-
+use emuchip_8::asm;
+use emuchip_8::audio::{AudioSettings, Tone};
+use emuchip_8::controller::{ControllerEvent, Controllers};
+use emuchip_8::debugger::{self, Command, Debugger};
+use emuchip_8::disasm;
+use emuchip_8::gdb::GdbServer;
+use emuchip_8::keymap::{self, Keymap, KeymapConfig};
+use emuchip_8::movie::Movie;
+use emuchip_8::quirks::{self, Quirks, QuirksConfig};
+use emuchip_8::recording::Recorder;
+use emuchip_8::rewind::Rewind;
+use emuchip_8::screenshot::{self, Style};
+use emuchip_8::vm::{Mode, DEFAULT_CLOCK_SPEED, PALETTE, TIMER_FREQUENCY, VM};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
+use sdl2::controller::GameController;
 use sdl2::event::Event;
-use sdl2::keyboard::Keycode;
+use sdl2::keyboard::{Keycode, Scancode};
 use sdl2::pixels::Color;
 use sdl2::rect::Rect;
+use std::collections::HashMap;
 use std::env;
-use std::time::Duration;
-use vm::VM;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+use std::process;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
 
-fn main() {
-    let args: Vec<String> = env::args().collect();
+const USAGE: &str = "Usage: emuchip-8 ROM [--debug] [--debugger] [--gdb PORT] \
+                     [--speed INSTRUCTIONS_PER_SECOND] \
+                     [--mode chip8|schip|xochip] [--quirks FILE|vip|chip48|schip|xochip] \
+                     [--quirk NAME=0|1]... [--record SECONDS] \
+                     [--volume PERCENT] [--frequency HZ] [--mute] \
+                     [--seed N] [--record-movie FILE | --play-movie FILE] \
+                     [--keymap FILE|qwerty|azerty|dvorak] [--disassemble]\n       emuchip-8 assemble SOURCE [-o ROM]";
+const SAVE_SLOTS: u8 = 10;
+const WINDOW_TITLE: &str = "EmuChip-8";
+const WINDOW_WIDTH: u32 = 640;
+const WINDOW_HEIGHT: u32 = 320;
+
+struct Options {
+    rom: String,
+    debug: bool,
+    debugger: bool,
+    gdb_port: Option<u16>,
+    clock_speed: u32,
+    mode: Mode,
+    quirks: Option<String>,
+    quirk_flags: Vec<String>, // NAME=0|1 overrides
+    record_seconds: Option<u32>,
+    audio: AudioSettings,
+    seed: Option<u64>,
+    record_movie: Option<String>,
+    play_movie: Option<String>,
+    keymap: Option<String>,
+    disassemble: bool,
+}
+
+fn parse_args() -> Options {
+    let mut rom = None;
     let mut debug = false;
+    let mut debugger = false;
+    let mut gdb_port = None;
+    let mut clock_speed = DEFAULT_CLOCK_SPEED;
+    let mut mode = Mode::Chip8;
+    let mut quirks = None;
+    let mut quirk_flags = Vec::new();
+    let mut record_seconds = None;
+    let mut audio = AudioSettings::default();
+    let mut seed = None;
+    let mut record_movie = None;
+    let mut play_movie = None;
+    let mut keymap = None;
+    let mut disassemble = false;
+    let mut args = env::args().skip(1);
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--debug" => debug = true,
+            "--debugger" => debugger = true,
+            "--gdb" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => gdb_port = Some(value),
+                None => exit_with_usage(),
+            },
+            "--disassemble" => disassemble = true,
+            "--volume" => match args.next().and_then(|value| value.parse::<f32>().ok()) {
+                Some(value) if (0.0..=100.0).contains(&value) => audio.volume = value / 100.0,
+                _ => exit_with_usage(),
+            },
+            "--frequency" => match args.next().and_then(|value| value.parse::<f32>().ok()) {
+                Some(value) if value > 0.0 => audio.frequency = value,
+                _ => exit_with_usage(),
+            },
+            "--mute" => audio.muted = true,
+            "--seed" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => seed = Some(value),
+                None => exit_with_usage(),
+            },
+            "--record-movie" => match args.next() {
+                Some(path) => record_movie = Some(path),
+                None => exit_with_usage(),
+            },
+            "--play-movie" => match args.next() {
+                Some(path) => play_movie = Some(path),
+                None => exit_with_usage(),
+            },
+            "--keymap" => match args.next() {
+                Some(name) => keymap = Some(name),
+                None => exit_with_usage(),
+            },
+            "--record" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => record_seconds = Some(value),
+                None => exit_with_usage(),
+            },
+            "--speed" => match args.next().and_then(|value| value.parse().ok()) {
+                Some(value) => clock_speed = value,
+                None => exit_with_usage(),
+            },
+            "--mode" => match args.next().and_then(|value| Mode::from_name(&value)) {
+                Some(value) => mode = value,
+                None => exit_with_usage(),
+            },
+            "--quirks" => match args.next() {
+                Some(name) => quirks = Some(name),
+                None => exit_with_usage(),
+            },
+            "--quirk" => match args.next() {
+                Some(flag) if Quirks::default().apply(&flag).is_some() => quirk_flags.push(flag),
+                _ => exit_with_usage(),
+            },
+            _ if rom.is_none() && !arg.starts_with("--") => rom = Some(arg),
+            _ => exit_with_usage(),
+        }
+    }
+
+    // Movies need every frame to go through the normal play loop
+    let movie = record_movie.is_some() || play_movie.is_some();
+
+    if (movie && (debugger || gdb_port.is_some()))
+        || (record_movie.is_some() && play_movie.is_some())
+    {
+        exit_with_usage();
+    }
+
+    match rom {
+        Some(rom) => Options {
+            rom,
+            debug,
+            debugger,
+            gdb_port,
+            clock_speed,
+            mode,
+            quirks,
+            quirk_flags,
+            record_seconds,
+            audio,
+            seed,
+            record_movie,
+            play_movie,
+            keymap,
+            disassemble,
+        },
+        None => exit_with_usage(),
+    }
+}
+
+// emuchip-8 assemble SOURCE [-o ROM], the ROM defaults to SOURCE with a .ch8 extension
+fn assemble(mut args: impl Iterator<Item = String>) {
+    let source_path = match args.next() {
+        Some(path) => path,
+        None => exit_with_usage(),
+    };
+    let rom_path = match (args.next().as_deref(), args.next()) {
+        (Some("-o"), Some(path)) => path,
+        (None, _) => Path::new(&source_path)
+            .with_extension("ch8")
+            .to_string_lossy()
+            .into_owned(),
+        _ => exit_with_usage(),
+    };
+
+    let result = fs::read_to_string(&source_path)
+        .map_err(|error| error.to_string())
+        .and_then(|source| asm::assemble(&source).map_err(|error| error.to_string()))
+        .and_then(|rom| fs::write(&rom_path, rom).map_err(|error| error.to_string()));
+
+    if let Err(error) = result {
+        eprintln!("{}: {}", source_path, error);
+        process::exit(1);
+    }
+}
+
+fn exit_with_usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
 
-    if args.len() > 2 {
-        debug = args[2] == "--debug";
+fn main() {
+    if env::args().nth(1).as_deref() == Some("assemble") {
+        assemble(env::args().skip(2));
+        return;
+    }
+
+    let options = parse_args();
+    let debug = options.debug;
+
+    let rom = fs::read(&options.rom).unwrap_or_else(|error| {
+        eprintln!("Cannot read ROM: {}", error);
+        process::exit(1);
+    });
+
+    if options.disassemble {
+        print!("{}", disasm::disassemble(&rom));
+        return;
+    }
+
+    let keymaps = match options.keymap {
+        Some(ref name) => keymap::load(name).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        }),
+        None => KeymapConfig::default(),
+    };
+    let keymap = keymaps.keymap(Some(&options.rom));
+
+    let mut vm = VM::with_mode(options.mode, debug);
+    vm.set_clock_speed(options.clock_speed);
+
+    let quirk_config = match options.quirks {
+        Some(ref name) => quirks::load(name).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            process::exit(1);
+        }),
+        None => QuirksConfig::default(),
+    };
+    let mut quirks = quirk_config.quirks(Some(&options.rom), options.mode);
+
+    for flag in options.quirk_flags.iter() {
+        quirks.apply(flag);
+    }
+
+    vm.set_quirks(quirks);
+
+    if let Some(seed) = options.seed {
+        vm.set_seed(seed);
     }
 
-    let mut vm = VM::initialize(debug);
     vm.load_fontset();
-    vm.load_game(&args[1]);
+
+    if let Err(error) = vm.load_rom(&rom) {
+        eprintln!("{}", error);
+        process::exit(1);
+    }
+
+    let mut movie = MovieSession::start(&options, &rom, &mut vm);
+
+    let mut gdb = options.gdb_port.map(|port| {
+        println!("Waiting for GDB on port {}", port);
+
+        match GdbServer::listen(port) {
+            Ok(server) => server,
+            Err(error) => {
+                eprintln!("Cannot start the GDB server: {}", error);
+                process::exit(1);
+            }
+        }
+    });
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
     let window = video_subsystem
-        .window("EmuChip-8", 640, 320)
+        .window(WINDOW_TITLE, WINDOW_WIDTH, WINDOW_HEIGHT)
         .position_centered()
         .opengl()
         .build()
@@ -38,9 +286,33 @@ fn main() {
     canvas.clear();
     canvas.present();
 
+    let mut speaker = Speaker::open(&sdl_context, options.audio);
     let mut event_pump = sdl_context.event_pump().unwrap();
 
+    // SDL reports the controllers plugged in at startup as added too
+    let controller_subsystem = sdl_context.game_controller().ok();
+    let mut gamepads: HashMap<i32, GameController> = HashMap::new();
+    let mut controllers = Controllers::new(keymaps.controller_map(Some(&options.rom)));
+    let frame_duration = Duration::new(0, 1_000_000_000u32 / TIMER_FREQUENCY);
+    let mut slot = 0;
+    let mut waiting_for_key = false;
+    let mut rewind = Rewind::default();
+
+    let mut prompt = if options.debugger {
+        Some(DebuggerPrompt::start(&vm))
+    } else {
+        None
+    };
+
+    let mut recording = options
+        .record_seconds
+        .and_then(|seconds| Recording::start(&vm, &options.rom, Some(seconds)));
+
+    rewind.record(&vm);
+
     'running: loop {
+        let frame_start = Instant::now();
+
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit { .. }
@@ -48,43 +320,531 @@ fn main() {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => break 'running,
+                Event::KeyDown {
+                    keycode: Some(Keycode::F5),
+                    ..
+                } => save_state(&vm, &options.rom, slot),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F6),
+                    ..
+                } => {
+                    slot = (slot + 1) % SAVE_SLOTS;
+                    println!("Save slot {}", slot);
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    ..
+                } if movie.is_some() => println!("Cannot load a state during a movie"),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F9),
+                    ..
+                } => load_state(&mut vm, &options.rom, slot),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F12),
+                    ..
+                } => save_screenshot(&vm, &options.rom),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F8),
+                    ..
+                } => {
+                    if let Some(device) = speaker.as_mut() {
+                        device.lock().toggle_mute();
+                    }
+                }
+                Event::KeyDown {
+                    keycode: Some(Keycode::F10),
+                    ..
+                } => {
+                    recording = match recording.take() {
+                        Some(active) => {
+                            active.stop();
+                            None
+                        }
+                        None => Recording::start(&vm, &options.rom, None),
+                    }
+                }
                 Event::KeyDown {
                     keycode: Some(keycode),
                     ..
-                } => handle_key_down(&mut vm, keycode),
+                } => handle_key(&mut vm, &mut controllers, &keymap, keycode, true),
                 Event::KeyUp {
                     keycode: Some(keycode),
                     ..
-                } => handle_key_up(&mut vm, keycode),
+                } => handle_key(&mut vm, &mut controllers, &keymap, keycode, false),
+                Event::ControllerDeviceAdded { which, .. } => {
+                    if let Some(subsystem) = controller_subsystem.as_ref() {
+                        match subsystem.open(which) {
+                            Ok(gamepad) => {
+                                let id = gamepad.instance_id();
+                                println!("Controller connected: {}", gamepad.name());
+                                controllers.handle(ControllerEvent::Added(id), &mut vm);
+                                gamepads.insert(id, gamepad);
+                            }
+                            Err(error) => println!("Cannot open controller: {}", error),
+                        }
+                    }
+                }
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    if let Some(gamepad) = gamepads.remove(&which) {
+                        println!("Controller disconnected: {}", gamepad.name());
+                    }
+
+                    controllers.handle(ControllerEvent::Removed(which), &mut vm);
+                }
+                Event::ControllerButtonDown { which, button, .. } => controllers.handle(
+                    ControllerEvent::Button {
+                        id: which,
+                        button: &button.string(),
+                        pressed: true,
+                    },
+                    &mut vm,
+                ),
+                Event::ControllerButtonUp { which, button, .. } => controllers.handle(
+                    ControllerEvent::Button {
+                        id: which,
+                        button: &button.string(),
+                        pressed: false,
+                    },
+                    &mut vm,
+                ),
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => controllers.handle(
+                    ControllerEvent::Axis {
+                        id: which,
+                        axis: &axis.string(),
+                        value,
+                    },
+                    &mut vm,
+                ),
                 _ => {}
             }
         }
-        ::std::thread::sleep(Duration::new(0, 1_000_000_000u32 / 600));
-        // The rest of the game loop goes here...
-        vm.emulate_cycle();
+
+        // Holding backspace plays the game backwards
+        if movie.is_none()
+            && event_pump
+                .keyboard_state()
+                .is_scancode_pressed(Scancode::Backspace)
+        {
+            rewind.step_back(&mut vm);
+        } else if let Some(server) = gdb.as_mut() {
+            match server.update(&mut vm) {
+                Ok(true) => {}
+                Ok(false) => break 'running,
+                Err(error) => {
+                    eprintln!("GDB connection lost: {}", error);
+                    break 'running;
+                }
+            }
+        } else if let Some(prompt) = prompt.as_mut() {
+            if !prompt.update(&mut vm) {
+                break 'running;
+            }
+
+            if !prompt.debugger.paused() {
+                rewind.record(&vm);
+            }
+        } else {
+            if let Some(session) = movie.as_ref() {
+                session.before_frame(&mut vm);
+            }
+
+            if let Err(error) = vm.run_frame() {
+                eprintln!("{}", error);
+                break 'running;
+            }
+
+            if let Some(session) = movie.as_mut() {
+                if !session.after_frame(&vm) {
+                    movie.take().unwrap().finish();
+                }
+            }
+
+            rewind.record(&vm);
+        }
 
         if vm.draw_flag {
-            draw_graphic(&vm, &mut canvas, 10);
+            draw_graphic(&vm, &mut canvas);
+        }
+
+        if let Some(device) = speaker.as_mut() {
+            device.lock().tone.update(&vm);
+        }
+
+        if let Some(active) = recording.as_mut() {
+            if !active.capture(&vm) {
+                recording.take().unwrap().stop();
+            }
+        }
+
+        // FX0A blocks until a key is pressed and released, tell the player
+        if vm.waiting_for_key() != waiting_for_key {
+            waiting_for_key = vm.waiting_for_key();
+
+            let title = if waiting_for_key {
+                format!("{} - press a key", WINDOW_TITLE)
+            } else {
+                WINDOW_TITLE.to_string()
+            };
+            let _ = canvas.window_mut().set_title(&title);
+        }
+
+        if vm.halted() {
+            break 'running;
+        }
+
+        let elapsed = frame_start.elapsed();
+
+        if elapsed < frame_duration {
+            thread::sleep(frame_duration - elapsed);
         }
     }
 
+    if let Some(active) = recording {
+        active.stop();
+    }
+
+    if let Some(session) = movie {
+        session.finish();
+    }
+
     if debug {
         vm.debug_memory();
         vm.debug_registers();
     }
 }
 
-fn draw_graphic(vm: &VM, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>, scale: u32) {
-    for i in 0..64 * 32 {
-        let pixel = vm.gfx[i];
-        let x = (i % 64) * scale as usize;
-        let y = (i / 64) * scale as usize;
+// Movie recorded or played back during normal play
+enum MovieSession {
+    Recording { movie: Movie, path: String },
+    Playing { movie: Movie, frame: usize },
+}
+
+impl MovieSession {
+    // Playback replaces the VM with one set up like the recorded one
+    fn start(options: &Options, rom: &[u8], vm: &mut VM) -> Option<MovieSession> {
+        if let Some(ref path) = options.play_movie {
+            let movie = fs::read_to_string(path)
+                .map_err(|error| error.to_string())
+                .and_then(|text| Movie::parse(&text).map_err(|error| error.to_string()))
+                .and_then(|movie| match movie.frames.len() {
+                    0 => Err("The movie has no frames".to_string()),
+                    _ => Ok(movie),
+                })
+                .unwrap_or_else(|error| {
+                    eprintln!("{}: {}", path, error);
+                    process::exit(1);
+                });
 
-        canvas.set_draw_color(Color::RGB(0, 0, 0));
+            *vm = movie.start(rom, options.debug).unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                process::exit(1);
+            });
+            println!("Playing {} frames from {}", movie.frames.len(), path);
 
-        if pixel == 1 {
-            canvas.set_draw_color(Color::RGB(255, 255, 255));
+            Some(MovieSession::Playing { movie, frame: 0 })
+        } else if let Some(ref path) = options.record_movie {
+            let seed = options.seed.unwrap_or_else(rand::random);
+            println!("Recording a movie to {}", path);
+
+            Some(MovieSession::Recording {
+                movie: Movie::record(vm, rom, seed),
+                path: path.clone(),
+            })
+        } else {
+            None
         }
+    }
+
+    fn before_frame(&self, vm: &mut VM) {
+        if let MovieSession::Playing { movie, frame } = self {
+            movie.set_keys(*frame, vm);
+        }
+    }
+
+    // Records or checks the frame that just ran, returns false once playback is over
+    fn after_frame(&mut self, vm: &VM) -> bool {
+        match self {
+            MovieSession::Recording { movie, .. } => {
+                movie.record_frame(vm);
+                true
+            }
+            MovieSession::Playing { movie, frame } => {
+                if let Err(error) = movie.check_frame(*frame, vm) {
+                    eprintln!("{}", error);
+                    return false;
+                }
+
+                *frame += 1;
+
+                if *frame == movie.frames.len() {
+                    println!("Movie finished, the keyboard is back in control");
+                    return false;
+                }
+
+                true
+            }
+        }
+    }
+
+    fn finish(self) {
+        if let MovieSession::Recording { movie, path } = self {
+            match fs::write(&path, movie.to_text()) {
+                Ok(()) => println!("Saved {} frames to {}", movie.frames.len(), path),
+                Err(error) => eprintln!("Cannot save the movie to {}: {}", path, error),
+            }
+        }
+    }
+}
+
+// Reads debugger commands from stdin on a separate thread so the window stays responsive
+struct DebuggerPrompt {
+    debugger: Debugger,
+    commands: Receiver<String>,
+}
+
+impl DebuggerPrompt {
+    fn start(vm: &VM) -> DebuggerPrompt {
+        let (sender, commands) = mpsc::channel();
+
+        thread::spawn(move || {
+            let stdin = io::stdin();
+
+            for line in stdin.lock().lines() {
+                // Stop on read errors, or once the emulator is gone
+                match line.map(|line| sender.send(line)) {
+                    Ok(Ok(())) => {}
+                    _ => break,
+                }
+            }
+        });
+
+        print!("Paused at {}", debugger::location(vm));
+        println!("Type help for a list of commands");
+        show_prompt();
+
+        DebuggerPrompt {
+            debugger: Debugger::new(),
+            commands,
+        }
+    }
+
+    // Handles the pending commands then runs a frame, returns false once the user quits
+    fn update(&mut self, vm: &mut VM) -> bool {
+        while let Ok(line) = self.commands.try_recv() {
+            if !line.trim().is_empty() {
+                match Command::parse(&line) {
+                    Ok(Command::Quit) => return false,
+                    Ok(command) => match self.debugger.execute(command, vm) {
+                        Ok(output) => print!("{}", output),
+                        Err(error) => {
+                            self.debugger.pause();
+                            println!("{}", error);
+                        }
+                    },
+                    Err(error) => println!("{}", error),
+                }
+            }
+
+            show_prompt();
+        }
+
+        match self.debugger.run_frame(vm) {
+            Ok(None) => {}
+            Ok(Some(message)) => {
+                print!("\n{}", message);
+                show_prompt();
+            }
+            Err(error) => {
+                self.debugger.pause();
+                print!("\n{}\nPaused at {}", error, debugger::location(vm));
+                show_prompt();
+            }
+        }
+
+        true
+    }
+}
+
+fn show_prompt() {
+    print!("(emuchip-8) ");
+    let _ = io::stdout().flush();
+}
+
+fn state_path(rom: &str, slot: u8) -> String {
+    format!("{}.state{}", rom, slot)
+}
+
+fn save_state(vm: &VM, rom: &str, slot: u8) {
+    let path = state_path(rom, slot);
+
+    match fs::write(&path, vm.save_state()) {
+        Ok(()) => println!("Saved state to {}", path),
+        Err(error) => eprintln!("Cannot save state to {}: {}", path, error),
+    }
+}
+
+fn load_state(vm: &mut VM, rom: &str, slot: u8) {
+    let path = state_path(rom, slot);
+    let result = fs::read(&path)
+        .map_err(|error| error.to_string())
+        .and_then(|data| vm.load_state(&data).map_err(|error| error.to_string()));
+
+    match result {
+        Ok(()) => println!("Loaded state from {}", path),
+        Err(error) => eprintln!("Cannot load state from {}: {}", path, error),
+    }
+}
+
+// ROM.NAMEN.EXTENSION, using the first N that is not taken
+fn free_path(rom: &str, name: &str, extension: &str) -> String {
+    (0..)
+        .map(|n| format!("{}.{}{}.{}", rom, name, n, extension))
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
+// Saves what the window shows as ROM.screenshotN.png
+fn save_screenshot(vm: &VM, rom: &str) {
+    let path = free_path(rom, "screenshot", "png");
+    let style = Style {
+        scale: WINDOW_WIDTH as usize / vm.width(),
+        ..Style::default()
+    };
+
+    match screenshot::save_png(vm, &style, &path) {
+        Ok(()) => println!("Saved screenshot to {}", path),
+        Err(error) => eprintln!("Cannot save screenshot to {}: {}", path, error),
+    }
+}
+
+// Plays the buzzer, SDL calls back from its audio thread for more samples
+struct Speaker {
+    tone: Tone,
+    sample_rate: u32,
+}
+
+impl Speaker {
+    // Missing audio is not fatal, the game just runs silently
+    fn open(sdl_context: &sdl2::Sdl, settings: AudioSettings) -> Option<AudioDevice<Speaker>> {
+        let desired_spec = AudioSpecDesired {
+            freq: Some(44100),
+            channels: Some(1),
+            samples: None,
+        };
+        let device = sdl_context.audio().and_then(|audio| {
+            audio.open_playback(None, &desired_spec, |spec| Speaker {
+                tone: Tone::new(settings),
+                sample_rate: spec.freq as u32,
+            })
+        });
+
+        match device {
+            Ok(device) => {
+                device.resume();
+                Some(device)
+            }
+            Err(error) => {
+                eprintln!("Cannot open the audio device: {}", error);
+                None
+            }
+        }
+    }
+
+    fn toggle_mute(&mut self) {
+        let settings = &mut self.tone.settings;
+        settings.muted = !settings.muted;
+
+        let state = if settings.muted { "off" } else { "on" };
+        println!("Sound {}", state);
+    }
+}
+
+impl AudioCallback for Speaker {
+    type Channel = f32;
+
+    fn callback(&mut self, output: &mut [f32]) {
+        self.tone.fill(self.sample_rate, output);
+    }
+}
+
+// GIF of the window as ROM.recordingN.gif, until F10 is pressed again or the limit is reached
+struct Recording {
+    recorder: Recorder<BufWriter<File>>,
+    path: String,
+    limit: Option<u64>, // Frames to record
+}
+
+impl Recording {
+    fn start(vm: &VM, rom: &str, seconds: Option<u32>) -> Option<Recording> {
+        let path = free_path(rom, "recording", "gif");
+        let (width, _) = vm.mode().max_resolution();
+        let style = Style {
+            scale: WINDOW_WIDTH as usize / width,
+            ..Style::default()
+        };
+        let recorder = File::create(&path)
+            .map_err(|error| error.to_string())
+            .and_then(|file| {
+                Recorder::new(BufWriter::new(file), vm, &style).map_err(|error| error.to_string())
+            });
+
+        match recorder {
+            Ok(recorder) => {
+                println!("Recording to {}", path);
+
+                Some(Recording {
+                    recorder,
+                    path,
+                    limit: seconds.map(|seconds| seconds as u64 * TIMER_FREQUENCY as u64),
+                })
+            }
+            Err(error) => {
+                eprintln!("Cannot record to {}: {}", path, error);
+                None
+            }
+        }
+    }
+
+    // Records a frame, returns false once the recording should stop
+    fn capture(&mut self, vm: &VM) -> bool {
+        if let Err(error) = self.recorder.capture(vm) {
+            eprintln!("Cannot record to {}: {}", self.path, error);
+            return false;
+        }
+
+        match self.limit {
+            Some(limit) => self.recorder.frames() < limit,
+            None => true,
+        }
+    }
+
+    fn stop(self) {
+        let seconds = self.recorder.frames() as f32 / TIMER_FREQUENCY as f32;
+        let result = self
+            .recorder
+            .finish()
+            .map_err(|error| error.to_string())
+            .and_then(|mut writer| writer.flush().map_err(|error| error.to_string()));
+
+        match result {
+            Ok(()) => println!("Saved {:.1} seconds to {}", seconds, self.path),
+            Err(error) => eprintln!("Cannot record to {}: {}", self.path, error),
+        }
+    }
+}
+
+fn draw_graphic(vm: &VM, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>) {
+    let width = vm.width();
+    let scale = WINDOW_WIDTH / width as u32;
+
+    for i in 0..width * vm.height() {
+        let [r, g, b] = PALETTE[vm.gfx[i] as usize & 0x3];
+        let x = (i % width) * scale as usize;
+        let y = (i / width) * scale as usize;
+
+        canvas.set_draw_color(Color::RGB(r, g, b));
 
         let _ = canvas.fill_rect(Rect::new(x as i32, y as i32, scale, scale));
     }
@@ -92,46 +852,14 @@ fn draw_graphic(vm: &VM, canvas: &mut sdl2::render::Canvas<sdl2::video::Window>,
     canvas.present();
 }
 
-pub fn handle_key_down(vm: &mut vm::VM, keycode: Keycode) {
-    match keycode {
-        Keycode::Num1 => vm.key[0x1] = true,
-        Keycode::Num2 => vm.key[0x2] = true,
-        Keycode::Num3 => vm.key[0x3] = true,
-        Keycode::Num4 => vm.key[0xC] = true,
-        Keycode::Q => vm.key[0x4] = true,
-        Keycode::W => vm.key[0x5] = true,
-        Keycode::E => vm.key[0x6] = true,
-        Keycode::R => vm.key[0xD] = true,
-        Keycode::A => vm.key[0x7] = true,
-        Keycode::S => vm.key[0x8] = true,
-        Keycode::D => vm.key[0x9] = true,
-        Keycode::F => vm.key[0xE] = true,
-        Keycode::Z => vm.key[0xA] = true,
-        Keycode::X => vm.key[0x0] = true,
-        Keycode::C => vm.key[0xB] = true,
-        Keycode::V => vm.key[0xF] = true,
-        _ => (),
-    }
-}
-
-pub fn handle_key_up(vm: &mut VM, keycode: Keycode) {
-    match keycode {
-        Keycode::Num1 => vm.key[0x1] = false,
-        Keycode::Num2 => vm.key[0x2] = false,
-        Keycode::Num3 => vm.key[0x3] = false,
-        Keycode::Num4 => vm.key[0xC] = false,
-        Keycode::Q => vm.key[0x4] = false,
-        Keycode::W => vm.key[0x5] = false,
-        Keycode::E => vm.key[0x6] = false,
-        Keycode::R => vm.key[0xD] = false,
-        Keycode::A => vm.key[0x7] = false,
-        Keycode::S => vm.key[0x8] = false,
-        Keycode::D => vm.key[0x9] = false,
-        Keycode::F => vm.key[0xE] = false,
-        Keycode::Z => vm.key[0xA] = false,
-        Keycode::X => vm.key[0x0] = false,
-        Keycode::C => vm.key[0xB] = false,
-        Keycode::V => vm.key[0xF] = false,
-        _ => (),
+pub fn handle_key(
+    vm: &mut VM,
+    controllers: &mut Controllers,
+    keymap: &Keymap,
+    keycode: Keycode,
+    pressed: bool,
+) {
+    if let Some(key) = keymap.key(&keycode.name()) {
+        controllers.keyboard(key, pressed, vm);
     }
 }
@@ -0,0 +1,445 @@
+use crate::quirks::Quirks;
+use crate::state::crc32;
+use crate::vm::{Mode, VmError, VM};
+use std::error::Error;
+use std::fmt;
+use std::num::ParseIntError;
+
+// Movies are text: a header line, the settings the VM ran with, then one line per frame
+// with the keys held (bit N for key N) and a checksum of the screen after the frame
+const HEADER: &str = "emuchip-8 movie";
+pub const VERSION: u32 = 2;
+// Version 1 movies predate load_store_adds_x_to_i, which is off in them
+const LOAD_STORE_ADDS_X_TO_I_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Invalid {
+        line: usize,
+        message: String,
+    },
+    RomMismatch {
+        expected: u32,
+        actual: u32,
+    },
+    Desync {
+        frame: usize,
+        expected: u32,
+        actual: u32,
+    },
+    Vm(VmError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Invalid { line, message } => write!(f, "line {}: {}", line, message),
+            MovieError::RomMismatch { expected, actual } => write!(
+                f,
+                "Movie was recorded with another ROM (CRC-32 {:08X}, this one is {:08X})",
+                expected, actual
+            ),
+            MovieError::Desync {
+                frame,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "Desync at frame {}: screen checksum {:08X}, recorded {:08X}",
+                frame, actual, expected
+            ),
+            MovieError::Vm(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+impl Error for MovieError {}
+
+impl From<VmError> for MovieError {
+    fn from(error: VmError) -> MovieError {
+        MovieError::Vm(error)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MovieFrame {
+    pub keys: u16,   // Keys held during the frame, bit N for key N
+    pub screen: u32, // CRC-32 of gfx after the frame
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    pub rom_crc: u32,
+    pub mode: Mode,
+    pub quirks: Quirks,
+    pub clock_speed: u32,
+    pub seed: u64,
+    pub frames: Vec<MovieFrame>,
+}
+
+impl Movie {
+    /// Starts recording a VM that has loaded `rom` and not run yet. Its RND is seeded so
+    /// playback gets the same numbers
+    pub fn record(vm: &mut VM, rom: &[u8], seed: u64) -> Movie {
+        vm.set_seed(seed);
+
+        Movie {
+            rom_crc: crc32(rom),
+            mode: vm.mode(),
+            quirks: vm.quirks(),
+            clock_speed: vm.clock_speed(),
+            seed,
+            frames: Vec::new(),
+        }
+    }
+
+    /// Adds the frame the VM just ran, call after every `run_frame` while recording
+    pub fn record_frame(&mut self, vm: &VM) {
+        let keys = (0..16)
+            .filter(|&key| vm.key[key])
+            .fold(0, |keys, key| keys | 1 << key);
+
+        self.frames.push(MovieFrame {
+            keys,
+            screen: crc32(&vm.gfx),
+        });
+    }
+
+    /// A new VM set up like the recorded one, with the fontset and `rom` loaded
+    pub fn start(&self, rom: &[u8], debug: bool) -> Result<VM, MovieError> {
+        let actual = crc32(rom);
+
+        if actual != self.rom_crc {
+            return Err(MovieError::RomMismatch {
+                expected: self.rom_crc,
+                actual,
+            });
+        }
+
+        let mut vm = VM::with_seed(self.mode, self.seed, debug);
+        vm.set_quirks(self.quirks);
+        vm.set_clock_speed(self.clock_speed);
+        vm.load_fontset();
+        vm.load_rom(rom)?;
+
+        Ok(vm)
+    }
+
+    /// Presses the keys recorded for frame `index`, call before running it
+    pub fn set_keys(&self, index: usize, vm: &mut VM) {
+        let keys = self.frames[index].keys;
+
+        for key in 0..16 {
+            vm.set_key(key, keys & 1 << key != 0);
+        }
+    }
+
+    /// Compares the screen after frame `index` with the recording
+    pub fn check_frame(&self, index: usize, vm: &VM) -> Result<(), MovieError> {
+        let expected = self.frames[index].screen;
+        let actual = crc32(&vm.gfx);
+
+        if actual != expected {
+            return Err(MovieError::Desync {
+                frame: index,
+                expected,
+                actual,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Runs frame `index` with the recorded keys and checks the screen matches the recording
+    pub fn play_frame(&self, index: usize, vm: &mut VM) -> Result<(), MovieError> {
+        self.set_keys(index, vm);
+        vm.run_frame()?;
+        self.check_frame(index, vm)
+    }
+
+    /// Plays the whole movie on a new VM, stopping at the first desync
+    pub fn replay(&self, rom: &[u8], debug: bool) -> Result<VM, MovieError> {
+        let mut vm = self.start(rom, debug)?;
+
+        for index in 0..self.frames.len() {
+            self.play_frame(index, &mut vm)?;
+        }
+
+        Ok(vm)
+    }
+
+    pub fn to_text(&self) -> String {
+        let quirks = &self.quirks;
+        let mut text = format!(
+            "{} {}\nrom {:08X}\nmode {}\nquirks shift_uses_vy={} load_store_increments_i={} \
+             load_store_adds_x_to_i={} clip_sprites={} logic_resets_vf={} jump_uses_vx={}\nspeed {}\nseed {:016X}\n",
+            HEADER,
+            VERSION,
+            self.rom_crc,
+            self.mode.name(),
+            quirks.shift_uses_vy as u8,
+            quirks.load_store_increments_i as u8,
+            quirks.load_store_adds_x_to_i as u8,
+            quirks.clip_sprites as u8,
+            quirks.logic_resets_vf as u8,
+            quirks.jump_uses_vx as u8,
+            self.clock_speed,
+            self.seed
+        );
+
+        for frame in self.frames.iter() {
+            text.push_str(&format!("{:04X} {:08X}\n", frame.keys, frame.screen));
+        }
+
+        text
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line));
+
+        let version = match lines.next() {
+            Some((_, line)) if line.starts_with(HEADER) => {
+                match line[HEADER.len()..].trim().parse() {
+                    Ok(version) if (1..=VERSION).contains(&version) => version,
+                    _ => return Err(invalid(1, "Unsupported movie version")),
+                }
+            }
+            _ => return Err(invalid(1, "Not a movie")),
+        };
+
+        let mut rom_crc = None;
+        let mut mode = None;
+        let mut quirks = None;
+        let mut clock_speed = None;
+        let mut seed = None;
+        let mut frames = Vec::new();
+        let mut last_line = 1;
+
+        for (number, line) in lines {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            last_line = number;
+
+            match fields.as_slice() {
+                [] => {}
+                ["rom", crc] => rom_crc = Some(parse_hex(number, crc, u32::from_str_radix)?),
+                ["mode", name] => match Mode::from_name(name) {
+                    Some(value) => mode = Some(value),
+                    None => return Err(invalid(number, "Unknown mode")),
+                },
+                ["quirks", flags @ ..] => {
+                    let mut value = Quirks::default();
+
+                    for flag in flags.iter() {
+                        if version < LOAD_STORE_ADDS_X_TO_I_VERSION
+                            && flag.starts_with("load_store_adds_x_to_i=")
+                        {
+                            return Err(invalid(number, "Invalid quirk"));
+                        }
+
+                        value
+                            .apply(flag)
+                            .ok_or_else(|| invalid(number, "Invalid quirk"))?;
+                    }
+
+                    quirks = Some(value);
+                }
+                ["speed", speed] => match speed.parse() {
+                    Ok(value) => clock_speed = Some(value),
+                    Err(_) => return Err(invalid(number, "Invalid speed")),
+                },
+                ["seed", value] => seed = Some(parse_hex(number, value, u64::from_str_radix)?),
+                [keys, screen] => frames.push(MovieFrame {
+                    keys: parse_hex(number, keys, u16::from_str_radix)?,
+                    screen: parse_hex(number, screen, u32::from_str_radix)?,
+                }),
+                _ => return Err(invalid(number, "Unexpected line")),
+            }
+        }
+
+        let missing = |field| invalid(last_line, &format!("Missing {}", field));
+        let rom_crc = rom_crc.ok_or_else(|| missing("rom"))?;
+        let mode = mode.ok_or_else(|| missing("mode"))?;
+
+        Ok(Movie {
+            rom_crc,
+            mode,
+            quirks: quirks.unwrap_or_else(|| Quirks::for_mode(mode)),
+            clock_speed: clock_speed.ok_or_else(|| missing("speed"))?,
+            seed: seed.ok_or_else(|| missing("seed"))?,
+            frames,
+        })
+    }
+}
+
+fn invalid(line: usize, message: &str) -> MovieError {
+    MovieError::Invalid {
+        line,
+        message: message.to_string(),
+    }
+}
+
+// `parse` is the `from_str_radix` of the integer type, so out of range values are errors too
+fn parse_hex<T>(
+    line: usize,
+    text: &str,
+    parse: fn(&str, u32) -> Result<T, ParseIntError>,
+) -> Result<T, MovieError> {
+    parse(text, 16).map_err(|_| invalid(line, &format!("Invalid number: {}", text)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Draws a pixel at a random position every loop, key 5 keeps the screen from clearing
+    const ROM: [u8; 16] = [
+        0x62, 0x05, // LD V2, 5
+        0xA0, 0x00, // LD I, 0x000
+        0xC0, 0x3F, // RND V0, 0x3F
+        0xC1, 0x1F, // RND V1, 0x1F
+        0xD0, 0x11, // DRW V0, V1, 1
+        0xE2, 0xA1, // SKNP V2
+        0x00, 0xE0, // CLS
+        0x12, 0x04, // JP 0x204
+    ];
+
+    fn record(seed: u64) -> (Movie, VM) {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.load_fontset();
+        vm.load_rom(&ROM).unwrap();
+
+        let mut movie = Movie::record(&mut vm, &ROM, seed);
+
+        for frame in 0..30 {
+            vm.set_key(5, frame % 10 < 4);
+            vm.run_frame().unwrap();
+            movie.record_frame(&vm);
+        }
+
+        (movie, vm)
+    }
+
+    #[test]
+    fn replay_matches_recording() {
+        let (movie, recorded) = record(1234);
+        let replayed = movie.replay(&ROM, false).unwrap();
+
+        assert_eq!(movie.frames.len(), 30);
+        assert_eq!(movie.frames[0].keys, 1 << 5);
+        assert_eq!(movie.frames[5].keys, 0);
+        assert_eq!(&replayed.gfx[..], &recorded.gfx[..]);
+        assert_eq!(replayed.quirks(), Quirks::COSMAC_VIP);
+    }
+
+    #[test]
+    fn text_round_trip() {
+        let (movie, _) = record(1234);
+        let text = movie.to_text();
+
+        assert!(text.starts_with("emuchip-8 movie 2\n"));
+        assert_eq!(Movie::parse(&text).unwrap(), movie);
+    }
+
+    #[test]
+    fn desync() {
+        let (mut movie, _) = record(1234);
+        movie.frames[3].screen ^= 1;
+
+        match movie.replay(&ROM, false) {
+            Err(MovieError::Desync { frame, .. }) => assert_eq!(frame, 3),
+            other => panic!("Expected a desync, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn other_seed_desyncs() {
+        let (mut movie, _) = record(1234);
+        movie.seed = 4321;
+
+        assert!(matches!(
+            movie.replay(&ROM, false),
+            Err(MovieError::Desync { .. })
+        ));
+    }
+
+    #[test]
+    fn rom_mismatch() {
+        let (movie, _) = record(1234);
+        let mut rom = ROM;
+        rom[1] = 0x06;
+
+        assert!(matches!(
+            movie.start(&rom, false),
+            Err(MovieError::RomMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| Movie::parse(text).unwrap_err().to_string();
+
+        assert_eq!(error("hello"), "line 1: Not a movie");
+        assert_eq!(
+            error("emuchip-8 movie 9"),
+            "line 1: Unsupported movie version"
+        );
+        assert_eq!(
+            error("emuchip-8 movie 2\nrom 12\nmode gameboy"),
+            "line 3: Unknown mode"
+        );
+        assert_eq!(
+            error("emuchip-8 movie 2\nrom 12\nmode chip8\nspeed 600"),
+            "line 4: Missing seed"
+        );
+        assert_eq!(
+            error("emuchip-8 movie 2\n0000 XYZ"),
+            "line 2: Invalid number: XYZ"
+        );
+        assert_eq!(
+            error("emuchip-8 movie 2\n10000 0"),
+            "line 2: Invalid number: 10000"
+        );
+        assert_eq!(
+            error("emuchip-8 movie 2\nrom 100000000"),
+            "line 2: Invalid number: 100000000"
+        );
+    }
+
+    #[test]
+    fn missing_quirks_follow_mode() {
+        let movie =
+            Movie::parse("emuchip-8 movie 2\nrom 12\nmode schip\nspeed 600\nseed 1\n0 0").unwrap();
+
+        assert_eq!(movie.quirks, Quirks::SCHIP);
+    }
+
+    #[test]
+    fn version_1_movies() {
+        let settings = "rom 12\nmode chip8\nspeed 600\nseed 1\n";
+        let movie = Movie::parse(&format!(
+            "emuchip-8 movie 1\n{}quirks shift_uses_vy=0 load_store_increments_i=0 \
+             clip_sprites=1 logic_resets_vf=0 jump_uses_vx=1\n0 0",
+            settings
+        ))
+        .unwrap();
+
+        assert_eq!(movie.quirks, Quirks::SCHIP);
+        assert_eq!(
+            Movie::parse(&format!(
+                "emuchip-8 movie 1\n{}quirks load_store_adds_x_to_i=1",
+                settings
+            ))
+            .unwrap_err()
+            .to_string(),
+            "line 6: Invalid quirk"
+        );
+        assert_eq!(
+            Movie::parse("emuchip-8 movie 0").unwrap_err().to_string(),
+            "line 1: Unsupported movie version"
+        );
+    }
+}
@@ -0,0 +1,362 @@
+use crate::keymap;
+use crate::vm::Mode;
+use serde_json::Value;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+
+// Names used by quirk overrides, quirk files and movies
+const NAMES: [&str; 6] = [
+    "shift_uses_vy",
+    "load_store_increments_i",
+    "load_store_adds_x_to_i",
+    "clip_sprites",
+    "logic_resets_vf",
+    "jump_uses_vx",
+];
+
+// Behaviours that differ between CHIP-8 interpreters, ROMs usually expect one specific set
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Quirks {
+    // 8XY6/8XYE shift VY into VX instead of shifting VX in place
+    pub shift_uses_vy: bool,
+    // FX55/FX65 leave I pointing past the last register
+    pub load_store_increments_i: bool,
+    // FX55/FX65 leave I pointing at the last register, CHIP-48 was off by one. Only used when
+    // load_store_increments_i is not set
+    pub load_store_adds_x_to_i: bool,
+    // DXYN clips sprites at the screen edges instead of wrapping them around
+    pub clip_sprites: bool,
+    // 8XY1/8XY2/8XY3 reset VF to 0
+    pub logic_resets_vf: bool,
+    // BXNN jumps to XNN + VX instead of NNN + V0
+    pub jump_uses_vx: bool,
+}
+
+impl Quirks {
+    pub const COSMAC_VIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        load_store_adds_x_to_i: false,
+        clip_sprites: true,
+        logic_resets_vf: true,
+        jump_uses_vx: false,
+    };
+
+    pub const CHIP_48: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        load_store_adds_x_to_i: true,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        jump_uses_vx: true,
+    };
+
+    pub const SCHIP: Quirks = Quirks {
+        shift_uses_vy: false,
+        load_store_increments_i: false,
+        load_store_adds_x_to_i: false,
+        clip_sprites: true,
+        logic_resets_vf: false,
+        jump_uses_vx: true,
+    };
+
+    pub const XO_CHIP: Quirks = Quirks {
+        shift_uses_vy: true,
+        load_store_increments_i: true,
+        load_store_adds_x_to_i: false,
+        clip_sprites: false,
+        logic_resets_vf: false,
+        jump_uses_vx: false,
+    };
+
+    pub fn from_name(name: &str) -> Option<Quirks> {
+        match name.to_lowercase().as_str() {
+            "default" => Some(Quirks::default()),
+            "vip" | "cosmac" | "cosmac-vip" => Some(Quirks::COSMAC_VIP),
+            "chip48" | "chip-48" => Some(Quirks::CHIP_48),
+            "schip" | "superchip" | "super-chip" => Some(Quirks::SCHIP),
+            "xochip" | "xo-chip" => Some(Quirks::XO_CHIP),
+            _ => None,
+        }
+    }
+
+    /// Names of the quirks `set` accepts
+    pub fn names() -> &'static [&'static str] {
+        &NAMES
+    }
+
+    /// Changes one quirk by name, false when there is no such quirk
+    pub fn set(&mut self, name: &str, value: bool) -> bool {
+        let quirk = match name {
+            "shift_uses_vy" => &mut self.shift_uses_vy,
+            "load_store_increments_i" => &mut self.load_store_increments_i,
+            "load_store_adds_x_to_i" => &mut self.load_store_adds_x_to_i,
+            "clip_sprites" => &mut self.clip_sprites,
+            "logic_resets_vf" => &mut self.logic_resets_vf,
+            "jump_uses_vx" => &mut self.jump_uses_vx,
+            _ => return false,
+        };
+
+        *quirk = value;
+        true
+    }
+
+    /// Applies a `NAME=0` or `NAME=1` override
+    pub fn apply(&mut self, flag: &str) -> Option<()> {
+        let mut parts = flag.splitn(2, '=');
+        let name = parts.next()?;
+        let value = match parts.next()? {
+            "0" => false,
+            "1" => true,
+            _ => return None,
+        };
+
+        if self.set(name, value) {
+            Some(())
+        } else {
+            None
+        }
+    }
+
+    /// Quirks a VM starts with: the historical emulator behaviour for CHIP-8, the matching
+    /// preset for the extended instruction sets
+    pub fn for_mode(mode: Mode) -> Quirks {
+        match mode {
+            Mode::Chip8 => Quirks::default(),
+            Mode::SuperChip => Quirks::SCHIP,
+            Mode::XoChip => Quirks::XO_CHIP,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct QuirksError {
+    pub message: String,
+}
+
+impl QuirksError {
+    fn new(message: String) -> QuirksError {
+        QuirksError { message }
+    }
+}
+
+impl fmt::Display for QuirksError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for QuirksError {}
+
+/// A preset name, or the path of a quirks file
+pub fn load(name: &str) -> Result<QuirksConfig, QuirksError> {
+    if Quirks::from_name(name).is_some() {
+        return Ok(QuirksConfig {
+            base: Profile {
+                preset: Some(name.to_string()),
+                ..Profile::default()
+            },
+            roms: Vec::new(),
+        });
+    }
+
+    fs::read_to_string(name)
+        .map_err(|error| QuirksError::new(error.to_string()))
+        .and_then(|text| QuirksConfig::parse(&text))
+        .map_err(|error| QuirksError::new(format!("{}: {}", name, error)))
+}
+
+// A preset and the quirks changed on top of it
+#[derive(Clone, Debug, Default, PartialEq)]
+struct Profile {
+    preset: Option<String>,
+    quirks: Vec<(String, bool)>,
+}
+
+/// A quirks file: a preset, single quirks, and profiles for the ROMs that need something
+/// else, matched like keymap profiles:
+///
+/// ```json
+/// {
+///   "preset": "vip",
+///   "quirks": { "logic_resets_vf": false },
+///   "roms": { "BLITZ": { "quirks": { "clip_sprites": true } }, "ALIEN": { "preset": "schip" } }
+/// }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuirksConfig {
+    base: Profile,
+    roms: Vec<(String, Profile)>,
+}
+
+impl QuirksConfig {
+    pub fn parse(text: &str) -> Result<QuirksConfig, QuirksError> {
+        let value: Value =
+            serde_json::from_str(text).map_err(|error| QuirksError::new(error.to_string()))?;
+        let base = parse_profile(&value, "quirks")?;
+        let roms = match value.get("roms") {
+            None => Vec::new(),
+            Some(Value::Object(roms)) => roms
+                .iter()
+                .map(|(rom, profile)| Ok((rom.clone(), parse_profile(profile, rom)?)))
+                .collect::<Result<_, QuirksError>>()?,
+            Some(_) => return Err(QuirksError::new("roms must be an object".to_string())),
+        };
+
+        Ok(QuirksConfig { base, roms })
+    }
+
+    /// The quirks for `rom` in `mode`, which picks them when no preset is given
+    pub fn quirks(&self, rom: Option<&str>, mode: Mode) -> Quirks {
+        let profile = rom.and_then(|rom| {
+            self.roms
+                .iter()
+                .find(|(name, _)| keymap::rom_matches(name, rom))
+                .map(|(_, profile)| profile)
+        });
+        let mut quirks = profile
+            .and_then(|profile| profile.preset.as_ref())
+            .or(self.base.preset.as_ref())
+            .and_then(|name| Quirks::from_name(name))
+            .unwrap_or_else(|| Quirks::for_mode(mode));
+
+        for profile in Some(&self.base).into_iter().chain(profile) {
+            for (name, value) in profile.quirks.iter() {
+                quirks.set(name, *value);
+            }
+        }
+
+        quirks
+    }
+}
+
+fn parse_profile(value: &Value, context: &str) -> Result<Profile, QuirksError> {
+    let invalid = |message: &str| QuirksError::new(format!("{}: {}", context, message));
+    let object = value
+        .as_object()
+        .ok_or_else(|| invalid("expected an object"))?;
+    let preset = match object.get("preset") {
+        None => None,
+        Some(Value::String(name)) if Quirks::from_name(name).is_some() => Some(name.clone()),
+        Some(preset) => return Err(invalid(&format!("unknown preset {}", preset))),
+    };
+    let quirks = match object.get("quirks") {
+        None => Vec::new(),
+        Some(Value::Object(quirks)) => quirks
+            .iter()
+            .map(|(name, value)| match value {
+                _ if !NAMES.contains(&name.as_str()) => {
+                    Err(invalid(&format!("unknown quirk {}", name)))
+                }
+                Value::Bool(value) => Ok((name.clone(), *value)),
+                _ => Err(invalid(&format!("invalid value {} for {}", value, name))),
+            })
+            .collect::<Result<_, QuirksError>>()?,
+        Some(_) => return Err(invalid("quirks must be an object")),
+    };
+
+    Ok(Profile { preset, quirks })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chip48_differs_from_schip() {
+        assert_eq!(Quirks::from_name("CHIP-48"), Some(Quirks::CHIP_48));
+        assert_ne!(Quirks::CHIP_48, Quirks::SCHIP);
+        assert_eq!(Quirks::from_name("gameboy"), None);
+    }
+
+    #[test]
+    fn overrides() {
+        let mut quirks = Quirks::SCHIP;
+
+        assert_eq!(quirks.apply("clip_sprites=0"), Some(()));
+        assert_eq!(quirks.apply("shift_uses_vy=1"), Some(()));
+        assert!(!quirks.clip_sprites);
+        assert!(quirks.shift_uses_vy);
+        assert_eq!(quirks.apply("clip_sprites=yes"), None);
+        assert_eq!(quirks.apply("wrap_sprites=1"), None);
+        assert_eq!(quirks.apply("clip_sprites"), None);
+
+        for name in Quirks::names() {
+            assert!(Quirks::default().set(name, true), "{}", name);
+        }
+    }
+
+    #[test]
+    fn rom_profiles() {
+        let config = QuirksConfig::parse(
+            r#"{
+                "quirks": { "logic_resets_vf": true },
+                "roms": {
+                    "BLITZ": { "quirks": { "clip_sprites": true } },
+                    "alien.ch8": { "preset": "schip", "quirks": { "logic_resets_vf": false } }
+                }
+            }"#,
+        )
+        .unwrap();
+
+        let other = config.quirks(Some("roms/PONG"), Mode::Chip8);
+        assert_eq!(
+            other,
+            Quirks {
+                logic_resets_vf: true,
+                ..Quirks::default()
+            }
+        );
+        assert_eq!(
+            config.quirks(None, Mode::XoChip),
+            Quirks {
+                logic_resets_vf: true,
+                ..Quirks::XO_CHIP
+            }
+        );
+
+        let blitz = config.quirks(Some("www/roms/blitz"), Mode::Chip8);
+        assert!(blitz.clip_sprites);
+        assert!(blitz.logic_resets_vf);
+
+        assert_eq!(config.quirks(Some("ALIEN.ch8"), Mode::Chip8), Quirks::SCHIP);
+    }
+
+    #[test]
+    fn load_preset() {
+        let config = load("vip").unwrap();
+
+        assert_eq!(
+            config.quirks(Some("PONG"), Mode::XoChip),
+            Quirks::COSMAC_VIP
+        );
+        assert!(load("missing.json").is_err());
+    }
+
+    #[test]
+    fn web_quirks() {
+        let config = QuirksConfig::parse(include_str!("../www/quirks.json")).unwrap();
+
+        assert!(config.quirks(Some("BLITZ"), Mode::Chip8).clip_sprites);
+    }
+
+    #[test]
+    fn parse_errors() {
+        let error = |text: &str| QuirksConfig::parse(text).unwrap_err().to_string();
+
+        assert_eq!(
+            error(r#"{ "preset": "gameboy" }"#),
+            "quirks: unknown preset \"gameboy\""
+        );
+        assert_eq!(
+            error(r#"{ "quirks": { "wrap_sprites": true } }"#),
+            "quirks: unknown quirk wrap_sprites"
+        );
+        assert_eq!(
+            error(r#"{ "roms": { "PONG": { "quirks": { "clip_sprites": 1 } } } }"#),
+            "PONG: invalid value 1 for clip_sprites"
+        );
+        assert_eq!(error(r#"{ "roms": [] }"#), "roms must be an object");
+    }
+}
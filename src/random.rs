@@ -0,0 +1,144 @@
+/// Where RND gets its numbers from. The state goes into save states, so a restored VM
+/// continues with the same numbers it would have returned when the state was saved
+pub trait RandomSource {
+    fn next_byte(&mut self) -> u8;
+    fn state(&self) -> u64;
+    fn set_state(&mut self, state: u64);
+}
+
+// SplitMix64, small and fast with good statistical quality, the same seed always gives
+// the same sequence on every platform
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SplitMix64 {
+    state: u64,
+}
+
+impl SplitMix64 {
+    pub fn new(seed: u64) -> SplitMix64 {
+        SplitMix64 { state: seed }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+}
+
+impl RandomSource for SplitMix64 {
+    fn next_byte(&mut self) -> u8 {
+        (self.next_u64() >> 56) as u8
+    }
+
+    fn state(&self) -> u64 {
+        self.state
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.state = state;
+    }
+}
+
+/// Returns the given bytes in order and starts over at the end, for tests that need
+/// exact RND results
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sequence {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl Sequence {
+    pub fn new(bytes: &[u8]) -> Sequence {
+        assert!(
+            !bytes.is_empty(),
+            "A random sequence needs at least one byte"
+        );
+
+        Sequence {
+            bytes: bytes.to_vec(),
+            position: 0,
+        }
+    }
+}
+
+impl RandomSource for Sequence {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.position];
+        self.position = (self.position + 1) % self.bytes.len();
+        byte
+    }
+
+    fn state(&self) -> u64 {
+        self.position as u64
+    }
+
+    fn set_state(&mut self, state: u64) {
+        self.position = state as usize % self.bytes.len();
+    }
+}
+
+/// A seed from the platform random number generator, different on every run
+pub fn system_seed() -> u64 {
+    if cfg!(target_arch = "wasm32") {
+        extern crate wbg_rand;
+        use wbg_rand::{wasm_rng, Rng};
+
+        wasm_rng().gen()
+    } else {
+        extern crate rand;
+        use rand::Rng;
+
+        rand::thread_rng().gen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bytes(source: &mut dyn RandomSource, count: usize) -> Vec<u8> {
+        (0..count).map(|_| source.next_byte()).collect()
+    }
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let first = bytes(&mut SplitMix64::new(42), 16);
+
+        assert_eq!(first, bytes(&mut SplitMix64::new(42), 16));
+        assert_ne!(first, bytes(&mut SplitMix64::new(43), 16));
+    }
+
+    #[test]
+    fn known_values() {
+        // Reference output of SplitMix64 seeded with 0
+        let mut rng = SplitMix64::new(0);
+
+        assert_eq!(rng.next_u64(), 0xE220_A839_7B1D_CDAF);
+        assert_eq!(rng.next_u64(), 0x6E78_9E6A_A1B9_65F4);
+    }
+
+    #[test]
+    fn restore_state() {
+        let mut rng = SplitMix64::new(7);
+        bytes(&mut rng, 5);
+        let state = rng.state();
+        let expected = bytes(&mut rng, 8);
+        rng.set_state(state);
+
+        assert_eq!(bytes(&mut rng, 8), expected);
+    }
+
+    #[test]
+    fn sequence() {
+        let mut sequence = Sequence::new(&[1, 2, 3]);
+
+        assert_eq!(bytes(&mut sequence, 5), [1, 2, 3, 1, 2]);
+
+        sequence.set_state(0);
+
+        assert_eq!(bytes(&mut sequence, 2), [1, 2]);
+    }
+}
@@ -0,0 +1,171 @@
+use crate::screenshot::{self, Style};
+use crate::vm::VM;
+use gif::{Encoder, EncodingError, Frame, Repeat};
+use std::io::Write;
+
+const FRAMES_PER_SECOND: u64 = 60;
+const MIN_DELAY: u64 = 2; // Viewers slow down anything shorter than 2/100 s
+
+/// Records the framebuffer into an animated GIF, one capture per presented frame
+pub struct Recorder<W: Write> {
+    encoder: Encoder<W>,
+    width: usize,             // Image width in pixels
+    height: usize,            // Image height in pixels
+    pending: Option<Vec<u8>>, // Last distinct image, written once its duration is known
+    pending_start: u64,       // Frame the pending image was first shown on
+    frames: u64,              // Frames captured so far
+}
+
+impl<W: Write> Recorder<W> {
+    /// The image fits the largest resolution of the VM mode, enlarged by the style scale
+    pub fn new(writer: W, vm: &VM, style: &Style) -> Result<Recorder<W>, EncodingError> {
+        let (width, height) = vm.mode().max_resolution();
+        let scale = style.scale.max(1);
+        let (width, height) = (width * scale, height * scale);
+        let mut encoder =
+            Encoder::new(writer, width as u16, height as u16, &style.palette.concat())?;
+
+        encoder.set_repeat(Repeat::Infinite)?;
+
+        Ok(Recorder {
+            encoder,
+            width,
+            height,
+            pending: None,
+            pending_start: 0,
+            frames: 0,
+        })
+    }
+
+    /// Adds the current framebuffer as the next 1/60 s of the animation
+    pub fn capture(&mut self, vm: &VM) -> Result<(), EncodingError> {
+        let pixels = screenshot::render(vm, self.width / vm.width());
+
+        if self.pending.as_ref() != Some(&pixels) {
+            // Frames too short to be shown are replaced by the next one
+            if centiseconds(self.frames) - centiseconds(self.pending_start) >= MIN_DELAY {
+                self.write_pending()?;
+                self.pending_start = self.frames;
+            }
+
+            self.pending = Some(pixels);
+        }
+
+        self.frames += 1;
+        Ok(())
+    }
+
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    /// Writes the last image and the end of the GIF
+    pub fn finish(mut self) -> Result<W, EncodingError> {
+        self.write_pending()?;
+        Ok(self.encoder.into_inner()?)
+    }
+
+    fn write_pending(&mut self) -> Result<(), EncodingError> {
+        let pixels = match self.pending.take() {
+            Some(pixels) => pixels,
+            None => return Ok(()),
+        };
+        let mut delay = centiseconds(self.frames) - centiseconds(self.pending_start);
+        let mut frame =
+            Frame::from_indexed_pixels(self.width as u16, self.height as u16, pixels, None);
+
+        // Delays are 16 bits, so long still images are split
+        while delay > 0 {
+            frame.delay = delay.min(u16::MAX as u64) as u16;
+            self.encoder.write_frame(&frame)?;
+            delay -= frame.delay as u64;
+        }
+
+        Ok(())
+    }
+}
+
+// Rounded time at which a frame starts, so rounding errors do not add up
+fn centiseconds(frame: u64) -> u64 {
+    (frame * 100 + FRAMES_PER_SECOND / 2) / FRAMES_PER_SECOND
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Mode;
+
+    fn decode(data: &[u8]) -> Vec<(u16, Vec<u8>)> {
+        let mut options = gif::DecodeOptions::new();
+        options.set_color_output(gif::ColorOutput::Indexed);
+        let mut decoder = options.read_info(data).unwrap();
+        let mut frames = Vec::new();
+
+        while let Some(frame) = decoder.read_next_frame().unwrap() {
+            frames.push((frame.delay, frame.buffer.to_vec()));
+        }
+
+        frames
+    }
+
+    #[test]
+    fn deduplicates_frames() {
+        let mut vm = VM::initialize(false);
+        let mut recorder = Recorder::new(Vec::new(), &vm, &Style::default()).unwrap();
+
+        for _ in 0..60 {
+            recorder.capture(&vm).unwrap();
+        }
+
+        vm.gfx[0] = 1;
+
+        for _ in 0..30 {
+            recorder.capture(&vm).unwrap();
+        }
+
+        assert_eq!(recorder.frames(), 90);
+
+        let frames = decode(&recorder.finish().unwrap());
+
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].0, 100);
+        assert_eq!(frames[0].1[0], 0);
+        assert_eq!(frames[1].0, 50);
+        assert_eq!(frames[1].1[0], 1);
+    }
+
+    #[test]
+    fn keeps_time_at_60_fps() {
+        let mut vm = VM::initialize(false);
+        let mut recorder = Recorder::new(Vec::new(), &vm, &Style::default()).unwrap();
+
+        for i in 0..60 {
+            vm.gfx[0] = (i % 2) as u8;
+            recorder.capture(&vm).unwrap();
+        }
+
+        let frames = decode(&recorder.finish().unwrap());
+        let total: u64 = frames.iter().map(|&(delay, _)| delay as u64).sum();
+
+        assert_eq!(total, 100);
+        assert!(frames.iter().all(|&(delay, _)| delay >= MIN_DELAY as u16));
+    }
+
+    #[test]
+    fn scales_low_resolution_in_hires_modes() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.gfx[1] = 1;
+        let style = Style {
+            scale: 2,
+            ..Style::default()
+        };
+        let mut recorder = Recorder::new(Vec::new(), &vm, &style).unwrap();
+        recorder.capture(&vm).unwrap();
+
+        let data = recorder.finish().unwrap();
+        let frames = decode(&data);
+
+        assert_eq!(frames[0].1.len(), 256 * 128);
+        assert_eq!(&frames[0].1[..8], &[0, 0, 0, 0, 1, 1, 1, 1]);
+    }
+}
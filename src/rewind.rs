@@ -0,0 +1,246 @@
+use crate::vm::VM;
+use std::collections::VecDeque;
+
+pub const DEFAULT_CAPACITY: usize = 60 * 30; // 30 seconds of frames
+
+// Bounded history of save states. Only the newest state is kept in full, older ones are
+// stored as run-length encoded XOR deltas against the state that follows them.
+pub struct Rewind {
+    capacity: usize,
+    latest: Option<Vec<u8>>,
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    pub fn new(capacity: usize) -> Rewind {
+        Rewind {
+            capacity,
+            latest: None,
+            deltas: VecDeque::new(),
+        }
+    }
+
+    /// Number of frames that can be stepped back
+    pub fn len(&self) -> usize {
+        self.deltas.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deltas.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+    }
+
+    /// Records the current state of the VM, call once per frame
+    pub fn record(&mut self, vm: &VM) {
+        let state = vm.save_state();
+
+        match self.latest {
+            // States only change size when the VM switches mode, the history is useless then
+            Some(ref latest) if latest.len() == state.len() => {
+                self.deltas.push_back(encode_delta(latest, &state));
+
+                if self.deltas.len() > self.capacity {
+                    self.deltas.pop_front();
+                }
+            }
+            _ => self.deltas.clear(),
+        }
+
+        self.latest = Some(state);
+    }
+
+    /// Restores the frame before the last recorded one, returns false when the history is empty.
+    /// Keys keep their current state so the player can resume as soon as rewinding stops.
+    pub fn step_back(&mut self, vm: &mut VM) -> bool {
+        let (latest, delta) = match (self.latest.as_mut(), self.deltas.pop_back()) {
+            (Some(latest), Some(delta)) => (latest, delta),
+            _ => return false,
+        };
+
+        apply_delta(latest, &delta);
+
+        let keys = vm.key;
+
+        if vm.load_state(latest).is_err() {
+            self.clear();
+            return false;
+        }
+
+        vm.key = keys;
+        vm.draw_flag = true;
+        true
+    }
+}
+
+impl Default for Rewind {
+    fn default() -> Rewind {
+        Rewind::new(DEFAULT_CAPACITY)
+    }
+}
+
+// Delta layout: repeated (unchanged length, changed length, XORed changed bytes), lengths
+// are LEB128 varints. Applying a delta to either state yields the other one.
+fn encode_delta(previous: &[u8], current: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut position = 0;
+
+    while position < current.len() {
+        let unchanged_start = position;
+
+        while position < current.len() && previous[position] == current[position] {
+            position += 1;
+        }
+
+        let changed_start = position;
+
+        while position < current.len() && previous[position] != current[position] {
+            position += 1;
+        }
+
+        write_varint(&mut delta, changed_start - unchanged_start);
+        write_varint(&mut delta, position - changed_start);
+
+        for offset in changed_start..position {
+            delta.push(previous[offset] ^ current[offset]);
+        }
+    }
+
+    delta
+}
+
+fn apply_delta(state: &mut [u8], delta: &[u8]) {
+    let mut position = 0;
+    let mut cursor = 0;
+
+    while cursor < delta.len() {
+        position += read_varint(delta, &mut cursor);
+        let changed = read_varint(delta, &mut cursor);
+
+        for offset in 0..changed {
+            state[position + offset] ^= delta[cursor + offset];
+        }
+
+        position += changed;
+        cursor += changed;
+    }
+}
+
+fn write_varint(buffer: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+
+    buffer.push(value as u8);
+}
+
+fn read_varint(buffer: &[u8], cursor: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = buffer[*cursor];
+        *cursor += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ADD V0, 1 forever, so every frame leaves a different state behind
+    fn counting_vm() -> VM {
+        let mut vm = VM::initialize(false);
+        vm.set_clock_speed(60);
+
+        for address in (0x200..0x1000).step_by(2) {
+            vm.memory[address] = 0x70;
+            vm.memory[address + 1] = 0x01;
+        }
+
+        vm
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let previous = vec![0; 300];
+        let mut current = previous.clone();
+        current[0] = 1;
+        current[200] = 2;
+        current[201] = 3;
+        current[299] = 4;
+
+        let delta = encode_delta(&previous, &current);
+        let mut state = current.clone();
+        apply_delta(&mut state, &delta);
+
+        assert_eq!(state, previous);
+        assert!(delta.len() < 20);
+    }
+
+    #[test]
+    fn step_back() {
+        let mut vm = counting_vm();
+        let mut rewind = Rewind::new(10);
+        rewind.record(&vm);
+
+        for _ in 0..3 {
+            vm.run_frame().unwrap();
+            rewind.record(&vm);
+        }
+
+        assert_eq!(vm.v()[0x0], 3);
+        assert_eq!(rewind.len(), 3);
+
+        assert!(rewind.step_back(&mut vm));
+        assert_eq!(vm.v()[0x0], 2);
+        assert_eq!(vm.pc(), 0x204);
+
+        assert!(rewind.step_back(&mut vm));
+        assert!(rewind.step_back(&mut vm));
+        assert_eq!(vm.v()[0x0], 0);
+        assert!(!rewind.step_back(&mut vm));
+    }
+
+    #[test]
+    fn capacity() {
+        let mut vm = counting_vm();
+        let mut rewind = Rewind::new(2);
+        rewind.record(&vm);
+
+        for _ in 0..5 {
+            vm.run_frame().unwrap();
+            rewind.record(&vm);
+        }
+
+        assert_eq!(rewind.len(), 2);
+
+        while rewind.step_back(&mut vm) {}
+
+        assert_eq!(vm.v()[0x0], 3);
+    }
+
+    #[test]
+    fn step_back_keeps_keys() {
+        let mut vm = counting_vm();
+        let mut rewind = Rewind::new(10);
+        rewind.record(&vm);
+        vm.run_frame().unwrap();
+        rewind.record(&vm);
+        vm.set_key(0x5, true);
+
+        assert!(rewind.step_back(&mut vm));
+        assert!(vm.key[0x5]);
+    }
+}
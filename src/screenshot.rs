@@ -0,0 +1,182 @@
+use crate::vm::{PALETTE, VM};
+use std::fs::File;
+use std::io::Write;
+
+// Characters for each pixel value, like the colors of PALETTE
+const ASCII_PIXELS: [char; 4] = ['.', '#', '+', '@'];
+
+// How the framebuffer is turned into an image
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Style {
+    pub scale: usize,          // Image pixels per VM pixel, in each direction
+    pub palette: [[u8; 3]; 4], // Color of each pixel value
+}
+
+impl Style {
+    /// Replaces the colors of unlit (0) and lit (1) pixels
+    pub fn with_colors(mut self, foreground: [u8; 3], background: [u8; 3]) -> Style {
+        self.palette[0] = background;
+        self.palette[1] = foreground;
+        self
+    }
+}
+
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            scale: 1,
+            palette: PALETTE,
+        }
+    }
+}
+
+/// Parses a color written as RRGGBB or #RRGGBB
+pub fn parse_color(text: &str) -> Option<[u8; 3]> {
+    let hex = text.strip_prefix('#').unwrap_or(text);
+
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channel = |index: usize| u8::from_str_radix(&hex[index..index + 2], 16).ok();
+
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+/// Framebuffer as text, one line per row
+pub fn to_ascii(vm: &VM) -> String {
+    let width = vm.width();
+    let mut output = String::with_capacity((width + 1) * vm.height());
+
+    for row in vm.gfx[..width * vm.height()].chunks(width) {
+        output.extend(row.iter().map(|&pixel| ASCII_PIXELS[pixel as usize & 0x3]));
+        output.push('\n');
+    }
+
+    output
+}
+
+/// Encodes the framebuffer at its current resolution as a PNG
+pub fn write_png<W: Write>(vm: &VM, style: &Style, writer: W) -> Result<(), png::EncodingError> {
+    let (width, height) = (vm.width(), vm.height());
+    let scale = style.scale.max(1);
+    let mut encoder = png::Encoder::new(writer, (width * scale) as u32, (height * scale) as u32);
+
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_palette(style.palette.concat());
+
+    let mut writer = encoder.write_header()?;
+
+    writer.write_image_data(&render(vm, scale))
+}
+
+// Pixel values of the framebuffer, each one repeated scale times in both directions
+pub(crate) fn render(vm: &VM, scale: usize) -> Vec<u8> {
+    let (width, height) = (vm.width(), vm.height());
+    let mut pixels = Vec::with_capacity(width * height * scale * scale);
+
+    for row in vm.gfx[..width * height].chunks(width) {
+        let line: Vec<u8> = row
+            .iter()
+            .flat_map(|&pixel| (0..scale).map(move |_| pixel & 0x3))
+            .collect();
+
+        for _ in 0..scale {
+            pixels.extend_from_slice(&line);
+        }
+    }
+
+    pixels
+}
+
+/// Writes the framebuffer to a PNG file
+pub fn save_png(vm: &VM, style: &Style, path: &str) -> Result<(), png::EncodingError> {
+    write_png(vm, style, File::create(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Mode;
+
+    fn vm() -> VM {
+        let mut vm = VM::initialize(false);
+        vm.gfx[1] = 1;
+        vm.gfx[64] = 1;
+        vm
+    }
+
+    fn decode(data: &[u8]) -> (u32, u32, Vec<u8>, Vec<u8>) {
+        let decoder = png::Decoder::new(data);
+        let mut reader = decoder.read_info().unwrap();
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        reader.next_frame(&mut pixels).unwrap();
+
+        let info = reader.info();
+        let palette = info.palette.as_ref().unwrap().to_vec();
+        (info.width, info.height, pixels, palette)
+    }
+
+    #[test]
+    fn ascii() {
+        let ascii = to_ascii(&vm());
+        let lines: Vec<&str> = ascii.lines().collect();
+
+        assert_eq!(lines.len(), 32);
+        assert_eq!(&lines[0][..4], ".#..");
+        assert_eq!(&lines[1][..4], "#...");
+    }
+
+    #[test]
+    fn png() {
+        let mut data = Vec::new();
+        write_png(&vm(), &Style::default(), &mut data).unwrap();
+
+        let (width, height, pixels, palette) = decode(&data);
+
+        assert_eq!((width, height), (64, 32));
+        assert_eq!(&pixels[..2], &[0, 1]);
+        assert_eq!(palette, PALETTE.concat());
+    }
+
+    #[test]
+    fn png_scale_and_colors() {
+        let style = Style {
+            scale: 3,
+            ..Style::default()
+        }
+        .with_colors([0xFF, 0x80, 0x00], [0x10, 0x20, 0x30]);
+        let mut data = Vec::new();
+        write_png(&vm(), &style, &mut data).unwrap();
+
+        let (width, height, pixels, palette) = decode(&data);
+
+        assert_eq!((width, height), (192, 96));
+        assert_eq!(&pixels[..7], &[0, 0, 0, 1, 1, 1, 0]);
+        assert_eq!(&pixels[192..199], &[0, 0, 0, 1, 1, 1, 0]);
+        assert_eq!(&pixels[3 * 192..3 * 192 + 4], &[1, 1, 1, 0]);
+        assert_eq!(&palette[..6], &[0x10, 0x20, 0x30, 0xFF, 0x80, 0x00]);
+    }
+
+    #[test]
+    fn png_hires() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.load_rom(&[0x00, 0xFF]).unwrap();
+        vm.emulate_cycle().unwrap();
+        let mut data = Vec::new();
+        write_png(&vm, &Style::default(), &mut data).unwrap();
+
+        let (width, height, _, _) = decode(&data);
+
+        assert_eq!((width, height), (128, 64));
+    }
+
+    #[test]
+    fn colors() {
+        assert_eq!(parse_color("#FF8000"), Some([0xFF, 0x80, 0x00]));
+        assert_eq!(parse_color("0a0b0c"), Some([0x0A, 0x0B, 0x0C]));
+        assert_eq!(parse_color("FF80"), None);
+        assert_eq!(parse_color("GG0000"), None);
+    }
+}
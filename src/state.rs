@@ -0,0 +1,238 @@
+use std::error::Error;
+use std::fmt;
+
+// Save state layout: magic, format version, little endian fields, CRC-32 of everything before it
+const MAGIC: &[u8; 4] = b"C8ST";
+pub const VERSION: u8 = 4;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    ChecksumMismatch,
+    InvalidValue(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::InvalidMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version: {}", version)
+            }
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::ChecksumMismatch => write!(f, "Save state is corrupted"),
+            StateError::InvalidValue(field) => write!(f, "Invalid {} in save state", field),
+        }
+    }
+}
+
+impl Error for StateError {}
+
+pub struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        let mut buffer = MAGIC.to_vec();
+        buffer.push(VERSION);
+
+        StateWriter { buffer }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buffer.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buffer.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    pub fn finish(mut self) -> Vec<u8> {
+        let checksum = crc32(&self.buffer);
+
+        self.write_u32(checksum);
+        self.buffer
+    }
+}
+
+impl Default for StateWriter {
+    fn default() -> StateWriter {
+        StateWriter::new()
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> StateReader<'a> {
+    /// Validates the header and checksum, then reads fields in the order they were written
+    pub fn new(data: &'a [u8]) -> Result<StateReader<'a>, StateError> {
+        if data.len() < MAGIC.len() + 1 + 4 {
+            return Err(StateError::Truncated);
+        }
+
+        if &data[..MAGIC.len()] != MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+
+        let (content, checksum) = data.split_at(data.len() - 4);
+
+        if crc32(content).to_le_bytes() != checksum {
+            return Err(StateError::ChecksumMismatch);
+        }
+
+        let version = content[MAGIC.len()];
+
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        Ok(StateReader {
+            data: content,
+            position: MAGIC.len() + 1,
+        })
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue("flag")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.read_bytes(2)?;
+
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.read_bytes(4)?;
+
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_bytes(&mut self, length: usize) -> Result<&'a [u8], StateError> {
+        if self.position + length > self.data.len() {
+            return Err(StateError::Truncated);
+        }
+
+        let bytes = &self.data[self.position..self.position + length];
+        self.position += length;
+        Ok(bytes)
+    }
+}
+
+// CRC-32 (IEEE 802.3), the same checksum used by zip and PNG
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+
+    for &byte in data {
+        crc ^= byte as u32;
+
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut writer = StateWriter::new();
+        writer.write_u8(0x2A);
+        writer.write_bool(true);
+        writer.write_u16(0x1234);
+        writer.write_u32(0xDEAD_BEEF);
+        writer.write_u64(0x0123_4567_89AB_CDEF);
+        writer.write_bytes(&[1, 2, 3]);
+        let data = writer.finish();
+
+        let mut reader = StateReader::new(&data).unwrap();
+
+        assert_eq!(reader.read_u8(), Ok(0x2A));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0x1234));
+        assert_eq!(reader.read_u32(), Ok(0xDEAD_BEEF));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89AB_CDEF));
+        assert_eq!(reader.read_bytes(3), Ok(&[1, 2, 3][..]));
+        assert_eq!(reader.read_u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let mut writer = StateWriter::new();
+        writer.write_u16(0x1234);
+        let mut data = writer.finish();
+        data[5] ^= 0xFF;
+
+        assert_eq!(
+            StateReader::new(&data).err(),
+            Some(StateError::ChecksumMismatch)
+        );
+    }
+
+    #[test]
+    fn invalid_magic() {
+        assert_eq!(
+            StateReader::new(b"NOPE\x01\x00\x00\x00\x00").err(),
+            Some(StateError::InvalidMagic)
+        );
+    }
+
+    #[test]
+    fn unsupported_version() {
+        let mut data = b"C8ST\x09".to_vec();
+        let checksum = crc32(&data);
+        data.extend_from_slice(&checksum.to_le_bytes());
+
+        assert_eq!(
+            StateReader::new(&data).err(),
+            Some(StateError::UnsupportedVersion(9))
+        );
+    }
+}
@@ -0,0 +1,248 @@
+use crate::vm::VM;
+
+// A half-block cell covers two pixel rows: none, top, bottom or both lit
+const HALF_BLOCKS: [char; 4] = [' ', '▀', '▄', '█'];
+
+// Dot bit of each pixel in a 2x4 braille cell, indexed by [row][column]
+const BRAILLE_DOTS: [[u32; 2]; 4] = [[0x01, 0x08], [0x02, 0x10], [0x04, 0x20], [0x40, 0x80]];
+const BRAILLE_BLANK: u32 = 0x2800;
+
+// Terminals only report key presses, repeated while the key stays down. A tapped key is held
+// long enough to outlast the delay before repeats start, then each repeat keeps it down a bit
+pub const TAP_FRAMES: u32 = 30;
+pub const REPEAT_FRAMES: u32 = 6;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Hold {
+    Up,
+    Frames(u32), // Released after this many more frames unless the key repeats
+    Down,        // Held until the terminal reports the release
+}
+
+/// State of the 16 keys when the terminal may or may not report key releases
+#[derive(Clone, Debug, PartialEq)]
+pub struct HeldKeys {
+    keys: [Hold; 16],
+}
+
+impl HeldKeys {
+    pub fn new() -> HeldKeys {
+        HeldKeys {
+            keys: [Hold::Up; 16],
+        }
+    }
+
+    /// A press or repeat from a terminal that does not report releases
+    pub fn tap(&mut self, key: usize) {
+        self.keys[key] = match self.keys[key] {
+            Hold::Up => Hold::Frames(TAP_FRAMES),
+            Hold::Frames(frames) => Hold::Frames(frames.max(REPEAT_FRAMES)),
+            Hold::Down => Hold::Down,
+        };
+    }
+
+    /// A press that will be followed by a `release`
+    pub fn press(&mut self, key: usize) {
+        self.keys[key] = Hold::Down;
+    }
+
+    pub fn release(&mut self, key: usize) {
+        self.keys[key] = Hold::Up;
+    }
+
+    /// Sets the VM keys for the next frame, call once per frame
+    pub fn update(&mut self, vm: &mut VM) {
+        for (key, hold) in self.keys.iter_mut().enumerate() {
+            vm.set_key(key, *hold != Hold::Up);
+
+            *hold = match *hold {
+                Hold::Frames(frames) if frames > 1 => Hold::Frames(frames - 1),
+                Hold::Frames(_) => Hold::Up,
+                other => other,
+            };
+        }
+    }
+}
+
+impl Default for HeldKeys {
+    fn default() -> HeldKeys {
+        HeldKeys::new()
+    }
+}
+
+/// Framebuffer as half-block characters, one line per two rows
+pub fn half_blocks(vm: &VM) -> Vec<String> {
+    (0..vm.height())
+        .step_by(2)
+        .map(|y| {
+            (0..vm.width())
+                .map(|x| HALF_BLOCKS[lit(vm, x, y) as usize | (lit(vm, x, y + 1) as usize) << 1])
+                .collect()
+        })
+        .collect()
+}
+
+/// Framebuffer as braille characters, one character per 2x4 pixels
+pub fn braille(vm: &VM) -> Vec<String> {
+    (0..vm.height())
+        .step_by(4)
+        .map(|y| {
+            (0..vm.width())
+                .step_by(2)
+                .map(|x| {
+                    let mut dots = 0;
+
+                    for (row, bits) in BRAILLE_DOTS.iter().enumerate() {
+                        for (column, bit) in bits.iter().enumerate() {
+                            if lit(vm, x + column, y + row) {
+                                dots |= bit;
+                            }
+                        }
+                    }
+
+                    std::char::from_u32(BRAILLE_BLANK + dots).unwrap_or(' ')
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// The registers `debug_registers` prints, then the timers and the call stack, innermost first
+pub fn panel(vm: &VM) -> Vec<String> {
+    let mut lines = vm.register_lines();
+
+    lines.push(format!(
+        "DT: {}  ST: {}",
+        vm.delay_timer(),
+        vm.sound_timer()
+    ));
+    lines.push(format!("SP: {}", vm.sp()));
+    lines.extend(
+        vm.stack()[..vm.sp() as usize]
+            .iter()
+            .enumerate()
+            .rev()
+            .map(|(depth, address)| format!("#{} 0x{:03X}", depth, address)),
+    );
+
+    lines
+}
+
+// Any plane counts, the terminal has a single color
+fn lit(vm: &VM, x: usize, y: usize) -> bool {
+    x < vm.width() && y < vm.height() && vm.gfx[y * vm.width() + x] != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::Mode;
+
+    // Top left corner lit, the pixel below the next one and a vertical bar at x = 3
+    fn vm() -> VM {
+        let mut vm = VM::initialize(false);
+        vm.gfx[0] = 1;
+        vm.gfx[64 + 1] = 1;
+        vm.gfx[3] = 1;
+        vm.gfx[64 + 3] = 1;
+        vm
+    }
+
+    #[test]
+    fn half_block_rows() {
+        let lines = half_blocks(&vm());
+
+        assert_eq!(lines.len(), 16);
+        assert_eq!(lines[0].chars().count(), 64);
+        assert!(lines[0].starts_with("▀▄ █ "));
+        assert!(lines[1].chars().all(|c| c == ' '));
+    }
+
+    #[test]
+    fn braille_cells() {
+        let lines = braille(&vm());
+
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0].chars().count(), 32);
+        // Dots 1 and 5 in the first cell, 4 and 5 in the second
+        assert!(lines[0].starts_with("\u{2811}\u{2818}\u{2800}"));
+    }
+
+    #[test]
+    fn hires_size() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.load_rom(&[0x00, 0xFF]).unwrap(); // HIGH
+
+        assert_eq!(half_blocks(&vm).len(), 16);
+
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(half_blocks(&vm).len(), 32);
+        assert_eq!(half_blocks(&vm)[0].chars().count(), 128);
+    }
+
+    #[test]
+    fn panel_shows_stack() {
+        let mut vm = VM::initialize(false);
+        vm.load_rom(&[0x22, 0x04, 0x00, 0x00, 0x22, 0x08]).unwrap();
+        vm.emulate_cycle().unwrap();
+        vm.emulate_cycle().unwrap();
+
+        let lines = panel(&vm);
+
+        assert_eq!(lines[17], "pc: 0x208");
+        assert_eq!(&lines[lines.len() - 3..], ["SP: 2", "#1 0x204", "#0 0x200"]);
+    }
+
+    #[test]
+    fn tapped_key_is_released() {
+        let mut vm = VM::initialize(false);
+        let mut keys = HeldKeys::new();
+        keys.tap(5);
+
+        for _ in 0..TAP_FRAMES {
+            keys.update(&mut vm);
+            assert!(vm.key[5]);
+        }
+
+        keys.update(&mut vm);
+        assert!(!vm.key[5]);
+    }
+
+    #[test]
+    fn repeats_keep_key_down() {
+        let mut vm = VM::initialize(false);
+        let mut keys = HeldKeys::new();
+        keys.tap(5);
+
+        for _ in 0..TAP_FRAMES + 20 {
+            keys.tap(5);
+            keys.update(&mut vm);
+        }
+
+        for _ in 0..REPEAT_FRAMES {
+            assert!(vm.key[5]);
+            keys.update(&mut vm);
+        }
+
+        assert!(!vm.key[5]);
+    }
+
+    #[test]
+    fn pressed_key_waits_for_release() {
+        let mut vm = VM::initialize(false);
+        let mut keys = HeldKeys::new();
+        keys.press(0xA);
+
+        for _ in 0..TAP_FRAMES * 2 {
+            keys.update(&mut vm);
+        }
+
+        assert!(vm.key[0xA]);
+
+        keys.release(0xA);
+        keys.update(&mut vm);
+
+        assert!(!vm.key[0xA]);
+    }
+}
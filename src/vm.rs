@@ -1,36 +1,186 @@
+use crate::disasm;
+use crate::quirks::Quirks;
+use crate::random::{self, RandomSource, SplitMix64};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::watch::{Access, WatchHit, Watchpoint};
+use std::error::Error;
+use std::fmt;
 use std::fs;
+use std::io;
+use std::mem;
+
+// Prints an instruction trace line, only when the VM runs in debug mode
+macro_rules! trace {
+    ($vm:expr, $($arg:tt)*) => {
+        if $vm.debug {
+            println!($($arg)*);
+        }
+    };
+}
+
+pub const TIMER_FREQUENCY: u32 = 60; // Delay and sound timers tick at 60 Hz
+pub const DEFAULT_CLOCK_SPEED: u32 = 600; // Instructions per second
+pub const MAX_WIDTH: usize = 128; // SUPER-CHIP hi-res display is 128x64 pixels
+pub const MAX_HEIGHT: usize = 64;
+pub const MAX_MEMORY: usize = 0x10000; // XO-CHIP has a 64KB address space
+const BIG_FONT_ADDRESS: usize = 0x50; // 8x10 SUPER-CHIP font, right after the 4x5 one
+
+// Colors for each pixel value, i.e. for each combination of the two XO-CHIP bitplanes
+pub const PALETTE: [[u8; 3]; 4] = [
+    [0x00, 0x00, 0x00], // No plane
+    [0xFF, 0xFF, 0xFF], // Plane 1
+    [0xAA, 0xAA, 0xAA], // Plane 2
+    [0x55, 0x55, 0x55], // Both planes
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    Chip8,     // Original COSMAC VIP instruction set
+    SuperChip, // SCHIP 1.1: hi-res display, scrolling, big font and RPL flags
+    XoChip,    // Octo's XO-CHIP: SCHIP plus 64KB memory, bitplanes and audio patterns
+}
+
+impl Mode {
+    pub fn name(self) -> &'static str {
+        match self {
+            Mode::Chip8 => "chip8",
+            Mode::SuperChip => "schip",
+            Mode::XoChip => "xochip",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Mode> {
+        match name.to_lowercase().as_str() {
+            "chip8" | "chip-8" => Some(Mode::Chip8),
+            "schip" | "superchip" | "super-chip" => Some(Mode::SuperChip),
+            "xochip" | "xo-chip" => Some(Mode::XoChip),
+            _ => None,
+        }
+    }
+
+    /// Largest display size in pixels, hi-res only exists from SUPER-CHIP on
+    pub fn max_resolution(self) -> (usize, usize) {
+        match self {
+            Mode::Chip8 => (64, 32),
+            Mode::SuperChip | Mode::XoChip => (MAX_WIDTH, MAX_HEIGHT),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum VmError {
+    UnknownOpcode { opcode: u16, pc: u16 },
+    StackOverflow { pc: u16 },
+    StackUnderflow { pc: u16 },
+    MemoryOutOfBounds { address: usize, pc: u16 },
+    RomTooLarge { size: usize, max: usize },
+    Io(io::Error),
+}
+
+impl fmt::Display for VmError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VmError::UnknownOpcode { opcode, pc } => {
+                write!(f, "Opcode not handled: 0x{:04X} at 0x{:03X}", opcode, pc)
+            }
+            VmError::StackOverflow { pc } => write!(f, "Stack overflow at 0x{:03X}", pc),
+            VmError::StackUnderflow { pc } => write!(f, "Stack underflow at 0x{:03X}", pc),
+            VmError::MemoryOutOfBounds { address, pc } => write!(
+                f,
+                "Memory access out of bounds: 0x{:04X} at 0x{:03X}",
+                address, pc
+            ),
+            VmError::RomTooLarge { size, max } => {
+                write!(f, "ROM too large: {} bytes (max {} bytes)", size, max)
+            }
+            VmError::Io(error) => write!(f, "Cannot read ROM: {}", error),
+        }
+    }
+}
+
+impl Error for VmError {}
+
+impl From<io::Error> for VmError {
+    fn from(error: io::Error) -> VmError {
+        VmError::Io(error)
+    }
+}
+
+// Progress of FX0A, which like the COSMAC VIP waits for a key to be pressed and released
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum KeyWait {
+    Press([bool; 16]), // Keys held when the wait started, they count once released
+    Release(u8),       // Key pressed, stored in VX when it goes up
+}
 
 pub struct VM {
-    opcode: u16,            // 2 bytes opcodes
-    pub memory: [u8; 4096], // 4KB of memory == 4096 bytes
-    v: [u8; 16],            // 16 8-bit registers (from V0 to VE)
-    i: u16,                 // Index register, 2 bytes
-    pc: u16,                // Program counter, 2 bytes
-    stack: [u16; 16],       // Stack
-    sp: u16,                // Stack Pointer
-    pub key: [bool; 16],    // 1 bit for each input direction + controls
-    pub gfx: [u8; 64 * 32], // Graphics is 64x32 pixels resolution, 1 byte each
-    delay_timer: u8,        // Timer for events
-    sound_timer: u8,        // Timer for emitting sounds. When zero, sound is emitted
-    pub draw_flag: bool,    // Flush graphic
-    debug: bool,            // Debug mode
+    opcode: u16,                  // 2 bytes opcodes
+    pub memory: [u8; 0x10000],    // 4KB of memory, 64KB in XO-CHIP mode
+    v: [u8; 16],                  // 16 8-bit registers (from V0 to VE)
+    i: u16,                       // Index register, 2 bytes
+    pc: u16,                      // Program counter, 2 bytes
+    stack: [u16; 16],             // Stack
+    sp: u16,                      // Stack Pointer
+    pub key: [bool; 16],          // 1 bit for each input direction + controls
+    pub gfx: [u8; 128 * 64],      // 1 byte per pixel, rows are width() pixels long
+    plane: u8,                    // XO-CHIP bitplanes affected by drawing, bit 0 is plane 1
+    audio_pattern: [u8; 16],      // XO-CHIP 1-bit audio samples
+    pitch: u8,                    // XO-CHIP audio pattern playback pitch
+    hires: bool,                  // SUPER-CHIP 128x64 mode, 64x32 otherwise
+    rpl: [u8; 16],                // SUPER-CHIP RPL user flags
+    halted: bool,                 // Set by the SUPER-CHIP EXIT instruction
+    mode: Mode,                   // Instruction set being emulated
+    quirks: Quirks,               // Interpreter specific behaviours
+    delay_timer: u8,              // Timer for events
+    sound_timer: u8,              // Timer for emitting sounds, the buzzer sounds while non-zero
+    pub draw_flag: bool,          // Flush graphic
+    clock_speed: u32,             // Instructions executed per second
+    cycle_budget: u32,            // Leftover instructions owed from previous frames, in 1/60ths
+    key_wait: Option<KeyWait>,    // Set while FX0A waits for a key
+    watchpoints: Vec<Watchpoint>, // Empty during normal play, so checks stay cheap
+    watch_hit: Option<WatchHit>,  // Watchpoint triggered by the last instruction
+    rng: Box<dyn RandomSource>,   // Numbers returned by RND, saved in save states
+    debug: bool,                  // Debug mode
 }
 
 impl VM {
     pub fn initialize(debug: bool) -> VM {
+        VM::with_mode(Mode::Chip8, debug)
+    }
+
+    pub fn with_mode(mode: Mode, debug: bool) -> VM {
+        VM::with_seed(mode, random::system_seed(), debug)
+    }
+
+    /// A VM whose RND returns the same sequence on every run with the same seed
+    pub fn with_seed(mode: Mode, seed: u64, debug: bool) -> VM {
         VM {
             pc: 0x200,
             opcode: 0,
-            memory: [0; 4096],
+            memory: [0; MAX_MEMORY],
             v: [0; 16],
             i: 0,
             stack: [0; 16],
             sp: 0,
             key: [false; 16],
-            gfx: [0; 64 * 32],
+            gfx: [0; MAX_WIDTH * MAX_HEIGHT],
+            hires: false,
+            plane: 1,
+            audio_pattern: [0; 16],
+            pitch: 64,
+            rpl: [0; 16],
+            halted: false,
+            mode,
+            quirks: Quirks::for_mode(mode),
             delay_timer: 0,
-            sound_timer: 0xFF,
+            sound_timer: 0,
             draw_flag: false,
+            clock_speed: DEFAULT_CLOCK_SPEED,
+            cycle_budget: 0,
+            key_wait: None,
+            watchpoints: Vec::new(),
+            watch_hit: None,
+            rng: Box::new(SplitMix64::new(seed)),
             debug: debug,
         }
     }
@@ -38,26 +188,216 @@ impl VM {
     pub fn reset(&mut self) {
         self.pc = 0x200;
         self.opcode = 0;
-        self.memory = [0; 4096];
+        self.memory = [0; MAX_MEMORY];
         self.v = [0; 16];
         self.i = 0;
         self.stack = [0; 16];
         self.sp = 0;
         self.key = [false; 16];
-        self.gfx = [0; 64 * 32];
+        self.gfx = [0; MAX_WIDTH * MAX_HEIGHT];
+        self.hires = false;
+        self.plane = 1;
+        self.audio_pattern = [0; 16];
+        self.pitch = 64;
+        self.halted = false;
         self.delay_timer = 0;
         self.sound_timer = 0;
         self.draw_flag = false;
+        self.cycle_budget = 0;
+        self.key_wait = None;
+    }
+
+    /// Loads a ROM from disk at the program start address (0x200)
+    pub fn load_game(&mut self, path: &str) -> Result<(), VmError> {
+        let buffer = fs::read(path)?;
+
+        self.load_rom(&buffer)
+    }
+
+    /// Loads a ROM image at the program start address (0x200)
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), VmError> {
+        let max = self.memory_size() - 512;
+
+        if rom.len() > max {
+            return Err(VmError::RomTooLarge {
+                size: rom.len(),
+                max,
+            });
+        }
+
+        self.memory[0x200..0x200 + rom.len()].copy_from_slice(rom);
+
+        Ok(())
+    }
+
+    /// Restarts RND with the sequence for this seed, see `with_seed`
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = Box::new(SplitMix64::new(seed));
+    }
+
+    /// Replaces the generator behind RND, e.g. with a fixed `random::Sequence` in tests
+    pub fn set_random_source(&mut self, source: Box<dyn RandomSource>) {
+        self.rng = source;
+    }
+
+    /// Sets the pressed state of one of the 16 hex keypad keys (0x0 - 0xF), other keys are
+    /// ignored
+    pub fn set_key(&mut self, key: usize, pressed: bool) {
+        if let Some(state) = self.key.get_mut(key) {
+            *state = pressed;
+        }
+    }
+
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Addressable memory in bytes, 64KB in XO-CHIP mode and 4KB otherwise
+    pub fn memory_size(&self) -> usize {
+        if self.mode == Mode::XoChip {
+            MAX_MEMORY
+        } else {
+            4096
+        }
+    }
+
+    /// Current display width in pixels, 128 in SUPER-CHIP hi-res mode and 64 otherwise
+    pub fn width(&self) -> usize {
+        if self.hires {
+            MAX_WIDTH
+        } else {
+            64
+        }
+    }
+
+    /// Current display height in pixels, 64 in SUPER-CHIP hi-res mode and 32 otherwise
+    pub fn height(&self) -> usize {
+        if self.hires {
+            MAX_HEIGHT
+        } else {
+            32
+        }
+    }
+
+    /// XO-CHIP 128 samples, 1 bit each, most significant bit first
+    pub fn audio_pattern(&self) -> &[u8; 16] {
+        &self.audio_pattern
+    }
+
+    /// Sample rate the XO-CHIP audio pattern is played at, 4000 Hz for the default pitch
+    pub fn playback_rate(&self) -> f64 {
+        4000.0 * 2f64.powf((self.pitch as f64 - 64.0) / 48.0)
+    }
+
+    /// True while FX0A waits for a key to be pressed and released, the CPU idles meanwhile
+    pub fn waiting_for_key(&self) -> bool {
+        self.key_wait.is_some()
     }
 
-    pub fn load_game(&mut self, path: &String) {
-        let buffer = fs::read(path).unwrap();
+    /// True once the program executed the SUPER-CHIP EXIT instruction
+    pub fn halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Sets how many instructions `run_frame` executes per second of emulated time
+    pub fn set_clock_speed(&mut self, instructions_per_second: u32) {
+        self.clock_speed = instructions_per_second;
+    }
+
+    pub fn clock_speed(&self) -> u32 {
+        self.clock_speed
+    }
 
-        for i in 0..buffer.len() {
-            self.memory[512 + i] = buffer[i];
+    /// Makes `run_frame` stop right after an instruction that triggers the watchpoint. Register
+    /// watchpoints compare the value before and after each instruction, so writing the same value
+    /// does not trigger them
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
         }
     }
 
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|&existing| existing != watchpoint);
+        self.watchpoints.len() != count
+    }
+
+    pub fn watchpoints(&self) -> &[Watchpoint] {
+        &self.watchpoints
+    }
+
+    /// Watchpoint hit by the last executed instruction. Registers and timers are compared
+    /// before and after each instruction, so writes that keep the same value don't count.
+    pub fn watch_hit(&self) -> Option<&WatchHit> {
+        self.watch_hit.as_ref()
+    }
+
+    pub fn v(&self) -> &[u8; 16] {
+        &self.v
+    }
+
+    pub fn i(&self) -> u16 {
+        self.i
+    }
+
+    pub fn pc(&self) -> u16 {
+        self.pc
+    }
+
+    pub fn sp(&self) -> u16 {
+        self.sp
+    }
+
+    pub fn stack(&self) -> &[u16; 16] {
+        &self.stack
+    }
+
+    pub fn opcode(&self) -> u16 {
+        self.opcode
+    }
+
+    pub fn delay_timer(&self) -> u8 {
+        self.delay_timer
+    }
+
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    /// True while the buzzer should sound
+    pub fn sound_active(&self) -> bool {
+        self.sound_timer > 0
+    }
+
+    pub fn set_v(&mut self, x: usize, value: u8) {
+        self.v[x & 0xF] = value;
+    }
+
+    pub fn set_i(&mut self, value: u16) {
+        self.i = value;
+    }
+
+    pub fn set_pc(&mut self, value: u16) {
+        self.pc = value;
+    }
+
+    pub fn set_delay_timer(&mut self, value: u8) {
+        self.delay_timer = value;
+    }
+
+    pub fn set_sound_timer(&mut self, value: u8) {
+        self.sound_timer = value;
+    }
+
     pub fn load_fontset(&mut self) {
         let fontset: [u8; 80] = [
             0xF0, 0x90, 0x90, 0x90, 0xF0, // 0
@@ -78,13 +418,208 @@ impl VM {
             0xF0, 0x80, 0xF0, 0x80, 0x80, // F
         ];
 
+        let big_fontset: [u8; 160] = [
+            0x3C, 0x7E, 0xE7, 0xC3, 0xC3, 0xC3, 0xC3, 0xE7, 0x7E, 0x3C, // 0
+            0x18, 0x38, 0x58, 0x18, 0x18, 0x18, 0x18, 0x18, 0x18, 0x3C, // 1
+            0x3E, 0x7F, 0xC3, 0x06, 0x0C, 0x18, 0x30, 0x60, 0xFF, 0xFF, // 2
+            0x3C, 0x7E, 0xC3, 0x03, 0x0E, 0x0E, 0x03, 0xC3, 0x7E, 0x3C, // 3
+            0x06, 0x0E, 0x1E, 0x36, 0x66, 0xC6, 0xFF, 0xFF, 0x06, 0x06, // 4
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFE, 0x03, 0xC3, 0x7E, 0x3C, // 5
+            0x3E, 0x7C, 0xE0, 0xC0, 0xFC, 0xFE, 0xC3, 0xC3, 0x7E, 0x3C, // 6
+            0xFF, 0xFF, 0x03, 0x06, 0x0C, 0x18, 0x30, 0x60, 0x60, 0x60, // 7
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7E, 0x7E, 0xC3, 0xC3, 0x7E, 0x3C, // 8
+            0x3C, 0x7E, 0xC3, 0xC3, 0x7F, 0x3F, 0x03, 0x03, 0x3E, 0x7C, // 9
+            0x3C, 0x7E, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, // A
+            0xFC, 0xFE, 0xC3, 0xC3, 0xFE, 0xFE, 0xC3, 0xC3, 0xFE, 0xFC, // B
+            0x3C, 0x7E, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0x7E, 0x3C, // C
+            0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xFF, 0xFF, // E
+            0xFF, 0xFF, 0xC0, 0xC0, 0xFC, 0xFC, 0xC0, 0xC0, 0xC0, 0xC0, // F
+        ];
+
         for i in 0..80 {
             self.memory[i] = fontset[i];
         }
+
+        self.memory[BIG_FONT_ADDRESS..BIG_FONT_ADDRESS + big_fontset.len()]
+            .copy_from_slice(&big_fontset);
+    }
+
+    /// Serializes everything needed to resume the running program, see `load_state`
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+
+        writer.write_u8(self.mode as u8);
+        writer.write_bool(self.quirks.shift_uses_vy);
+        writer.write_bool(self.quirks.load_store_increments_i);
+        writer.write_bool(self.quirks.load_store_adds_x_to_i);
+        writer.write_bool(self.quirks.clip_sprites);
+        writer.write_bool(self.quirks.logic_resets_vf);
+        writer.write_bool(self.quirks.jump_uses_vx);
+        writer.write_u16(self.opcode);
+        writer.write_bytes(&self.memory[..self.memory_size()]);
+        writer.write_bytes(&self.v);
+        writer.write_u16(self.i);
+        writer.write_u16(self.pc);
+
+        for &address in self.stack.iter() {
+            writer.write_u16(address);
+        }
+
+        writer.write_u16(self.sp);
+
+        for &pressed in self.key.iter() {
+            writer.write_bool(pressed);
+        }
+
+        writer.write_bytes(&self.gfx);
+        writer.write_u8(self.plane);
+        writer.write_bytes(&self.audio_pattern);
+        writer.write_u8(self.pitch);
+        writer.write_bool(self.hires);
+        writer.write_bytes(&self.rpl);
+        writer.write_bool(self.halted);
+        writer.write_u8(self.delay_timer);
+        writer.write_u8(self.sound_timer);
+        writer.write_bool(self.draw_flag);
+        writer.write_u32(self.clock_speed);
+        writer.write_u32(self.cycle_budget);
+
+        match self.key_wait {
+            None => writer.write_u8(0),
+            Some(KeyWait::Press(held)) => {
+                writer.write_u8(1);
+
+                for &pressed in held.iter() {
+                    writer.write_bool(pressed);
+                }
+            }
+            Some(KeyWait::Release(key)) => {
+                writer.write_u8(2);
+                writer.write_u8(key);
+            }
+        }
+
+        writer.write_u64(self.rng.state());
+
+        writer.finish()
+    }
+
+    /// Restores a state produced by `save_state`, leaving the VM untouched when it is invalid
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data)?;
+
+        let mode = match reader.read_u8()? {
+            0 => Mode::Chip8,
+            1 => Mode::SuperChip,
+            2 => Mode::XoChip,
+            _ => return Err(StateError::InvalidValue("mode")),
+        };
+        let mut restored = VM::with_seed(mode, 0, self.debug);
+
+        restored.quirks = Quirks {
+            shift_uses_vy: reader.read_bool()?,
+            load_store_increments_i: reader.read_bool()?,
+            load_store_adds_x_to_i: reader.read_bool()?,
+            clip_sprites: reader.read_bool()?,
+            logic_resets_vf: reader.read_bool()?,
+            jump_uses_vx: reader.read_bool()?,
+        };
+        restored.opcode = reader.read_u16()?;
+
+        let memory_size = restored.memory_size();
+        restored.memory[..memory_size].copy_from_slice(reader.read_bytes(memory_size)?);
+        restored.v.copy_from_slice(reader.read_bytes(16)?);
+        restored.i = reader.read_u16()?;
+        restored.pc = reader.read_u16()?;
+
+        for address in restored.stack.iter_mut() {
+            *address = reader.read_u16()?;
+        }
+
+        if restored.i as usize >= memory_size {
+            return Err(StateError::InvalidValue("I"));
+        }
+
+        if restored.pc as usize >= memory_size {
+            return Err(StateError::InvalidValue("program counter"));
+        }
+
+        if restored
+            .stack
+            .iter()
+            .any(|&address| address as usize >= memory_size)
+        {
+            return Err(StateError::InvalidValue("stack"));
+        }
+
+        restored.sp = reader.read_u16()?;
+
+        if restored.sp as usize > restored.stack.len() {
+            return Err(StateError::InvalidValue("stack pointer"));
+        }
+
+        for pressed in restored.key.iter_mut() {
+            *pressed = reader.read_bool()?;
+        }
+
+        restored
+            .gfx
+            .copy_from_slice(reader.read_bytes(MAX_WIDTH * MAX_HEIGHT)?);
+        restored.plane = reader.read_u8()?;
+
+        // Only XO-CHIP can select planes other than the first one
+        let planes = if mode == Mode::XoChip { 0x3 } else { 0x1 };
+
+        if restored.plane > planes {
+            return Err(StateError::InvalidValue("plane"));
+        }
+
+        restored
+            .audio_pattern
+            .copy_from_slice(reader.read_bytes(16)?);
+        restored.pitch = reader.read_u8()?;
+        restored.hires = reader.read_bool()?;
+        restored.rpl.copy_from_slice(reader.read_bytes(16)?);
+        restored.halted = reader.read_bool()?;
+        restored.delay_timer = reader.read_u8()?;
+        restored.sound_timer = reader.read_u8()?;
+        restored.draw_flag = reader.read_bool()?;
+        restored.clock_speed = reader.read_u32()?;
+        restored.cycle_budget = reader.read_u32()?;
+        restored.key_wait = match reader.read_u8()? {
+            0 => None,
+            1 => {
+                let mut held = [false; 16];
+
+                for pressed in held.iter_mut() {
+                    *pressed = reader.read_bool()?;
+                }
+
+                Some(KeyWait::Press(held))
+            }
+            2 => match reader.read_u8()? {
+                key if key < 16 => Some(KeyWait::Release(key)),
+                _ => return Err(StateError::InvalidValue("key")),
+            },
+            _ => return Err(StateError::InvalidValue("key wait")),
+        };
+
+        // The VM keeps its own kind of generator, only the position in the sequence is restored
+        let rng_state = reader.read_u64()?;
+        mem::swap(&mut restored.rng, &mut self.rng);
+        restored.rng.set_state(rng_state);
+
+        // Watchpoints belong to the debugging session rather than the saved game
+        mem::swap(&mut restored.watchpoints, &mut self.watchpoints);
+        mem::swap(&mut restored.watch_hit, &mut self.watch_hit);
+
+        *self = restored;
+        Ok(())
     }
 
     pub fn debug_memory(&self) {
-        for i in (0..self.memory.len()).step_by(8) {
+        for i in (0..self.memory_size()).step_by(8) {
             println!(
                 "0x{:02X} | {:02X}{:02X} {:02X}{:02X} {:02X}{:02X} {:02X}{:02X}",
                 i,
@@ -101,37 +636,140 @@ impl VM {
     }
 
     pub fn debug_registers(&self) {
-        for i in 0..16 {
-            println!("V{:X}: {:X}", i, self.v[i as usize]);
+        for line in self.register_lines() {
+            println!("{}", line);
+        }
+    }
+
+    /// The lines `debug_registers` prints, for frontends that show them elsewhere
+    pub fn register_lines(&self) -> Vec<String> {
+        let mut lines: Vec<String> = self
+            .v
+            .iter()
+            .enumerate()
+            .map(|(i, value)| format!("V{:X}: {:X}", i, value))
+            .collect();
+
+        lines.push(format!("I: {:X}", self.i));
+        lines.push(format!("pc: 0x{:02X}", self.pc));
+
+        if let Some(top) = self.stack.get(self.sp as usize) {
+            lines.push(format!("s[sp]: 0x{:02X}", top));
+        }
+
+        lines
+    }
+
+    /// Disassembly of the instruction stored at `address`, "???" when it isn't a known opcode
+    pub fn disassemble_at(&self, address: usize) -> String {
+        let word = |address: usize| {
+            ((self.memory.get(address).cloned().unwrap_or(0) as u16) << 8)
+                | self.memory.get(address + 1).cloned().unwrap_or(0) as u16
+        };
+
+        disasm::instruction(word(address), word(address + 2)).unwrap_or_else(|| "???".to_string())
+    }
+
+    /// Emulates one 60 Hz frame: runs `clock_speed / 60` instructions, then ticks the timers once
+    pub fn run_frame(&mut self) -> Result<(), VmError> {
+        self.run_frame_until(|_| false).map(|_| ())
+    }
+
+    /// Like `run_frame`, but asks `stop` before every instruction. Returns true when the frame
+    /// was cut short, the remaining instructions are dropped and the timers don't tick then.
+    pub fn run_frame_until<F>(&mut self, mut stop: F) -> Result<bool, VmError>
+    where
+        F: FnMut(&VM) -> bool,
+    {
+        self.cycle_budget = self.cycle_budget.saturating_add(self.clock_speed);
+
+        while self.cycle_budget >= TIMER_FREQUENCY {
+            if stop(self) {
+                self.cycle_budget = 0;
+                return Ok(true);
+            }
+
+            self.cycle_budget -= TIMER_FREQUENCY;
+            self.emulate_cycle()?;
+
+            if self.watch_hit.is_some() {
+                self.cycle_budget = 0;
+                return Ok(true);
+            }
+
+            // Keys only change between frames, so the rest of the frame would spin on FX0A
+            if self.key_wait.is_some() {
+                self.cycle_budget %= TIMER_FREQUENCY;
+                break;
+            }
+        }
+
+        self.tick_timers();
+        Ok(false)
+    }
+
+    /// Decrements the delay and sound timers, must be called at 60 Hz
+    pub fn tick_timers(&mut self) {
+        if self.delay_timer > 0 {
+            self.delay_timer -= 1;
         }
 
-        println!("I: {:X}", self.i);
-        println!("pc: 0x{:02X}", self.pc);
-        println!("s[sp]: 0x{:02X}", self.stack[self.sp as usize]);
+        if self.sound_timer > 0 {
+            self.sound_timer -= 1;
+        }
     }
 
-    pub fn emulate_cycle(&mut self) {
+    pub fn emulate_cycle(&mut self) -> Result<(), VmError> {
+        if self.halted {
+            return Ok(());
+        }
+
+        self.watch_hit = None;
+
         // Fetch Opcode
         // -----------
         // Left bitshift + bitwise or = merge two bytes
-        self.opcode = ((self.memory[self.pc as usize] as u16) << 8)
-            | (self.memory[self.pc as usize + 1]) as u16;
+        self.opcode = self.fetch_word(self.pc as usize)?;
 
-        println!("Opcode: 0x{:04X}", self.opcode);
+        trace!(
+            self,
+            "{:03X}: {:04X}  {}",
+            self.pc,
+            self.opcode,
+            self.disassemble_at(self.pc as usize)
+        );
 
         let op_1 = (self.opcode & 0xF000) >> 12;
         let op_2 = (self.opcode & 0x0F00) >> 8;
         let op_3 = (self.opcode & 0x00F0) >> 4;
         let op_4 = self.opcode & 0x000F;
 
+        let watching = !self.watchpoints.is_empty();
+        let pc = self.pc;
+        let registers = (self.v, self.i, self.delay_timer, self.sound_timer);
+
+        let extended = self.mode != Mode::Chip8;
+        let xo_chip = self.mode == Mode::XoChip;
+
         // Decode and Execute Opcode
         match (op_1, op_2, op_3, op_4) {
+            (0x0, 0x0, 0xC, _) if extended => self.scd_n(),
+            (0x0, 0x0, 0xD, _) if xo_chip => self.scu_n(),
             (0x0, 0x0, 0xE, 0x0) => self.cls(),
-            (0x0, 0x0, 0xE, 0xE) => self.ret(),
+            (0x0, 0x0, 0xE, 0xE) => self.ret()?,
+            (0x0, 0x0, 0xF, 0xB) if extended => self.scr(),
+            (0x0, 0x0, 0xF, 0xC) if extended => self.scl(),
+            (0x0, 0x0, 0xF, 0xD) if extended => self.exit(),
+            (0x0, 0x0, 0xF, 0xE) if extended => self.low(),
+            (0x0, 0x0, 0xF, 0xF) if extended => self.high(),
+            (0x0, _, _, _) => self.sys_addr(),
             (0x1, _, _, _) => self.jp_addr(),
-            (0x2, _, _, _) => self.call_addr(),
+            (0x2, _, _, _) => self.call_addr()?,
             (0x3, _, _, _) => self.se_vx_byte(),
             (0x4, _, _, _) => self.sne_vx_byte(),
+            (0x5, _, _, 0x0) => self.se_vx_vy(),
+            (0x5, _, _, 0x2) if xo_chip => self.ld_i_vx_vy()?,
+            (0x5, _, _, 0x3) if xo_chip => self.ld_vx_vy_i()?,
             (0x6, _, _, _) => self.ld_vx_byte(),
             (0x7, _, _, _) => self.add_vx_byte(),
             (0x8, _, _, 0x0) => self.ld_vx_vy(),
@@ -141,224 +779,470 @@ impl VM {
             (0x8, _, _, 0x4) => self.add_vx_vy(),
             (0x8, _, _, 0x5) => self.sub_vx_vy(),
             (0x8, _, _, 0x6) => self.shr_vx_vy(),
+            (0x8, _, _, 0x7) => self.subn_vx_vy(),
             (0x8, _, _, 0xE) => self.shl_vx_vy(),
             (0x9, _, _, 0x0) => self.sne_vx_vy(),
             (0xA, _, _, _) => self.ld_i_addr(),
+            (0xB, _, _, _) => self.jp_v0_addr(),
             (0xC, _, _, _) => self.rnd_vx_byte(),
-            (0xD, _, _, _) => self.drw_vx_vy_n(),
+            (0xD, _, _, _) => self.drw_vx_vy_n()?,
             (0xE, _, 0x9, 0xE) => self.skp_vx(),
             (0xE, _, 0xA, 0x1) => self.sknp_vx(),
+            (0xF, 0x0, 0x0, 0x0) if xo_chip => self.ld_i_long()?,
+            (0xF, _, 0x0, 0x1) if xo_chip => self.plane_n(),
+            (0xF, 0x0, 0x0, 0x2) if xo_chip => self.audio()?,
             (0xF, _, 0x0, 0x7) => self.ld_vx_dt(),
             (0xF, _, 0x0, 0xA) => self.ld_vx_k(),
             (0xF, _, 0x1, 0x5) => self.ld_dt_vx(),
             (0xF, _, 0x1, 0x8) => self.ld_st_vx(),
             (0xF, _, 0x1, 0xE) => self.add_i_vx(),
             (0xF, _, 0x2, 0x9) => self.ld_f_vx(),
-            (0xF, _, 0x3, 0x3) => self.ld_b_vx(),
-            (0xF, _, 0x5, 0x5) => self.ld_i_vx(),
-            (0xF, _, 0x6, 0x5) => self.ld_vx_i(),
-            _ => self.unsupported_opcode(),
+            (0xF, _, 0x3, 0x0) if extended => self.ld_hf_vx(),
+            (0xF, _, 0x3, 0x3) => self.ld_b_vx()?,
+            (0xF, _, 0x3, 0xA) if xo_chip => self.pitch_vx(),
+            (0xF, _, 0x5, 0x5) => self.ld_i_vx()?,
+            (0xF, _, 0x6, 0x5) => self.ld_vx_i()?,
+            (0xF, _, 0x7, 0x5) if extended => self.ld_r_vx(),
+            (0xF, _, 0x8, 0x5) if extended => self.ld_vx_r(),
+            _ => return Err(self.unsupported_opcode()),
         }
 
-        // Update timers
-        if self.delay_timer > 0 {
-            self.delay_timer -= 1;
+        if watching {
+            self.watch_registers(pc, registers);
         }
 
-        if self.sound_timer > 0 {
-            self.sound_timer -= 1;
-
-            if self.sound_timer == 0 {
-                println!("Sound");
-            }
-        }
+        Ok(())
     }
-    fn cls(&mut self) {
-        println!("CLS\n");
 
-        for i in 0..64 * 32 {
-            self.gfx[i] = 0;
+    fn check_address(&self, address: usize) -> Result<(), VmError> {
+        if address >= self.memory_size() {
+            return Err(VmError::MemoryOutOfBounds {
+                address,
+                pc: self.pc,
+            });
         }
 
-        self.draw_flag = true;
-        self.pc += 2;
+        Ok(())
     }
 
-    fn ret(&mut self) {
-        println!("RET\n");
-        self.sp -= 1;
-        self.pc = self.stack[self.sp as usize] + 2;
+    // Instruction fetches don't trigger memory watchpoints
+    fn fetch_word(&self, address: usize) -> Result<u16, VmError> {
+        self.check_address(address)?;
+        self.check_address(address + 1)?;
+
+        Ok(((self.memory[address] as u16) << 8) | self.memory[address + 1] as u16)
     }
 
-    fn jp_addr(&mut self) {
-        let addr = self.opcode & 0x0FFF;
+    fn read_memory(&mut self, address: usize) -> Result<u8, VmError> {
+        self.check_address(address)?;
 
-        println!("JP {:X}\n", addr);
+        if !self.watchpoints.is_empty() {
+            self.watch_memory(address, Access::Read(address));
+        }
 
-        self.pc = addr;
+        Ok(self.memory[address])
     }
 
-    fn call_addr(&mut self) {
-        let subroutine_address = self.opcode & 0xFFF;
+    fn write_memory(&mut self, address: usize, value: u8) -> Result<(), VmError> {
+        self.check_address(address)?;
 
-        println!("CALL {:X}\n", subroutine_address);
+        if !self.watchpoints.is_empty() {
+            self.watch_memory(address, Access::Write(address));
+        }
 
-        self.stack[self.sp as usize] = self.pc;
-        self.sp += 1;
-        self.pc = subroutine_address;
+        self.memory[address] = value;
+        Ok(())
     }
 
-    fn se_vx_byte(&mut self) {
-        let x = (self.opcode & 0x0F00) >> 8;
-        let byte = (self.opcode & 0x00FF) as u8;
-
-        println!("SE V{}, {:X}\n", x, byte);
+    fn watch_memory(&mut self, address: usize, access: Access) {
+        let watchpoint = self
+            .watchpoints
+            .iter()
+            .find(|watchpoint| watchpoint.contains(address))
+            .cloned();
 
-        if self.v[x as usize] == byte {
-            self.pc += 4;
-        } else {
-            self.pc += 2;
+        if let Some(watchpoint) = watchpoint {
+            self.record_watch_hit(watchpoint, access, self.pc);
         }
     }
 
-    fn sne_vx_byte(&mut self) {
-        let x = (self.opcode & 0x0F00) >> 8;
-        let byte = (self.opcode & 0x00FF) as u8;
-
-        println!("SNE V{}, {:X}\n", x, byte);
-
-        if self.v[x as usize] != byte {
-            self.pc += 4;
-        } else {
-            self.pc += 2;
+    fn watch_registers(
+        &mut self,
+        pc: u16,
+        (v, i, delay_timer, sound_timer): ([u8; 16], u16, u8, u8),
+    ) {
+        for index in 0..self.watchpoints.len() {
+            let watchpoint = self.watchpoints[index];
+            let (old, new) = match watchpoint {
+                Watchpoint::Register(x) => (v[x] as u16, self.v[x] as u16),
+                Watchpoint::I => (i, self.i),
+                Watchpoint::DelayTimer => (delay_timer as u16, self.delay_timer as u16),
+                Watchpoint::SoundTimer => (sound_timer as u16, self.sound_timer as u16),
+                Watchpoint::Memory { .. } => continue,
+            };
+
+            if old != new {
+                self.record_watch_hit(watchpoint, Access::Change(old, new), pc);
+            }
         }
     }
 
-    fn ld_vx_byte(&mut self) {
-        let x = (self.opcode & 0x0F00) >> 8;
-        let value = (self.opcode & 0x00FF) as u8;
+    // Only the first hit of an instruction is kept
+    fn record_watch_hit(&mut self, watchpoint: Watchpoint, access: Access, pc: u16) {
+        if self.watch_hit.is_none() {
+            self.watch_hit = Some(WatchHit {
+                watchpoint,
+                access,
+                opcode: self.opcode,
+                pc,
+            });
+        }
+    }
 
-        println!("LD V{}, {:X}\n", x, value);
+    fn cls(&mut self) {
+        for pixel in self.gfx.iter_mut() {
+            *pixel &= !self.plane;
+        }
 
-        self.v[x as usize] = value;
-        self.pc += 2;
+        self.draw_flag = true;
+        self.pc = self.pc.wrapping_add(2);
     }
 
-    fn ld_f_vx(&mut self) {
-        let x = (self.opcode & 0x0F00) >> 8;
+    fn scd_n(&mut self) {
+        let n = (self.opcode & 0x000F) as isize;
 
-        println!("LD F, V{}\n", x);
-
-        self.i = (self.v[x as usize] * 0x5) as u16;
-        self.pc += 2;
+        self.scroll(0, n);
     }
 
-    fn ld_b_vx(&mut self) {
-        let vx = (self.opcode & 0x0F00) >> 8;
+    fn scu_n(&mut self) {
+        let n = (self.opcode & 0x000F) as isize;
 
-        println!("LD B, V{}\n", vx);
+        self.scroll(0, -n);
+    }
 
-        self.memory[self.i as usize] = self.v[vx as usize] / 100;
-        self.memory[(self.i + 1) as usize] = (self.v[vx as usize] / 10) % 10;
-        self.memory[(self.i + 2) as usize] = (self.v[vx as usize] % 100) % 10;
-        self.pc += 2;
+    fn scr(&mut self) {
+        self.scroll(4, 0);
     }
 
-    fn ld_i_vx(&mut self) {
-        let x = (self.opcode & 0x0F00) >> 8;
+    fn scl(&mut self) {
+        self.scroll(-4, 0);
+    }
 
-        println!("LD [I], V{}\n", x);
+    // Moves the selected planes by (dx, dy) pixels, filling the uncovered area with blank pixels
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let (width, height) = (self.width() as isize, self.height() as isize);
+        let previous = self.gfx;
 
-        for v in 0..x + 1 {
-            self.memory[(self.i + v) as usize] = self.v[v as usize]
+        for y in 0..height {
+            for x in 0..width {
+                let (source_x, source_y) = (x - dx, y - dy);
+                let source =
+                    if source_x >= 0 && source_x < width && source_y >= 0 && source_y < height {
+                        previous[(source_y * width + source_x) as usize]
+                    } else {
+                        0
+                    };
+                let pixel = &mut self.gfx[(y * width + x) as usize];
+
+                *pixel = (*pixel & !self.plane) | (source & self.plane);
+            }
         }
 
-        self.pc += 2;
+        self.draw_flag = true;
+        self.pc = self.pc.wrapping_add(2);
     }
 
-    fn ld_vx_i(&mut self) {
-        let vx = (self.opcode & 0x0F00) >> 8;
+    fn exit(&mut self) {
+        self.halted = true;
+    }
 
-        println!("LD V{}, [I]\n", vx);
+    fn low(&mut self) {
+        self.hires = false;
+        self.cls();
+    }
 
-        for v in 0..vx + 1 {
-            self.v[v as usize] = self.memory[(self.i + v) as usize];
+    fn high(&mut self) {
+        self.hires = true;
+        self.cls();
+    }
+
+    fn plane_n(&mut self) {
+        let n = ((self.opcode & 0x0F00) >> 8) as u8;
+
+        self.plane = n & 0x3;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn audio(&mut self) -> Result<(), VmError> {
+        for offset in 0..16 {
+            self.audio_pattern[offset] = self.read_memory(self.i as usize + offset)?;
         }
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
     }
 
-    fn add_vx_byte(&mut self) {
+    fn pitch_vx(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
-        let byte = (self.opcode & 0x00FF) as u16;
-        let sum = self.v[x] as u16 + byte;
-
-        println!("ADD V{}, {:X}\n", x, byte);
 
-        self.v[x] = sum as u8;
-        self.pc += 2;
+        self.pitch = self.v[x];
+        self.pc = self.pc.wrapping_add(2);
     }
 
-    fn ld_i_addr(&mut self) {
-        let value = self.opcode & 0x0FFF;
+    fn ld_i_long(&mut self) -> Result<(), VmError> {
+        let address = self.fetch_word(self.pc as usize + 2)?;
 
-        println!("LD I, {:X}\n", value);
-        self.i = value;
-        self.pc += 2;
+        self.i = address;
+        self.pc = self.pc.wrapping_add(4);
+        Ok(())
     }
 
-    fn rnd_vx_byte(&mut self) {
+    fn ld_i_vx_vy(&mut self) -> Result<(), VmError> {
+        let x = ((self.opcode & 0x0F00) >> 8) as usize;
+        let y = ((self.opcode & 0x00F0) >> 4) as usize;
+
+        for (offset, &v) in register_range(x, y).iter().enumerate() {
+            self.write_memory(self.i as usize + offset, self.v[v])?;
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn ld_vx_vy_i(&mut self) -> Result<(), VmError> {
+        let x = ((self.opcode & 0x0F00) >> 8) as usize;
+        let y = ((self.opcode & 0x00F0) >> 4) as usize;
+
+        for (offset, &v) in register_range(x, y).iter().enumerate() {
+            self.v[v] = self.read_memory(self.i as usize + offset)?;
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    // Skips the next instruction, which is 4 bytes long when it is an XO-CHIP long I load
+    fn skip_next_instruction(&mut self) {
+        let next = self.pc as usize + 2;
+        let long_load = self.mode == Mode::XoChip
+            && next + 1 < self.memory_size()
+            && self.memory[next] == 0xF0
+            && self.memory[next + 1] == 0x00;
+
+        self.pc = self.pc.wrapping_add(if long_load { 6 } else { 4 });
+    }
+
+    fn ret(&mut self) -> Result<(), VmError> {
+        if self.sp == 0 {
+            return Err(VmError::StackUnderflow { pc: self.pc });
+        }
+
+        self.sp -= 1;
+        self.pc = self.stack[self.sp as usize].wrapping_add(2);
+        Ok(())
+    }
+
+    fn sys_addr(&mut self) {
+        // Machine code routines only existed on the original hardware, ignore them
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn jp_addr(&mut self) {
+        let addr = self.opcode & 0x0FFF;
+
+        self.pc = addr;
+    }
+
+    fn call_addr(&mut self) -> Result<(), VmError> {
+        let subroutine_address = self.opcode & 0xFFF;
+
+        if self.sp as usize >= self.stack.len() {
+            return Err(VmError::StackOverflow { pc: self.pc });
+        }
+
+        self.stack[self.sp as usize] = self.pc;
+        self.sp += 1;
+        self.pc = subroutine_address;
+        Ok(())
+    }
+
+    fn se_vx_byte(&mut self) {
+        let x = (self.opcode & 0x0F00) >> 8;
+        let byte = (self.opcode & 0x00FF) as u8;
+
+        if self.v[x as usize] == byte {
+            self.skip_next_instruction();
+        } else {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn sne_vx_byte(&mut self) {
         let x = (self.opcode & 0x0F00) >> 8;
         let byte = (self.opcode & 0x00FF) as u8;
-        let random_byte: u8;
 
-        if cfg!(target_arch = "wasm32") {
-            extern crate wbg_rand;
-            use wbg_rand::{wasm_rng, Rng};
+        if self.v[x as usize] != byte {
+            self.skip_next_instruction();
+        } else {
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn se_vx_vy(&mut self) {
+        let x = ((self.opcode & 0x0F00) >> 8) as usize;
+        let y = ((self.opcode & 0x00F0) >> 4) as usize;
 
-            random_byte = wasm_rng().gen();
+        if self.v[x] == self.v[y] {
+            self.skip_next_instruction();
         } else {
-            extern crate rand;
-            use rand::Rng;
+            self.pc = self.pc.wrapping_add(2);
+        }
+    }
+
+    fn ld_vx_byte(&mut self) {
+        let x = (self.opcode & 0x0F00) >> 8;
+        let value = (self.opcode & 0x00FF) as u8;
+
+        self.v[x as usize] = value;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn ld_f_vx(&mut self) {
+        let x = (self.opcode & 0x0F00) >> 8;
+
+        self.i = (self.v[x as usize] as u16) * 0x5;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn ld_hf_vx(&mut self) {
+        let x = (self.opcode & 0x0F00) >> 8;
+
+        self.i = (BIG_FONT_ADDRESS + (self.v[x as usize] & 0xF) as usize * 10) as u16;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn ld_b_vx(&mut self) -> Result<(), VmError> {
+        let vx = (self.opcode & 0x0F00) >> 8;
+        let i = self.i as usize;
+
+        self.write_memory(i, self.v[vx as usize] / 100)?;
+        self.write_memory(i + 1, (self.v[vx as usize] / 10) % 10)?;
+        self.write_memory(i + 2, (self.v[vx as usize] % 100) % 10)?;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn ld_i_vx(&mut self) -> Result<(), VmError> {
+        let x = (self.opcode & 0x0F00) >> 8;
+
+        for v in 0..x + 1 {
+            self.write_memory(self.i as usize + v as usize, self.v[v as usize])?;
+        }
+
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(x + 1);
+        } else if self.quirks.load_store_adds_x_to_i {
+            self.i = self.i.wrapping_add(x);
+        }
+
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
 
-            random_byte = rand::thread_rng().gen();
+    fn ld_vx_i(&mut self) -> Result<(), VmError> {
+        let vx = (self.opcode & 0x0F00) >> 8;
+
+        for v in 0..vx + 1 {
+            self.v[v as usize] = self.read_memory(self.i as usize + v as usize)?;
+        }
+
+        if self.quirks.load_store_increments_i {
+            self.i = self.i.wrapping_add(vx + 1);
+        } else if self.quirks.load_store_adds_x_to_i {
+            self.i = self.i.wrapping_add(vx);
         }
 
-        println!("RND V{}, {:X}\n", x, byte);
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
+    }
+
+    fn ld_r_vx(&mut self) {
+        let x = ((self.opcode & 0x0F00) >> 8) as usize;
+
+        self.rpl[..=x].copy_from_slice(&self.v[..=x]);
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn ld_vx_r(&mut self) {
+        let x = ((self.opcode & 0x0F00) >> 8) as usize;
+
+        self.v[..=x].copy_from_slice(&self.rpl[..=x]);
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn add_vx_byte(&mut self) {
+        let x = ((self.opcode & 0x0F00) >> 8) as usize;
+        let byte = (self.opcode & 0x00FF) as u16;
+        let sum = self.v[x] as u16 + byte;
+
+        self.v[x] = sum as u8;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn ld_i_addr(&mut self) {
+        let value = self.opcode & 0x0FFF;
+
+        self.i = value;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn jp_v0_addr(&mut self) {
+        let addr = self.opcode & 0x0FFF;
+
+        // CHIP-48 and SUPER-CHIP read the register from the highest nibble of the address
+        let x = if self.quirks.jump_uses_vx {
+            ((self.opcode & 0x0F00) >> 8) as usize
+        } else {
+            0x0
+        };
+
+        self.pc = addr + self.v[x] as u16;
+    }
+
+    fn rnd_vx_byte(&mut self) {
+        let x = (self.opcode & 0x0F00) >> 8;
+        let byte = (self.opcode & 0x00FF) as u8;
+        let random_byte = self.rng.next_byte();
 
         self.v[x as usize] = random_byte & byte;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn and_vx_vy(&mut self) {
         let x = (self.opcode & 0x0F00) >> 8;
         let y = (self.opcode & 0x00F0) >> 4;
 
-        println!("AND V{}, V{}\n", x, y);
-
         self.v[x as usize] &= self.v[y as usize];
-        self.pc += 2;
+        self.reset_vf_after_logic();
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn xor_vx_vy(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
         let y = ((self.opcode & 0x00F0) >> 4) as usize;
 
-        println!("XOR V{}, V{}\n", x, y);
-
         self.v[x] ^= self.v[y];
-        self.pc += 2;
+        self.reset_vf_after_logic();
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn ld_vx_vy(&mut self) {
         let x = (self.opcode & 0x0F00) >> 8;
         let y = (self.opcode & 0x00F0) >> 4;
 
-        println!("LD V{}, V{}", x, y);
-
         self.v[x as usize] = self.v[y as usize];
 
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn or_vx_vy(&mut self) {
@@ -366,7 +1250,14 @@ impl VM {
         let y = ((self.opcode & 0x00F0) >> 4) as usize;
 
         self.v[x] |= self.v[y];
-        self.pc += 2;
+        self.reset_vf_after_logic();
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn reset_vf_after_logic(&mut self) {
+        if self.quirks.logic_resets_vf {
+            self.v[0xF] = 0;
+        }
     }
 
     fn add_vx_vy(&mut self) {
@@ -375,8 +1266,6 @@ impl VM {
 
         let sum = (self.v[x] as u16) + (self.v[y] as u16);
 
-        println!("ADD V{}, V{}\n", x, y);
-
         if sum > 0xFF {
             self.v[0xF] = 1;
         } else {
@@ -384,15 +1273,13 @@ impl VM {
         }
 
         self.v[x] = sum as u8;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn sub_vx_vy(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
         let y = ((self.opcode & 0x00F0) >> 4) as usize;
 
-        println!("SUB V{}, V{}\n", x, y);
-
         if self.v[x] > self.v[y] {
             self.v[0xF] = 1;
         } else {
@@ -400,159 +1287,216 @@ impl VM {
         }
 
         self.v[x] = self.v[x].wrapping_sub(self.v[y]);
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+    }
+
+    fn subn_vx_vy(&mut self) {
+        let x = ((self.opcode & 0x0F00) >> 8) as usize;
+        let y = ((self.opcode & 0x00F0) >> 4) as usize;
+
+        if self.v[y] > self.v[x] {
+            self.v[0xF] = 1;
+        } else {
+            self.v[0xF] = 0;
+        }
+
+        self.v[x] = self.v[y].wrapping_sub(self.v[x]);
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn shr_vx_vy(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
 
-        println!("SHR V{}\n", x);
+        let y = ((self.opcode & 0x00F0) >> 4) as usize;
+
+        if self.quirks.shift_uses_vy {
+            self.v[x] = self.v[y];
+        }
 
         self.v[0xF] = self.v[x] & 0x01;
         self.v[x] >>= 1;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn shl_vx_vy(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
 
-        println!("SHL V{}", x);
+        let y = ((self.opcode & 0x00F0) >> 4) as usize;
+
+        if self.quirks.shift_uses_vy {
+            self.v[x] = self.v[y];
+        }
 
         self.v[0xF] = (self.v[x] & 0x80) >> 7;
         self.v[x] = (self.v[x] << 1) as u8;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn sne_vx_vy(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
         let y = ((self.opcode & 0x00F0) >> 4) as usize;
 
-        println!("SNE V{}, V{}", x, y);
-
         if self.v[x] != self.v[y] {
-            self.pc += 4
+            self.skip_next_instruction();
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
     }
 
-    fn drw_vx_vy_n(&mut self) {
-        let vx = self.v[((self.opcode & 0x0F00) >> 8) as usize];
-        let vy = self.v[((self.opcode & 0x00F0) >> 4) as usize];
-        let rows = self.opcode & 0x000F;
+    fn drw_vx_vy_n(&mut self) -> Result<(), VmError> {
+        let (width, height) = (self.width(), self.height());
+        let vx = self.v[((self.opcode & 0x0F00) >> 8) as usize] as usize % width;
+        let vy = self.v[((self.opcode & 0x00F0) >> 4) as usize] as usize % height;
+        let n = (self.opcode & 0x000F) as usize;
 
-        println!("DRW V{}, V{}, {}\n", vx, vy, rows);
+        // SUPER-CHIP draws a 16x16 sprite (2 bytes per row) when N is zero
+        let (sprite_width, rows) = if n == 0 && self.mode != Mode::Chip8 {
+            (16, 16)
+        } else {
+            (8, n)
+        };
 
         self.v[0xF] = 0; // Reset register VF
 
-        for y in 0..rows {
-            let pixel = self.memory[(self.i + y) as usize];
+        let sprite_bytes = rows * sprite_width / 8;
+        let mut address = self.i as usize;
+
+        // XO-CHIP draws one sprite per selected plane, stored one after the other
+        let selected = self.plane;
 
-            for x in 0..8 {
-                if (pixel & (0x80 >> x)) != 0 {
-                    let current_position =
-                        ((vx as u16 + x as u16) + ((vy as u16 + y) * 64)) % (32 * 64);
+        for &plane in [0x1, 0x2].iter().filter(|&&plane| selected & plane != 0) {
+            for y in 0..rows {
+                for x in 0..sprite_width {
+                    let pixel = self.read_memory(address + y * sprite_width / 8 + x / 8)?;
 
-                    if self.gfx[current_position as usize] == 1 {
-                        self.v[0xF] = 1; // Collision detected, set register VF
+                    let (px, py) = (vx + x, vy + y);
+
+                    if self.quirks.clip_sprites && (px >= width || py >= height) {
+                        continue;
                     }
 
-                    self.gfx[current_position as usize] ^= 1; // Set pixel value using XOR
+                    if (pixel & (0x80 >> (x % 8))) != 0 {
+                        let current_position = (py % height) * width + px % width;
+
+                        if self.gfx[current_position] & plane != 0 {
+                            self.v[0xF] = 1; // Collision detected, set register VF
+                        }
+
+                        self.gfx[current_position] ^= plane; // Set pixel value using XOR
+                    }
                 }
             }
+
+            address += sprite_bytes;
         }
 
         self.draw_flag = true;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
+        Ok(())
     }
 
     fn skp_vx(&mut self) {
         let x = (self.opcode & 0x0F00) >> 8;
 
-        println!("SKNP V{}\n", x);
-
-        if self.key[self.v[x as usize] as usize] == true {
-            self.pc += 4;
+        if self.key[(self.v[x as usize] & 0xF) as usize] {
+            self.skip_next_instruction();
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
     }
 
     fn sknp_vx(&mut self) {
         let x = (self.opcode & 0x0F00) >> 8;
 
-        println!("SKNP V{}\n", x);
-
-        if self.key[self.v[x as usize] as usize] == false {
-            self.pc += 4;
+        if !self.key[(self.v[x as usize] & 0xF) as usize] {
+            self.skip_next_instruction();
         } else {
-            self.pc += 2;
+            self.pc = self.pc.wrapping_add(2);
         }
     }
 
     fn ld_vx_dt(&mut self) {
         let x = (self.opcode & 0x0F00) >> 8;
 
-        println!("LD V{}, DT\n", x);
-
         self.v[x as usize] = self.delay_timer;
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
+    // Runs again until a key goes down and back up, a key held since before is ignored
+    // until it is released
     fn ld_vx_k(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
 
-        println!("LD V{}, K\n", x);
+        self.key_wait = match self.key_wait {
+            None => Some(KeyWait::Press(self.key)),
+            Some(KeyWait::Press(mut held)) => {
+                let pressed = (0..16).find(|&i| self.key[i] && !held[i]);
 
-        for i in 0..self.key.len() {
-            if self.key[i] == true {
-                self.v[x] = i as u8;
-                self.pc += 2;
-                break;
+                for (held, &down) in held.iter_mut().zip(self.key.iter()) {
+                    *held &= down;
+                }
+
+                match pressed {
+                    Some(key) => Some(KeyWait::Release(key as u8)),
+                    None => Some(KeyWait::Press(held)),
+                }
             }
-        }
+            Some(KeyWait::Release(key)) if !self.key[key as usize] => {
+                self.v[x] = key;
+                self.pc = self.pc.wrapping_add(2);
+                None
+            }
+            Some(KeyWait::Release(key)) => Some(KeyWait::Release(key)),
+        };
     }
 
     fn ld_dt_vx(&mut self) {
         let x = (self.opcode & 0x0F00) >> 8;
 
-        println!("LD DT, V{}\n", x);
-
         self.delay_timer = self.v[x as usize];
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn ld_st_vx(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
 
-        println!("LD ST, V{}\n", x);
-
         self.sound_timer = self.v[x];
-        self.pc += 2;
+        self.pc = self.pc.wrapping_add(2);
     }
 
     fn add_i_vx(&mut self) {
         let x = ((self.opcode & 0x0F00) >> 8) as usize;
 
-        println!("ADD I, V{}", x);
-
-        self.i = self.i + (self.v[x] as u16);
-        self.pc += 2;
+        self.i = self.i.wrapping_add(self.v[x] as u16);
+        self.pc = self.pc.wrapping_add(2);
     }
 
-    fn unsupported_opcode(&self) {
+    fn unsupported_opcode(&self) -> VmError {
         if self.debug {
             self.debug_memory();
             self.debug_registers();
         }
 
-        panic!("Opcode not handled: 0x{:04X}", self.opcode);
+        VmError::UnknownOpcode {
+            opcode: self.opcode,
+            pc: self.pc,
+        }
+    }
+}
+
+// Registers from Vx to Vy included, in descending order when X is greater than Y
+fn register_range(x: usize, y: usize) -> Vec<usize> {
+    if x <= y {
+        (x..=y).collect()
+    } else {
+        (y..=x).rev().collect()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::random::Sequence;
 
     #[test]
     fn cls() {
@@ -577,12 +1521,31 @@ mod tests {
         let mut vm = VM::initialize(false);
         vm.stack[0] = 0x2E;
         vm.sp = 1;
-        vm.ret();
+        vm.ret().unwrap();
 
         assert_eq!(vm.sp, 0);
         assert_eq!(vm.pc, 0x2E + 2);
     }
 
+    #[test]
+    fn ret_empty_stack() {
+        let mut vm = VM::initialize(false);
+
+        match vm.ret() {
+            Err(VmError::StackUnderflow { pc }) => assert_eq!(pc, 0x200),
+            _ => panic!("expected a stack underflow"),
+        }
+    }
+
+    #[test]
+    fn sys_addr() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0x0123;
+        vm.sys_addr();
+
+        assert_eq!(vm.pc, 0x202);
+    }
+
     #[test]
     fn jp_addr() {
         let mut vm = VM::initialize(false);
@@ -597,13 +1560,25 @@ mod tests {
         let mut vm = VM::initialize(false);
         vm.pc = 0x444;
         vm.opcode = 0x2123;
-        vm.call_addr();
+        vm.call_addr().unwrap();
 
         assert_eq!(vm.stack[0], 0x444);
         assert_eq!(vm.sp, 1);
         assert_eq!(vm.pc, 0x123);
     }
 
+    #[test]
+    fn call_addr_full_stack() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0x2123;
+        vm.sp = 16;
+
+        match vm.call_addr() {
+            Err(VmError::StackOverflow { pc }) => assert_eq!(pc, 0x200),
+            _ => panic!("expected a stack overflow"),
+        }
+    }
+
     #[test]
     fn se_vx_byte_equals() {
         let mut vm = VM::initialize(false);
@@ -644,6 +1619,28 @@ mod tests {
         assert_eq!(vm.pc, 0x204);
     }
 
+    #[test]
+    fn se_vx_vy_equals() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0x5AB0;
+        vm.v[0xA] = 0x1;
+        vm.v[0xB] = 0x1;
+        vm.se_vx_vy();
+
+        assert_eq!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn se_vx_vy_not_equals() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0x5AB0;
+        vm.v[0xA] = 0x1;
+        vm.v[0xB] = 0x2;
+        vm.se_vx_vy();
+
+        assert_eq!(vm.pc, 0x202);
+    }
+
     #[test]
     fn ld_vx_byte() {
         let mut vm = VM::initialize(false);
@@ -764,6 +1761,32 @@ mod tests {
         assert_eq!(vm.pc, 0x202);
     }
 
+    #[test]
+    fn subn_vx_vy_no_borrow() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0x8AB7;
+        vm.v[0xA] = 0xAA;
+        vm.v[0xB] = 0xFF;
+        vm.subn_vx_vy();
+
+        assert_eq!(vm.v[0xA], 0x55);
+        assert_eq!(vm.v[0xF], 1);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn subn_vx_vy_borrow() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0x8AB7;
+        vm.v[0xA] = 0xFF;
+        vm.v[0xB] = 0xAA;
+        vm.subn_vx_vy();
+
+        assert_eq!(vm.v[0xA], 0xAB);
+        assert_eq!(vm.v[0xF], 0);
+        assert_eq!(vm.pc, 0x202);
+    }
+
     #[test]
     fn shr_vx_vy() {
         let mut vm = VM::initialize(false);
@@ -800,16 +1823,47 @@ mod tests {
         assert_eq!(vm.pc, 0x202);
     }
 
+    #[test]
+    fn jp_v0_addr() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0xB300;
+        vm.v[0x0] = 0x2A;
+        vm.jp_v0_addr();
+
+        assert_eq!(vm.pc, 0x32A);
+    }
+
     #[test]
     fn rnd_vx_byte() {
         let mut vm = VM::initialize(false);
+        vm.set_random_source(Box::new(Sequence::new(&[0xFF, 0x5A])));
         vm.opcode = 0xCA23;
-        vm.v[0xA] = 0x0;
         vm.rnd_vx_byte();
 
-        assert_ne!(vm.v[0xA], 0x0);
-        assert_ne!(vm.v[0xA], 0x23);
+        assert_eq!(vm.v[0xA], 0x23);
         assert_eq!(vm.pc, 0x202);
+
+        vm.rnd_vx_byte();
+
+        assert_eq!(vm.v[0xA], 0x02);
+    }
+
+    #[test]
+    fn rnd_vx_byte_seeded() {
+        let values = |seed| {
+            let mut vm = VM::with_seed(Mode::Chip8, seed, false);
+            vm.opcode = 0xCAFF;
+
+            (0..8)
+                .map(|_| {
+                    vm.rnd_vx_byte();
+                    vm.v[0xA]
+                })
+                .collect::<Vec<u8>>()
+        };
+
+        assert_eq!(values(7), values(7));
+        assert_ne!(values(7), values(8));
     }
 
     #[test]
@@ -844,7 +1898,7 @@ mod tests {
         vm.memory[0x200] = 0x3C;
         vm.memory[0x201] = 0xC3;
         vm.memory[0x202] = 0xFF;
-        vm.drw_vx_vy_n();
+        vm.drw_vx_vy_n().unwrap();
 
         assert_eq!(vm.v[0xF], 0);
         assert_eq!(&vm.gfx[0..8], [0, 0, 1, 1, 1, 1, 0, 0]);
@@ -864,7 +1918,7 @@ mod tests {
         vm.memory[0x201] = 0xC3;
         vm.memory[0x202] = 0xFF;
         vm.gfx[128] = 1;
-        vm.drw_vx_vy_n();
+        vm.drw_vx_vy_n().unwrap();
 
         assert_eq!(vm.v[0xF], 1);
         assert_eq!(&vm.gfx[0..8], [0, 0, 1, 1, 1, 1, 0, 0]);
@@ -932,21 +1986,81 @@ mod tests {
     fn ld_vx_k() {
         let mut vm = VM::initialize(false);
         vm.opcode = 0xFA0A;
+        vm.ld_vx_k();
+
+        assert!(vm.waiting_for_key());
+        assert_eq!(vm.pc, 0x200);
+
         vm.key[0xB] = true;
         vm.ld_vx_k();
 
+        assert!(vm.waiting_for_key());
+        assert_eq!(vm.pc, 0x200);
+
+        vm.key[0xB] = false;
+        vm.ld_vx_k();
+
+        assert!(!vm.waiting_for_key());
         assert_eq!(vm.v[0xA], 0xB);
         assert_eq!(vm.pc, 0x202);
     }
 
     #[test]
-    fn ld_dt_vx() {
+    fn ld_vx_k_ignores_held_key() {
         let mut vm = VM::initialize(false);
-        vm.opcode = 0xFA15;
-        vm.v[0xA] = 0x7;
-        vm.ld_dt_vx();
-
-        assert_eq!(vm.delay_timer, 0x7);
+        vm.opcode = 0xFA0A;
+        vm.key[0x3] = true;
+        vm.ld_vx_k();
+        vm.ld_vx_k();
+        vm.key[0x3] = false;
+        vm.ld_vx_k();
+
+        assert_eq!(vm.pc, 0x200);
+
+        vm.key[0x3] = true;
+        vm.ld_vx_k();
+        vm.key[0x3] = false;
+        vm.ld_vx_k();
+
+        assert_eq!(vm.v[0xA], 0x3);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn run_frame_idles_while_waiting_for_key() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0xF0; // LD V0, K
+        vm.memory[0x201] = 0x0A;
+        vm.delay_timer = 0x5;
+        let mut cycles = 0;
+        vm.run_frame_until(|_| {
+            cycles += 1;
+            false
+        })
+        .unwrap();
+
+        assert_eq!(cycles, 1);
+        assert!(vm.waiting_for_key());
+        assert_eq!(vm.delay_timer, 0x4);
+
+        vm.key[0x7] = true;
+        vm.run_frame().unwrap();
+        vm.key[0x7] = false;
+        vm.run_frame().unwrap();
+
+        assert!(!vm.waiting_for_key());
+        assert_eq!(vm.v[0x0], 0x7);
+        assert_eq!(vm.delay_timer, 0x2);
+    }
+
+    #[test]
+    fn ld_dt_vx() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0xFA15;
+        vm.v[0xA] = 0x7;
+        vm.ld_dt_vx();
+
+        assert_eq!(vm.delay_timer, 0x7);
         assert_eq!(vm.pc, 0x202);
     }
 
@@ -989,7 +2103,7 @@ mod tests {
         vm.opcode = 0xFA33;
         vm.v[0xA] = 0x7B;
         vm.i = 0x400;
-        vm.ld_b_vx();
+        vm.ld_b_vx().unwrap();
 
         assert_eq!(vm.memory[0x400], 0x1);
         assert_eq!(vm.memory[0x401], 0x2);
@@ -1006,7 +2120,7 @@ mod tests {
         vm.v[0x2] = 0x3;
         vm.v[0x3] = 0x4;
         vm.i = 0x400;
-        vm.ld_i_vx();
+        vm.ld_i_vx().unwrap();
 
         assert_eq!(vm.memory[0x400], 0x1);
         assert_eq!(vm.memory[0x401], 0x2);
@@ -1015,6 +2129,18 @@ mod tests {
         assert_eq!(vm.pc, 0x202);
     }
 
+    #[test]
+    fn ld_i_vx_out_of_bounds() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0xF355;
+        vm.i = 0xFFE;
+
+        match vm.ld_i_vx() {
+            Err(VmError::MemoryOutOfBounds { address, .. }) => assert_eq!(address, 0x1000),
+            _ => panic!("expected an out of bounds access"),
+        }
+    }
+
     #[test]
     fn ld_vx_i() {
         let mut vm = VM::initialize(false);
@@ -1024,7 +2150,7 @@ mod tests {
         vm.memory[0x402] = 0x3;
         vm.memory[0x403] = 0x4;
         vm.i = 0x400;
-        vm.ld_vx_i();
+        vm.ld_vx_i().unwrap();
 
         assert_eq!(vm.v[0x0], 0x1);
         assert_eq!(vm.v[0x1], 0x2);
@@ -1032,4 +2158,803 @@ mod tests {
         assert_eq!(vm.v[0x3], 0x4);
         assert_eq!(vm.pc, 0x202);
     }
+
+    #[test]
+    fn emulate_cycle_unknown_opcode() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0xFF;
+        vm.memory[0x201] = 0xFF;
+
+        match vm.emulate_cycle() {
+            Err(VmError::UnknownOpcode { opcode, pc }) => {
+                assert_eq!(opcode, 0xFFFF);
+                assert_eq!(pc, 0x200);
+            }
+            _ => panic!("expected an unknown opcode"),
+        }
+    }
+
+    #[test]
+    fn load_rom_too_large() {
+        let mut vm = VM::initialize(false);
+
+        match vm.load_rom(&[0; 4096]) {
+            Err(VmError::RomTooLarge { size, max }) => {
+                assert_eq!(size, 4096);
+                assert_eq!(max, 4096 - 512);
+            }
+            _ => panic!("expected a ROM too large error"),
+        }
+    }
+
+    #[test]
+    fn emulate_cycle_keeps_timers() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0x60;
+        vm.memory[0x201] = 0x01;
+        vm.delay_timer = 0x7;
+        vm.sound_timer = 0x7;
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(vm.delay_timer, 0x7);
+        assert_eq!(vm.sound_timer, 0x7);
+    }
+
+    #[test]
+    fn set_key_ignores_unknown_keys() {
+        let mut vm = VM::initialize(false);
+        vm.set_key(0xF, true);
+        vm.set_key(16, true);
+        vm.set_key(usize::MAX, true);
+
+        assert!(vm.key[0xF]);
+        assert_eq!(vm.key.iter().filter(|&&pressed| pressed).count(), 1);
+    }
+
+    #[test]
+    fn tick_timers() {
+        let mut vm = VM::initialize(false);
+        vm.delay_timer = 0x7;
+        vm.sound_timer = 0x1;
+        vm.tick_timers();
+        vm.tick_timers();
+
+        assert_eq!(vm.delay_timer, 0x5);
+        assert_eq!(vm.sound_timer, 0x0);
+    }
+
+    #[test]
+    fn sound_active_while_timer_runs() {
+        let mut vm = VM::initialize(false);
+
+        assert!(!vm.sound_active());
+
+        vm.sound_timer = 0x2;
+        vm.tick_timers();
+
+        assert!(vm.sound_active());
+
+        vm.tick_timers();
+
+        assert!(!vm.sound_active());
+    }
+
+    #[test]
+    fn register_lines() {
+        let mut vm = VM::initialize(false);
+        vm.v[0xA] = 0x3C;
+        vm.i = 0x2F0;
+        vm.stack[0] = 0x204;
+
+        let lines = vm.register_lines();
+
+        assert_eq!(lines.len(), 19);
+        assert_eq!(lines[0xA], "VA: 3C");
+        assert_eq!(&lines[16..], ["I: 2F0", "pc: 0x200", "s[sp]: 0x204"]);
+    }
+
+    #[test]
+    fn run_frame() {
+        let mut vm = VM::initialize(false);
+        vm.set_clock_speed(600);
+        vm.delay_timer = 0x7;
+
+        for i in (0x200..0x400).step_by(2) {
+            vm.memory[i] = 0x70; // ADD V0, 1
+            vm.memory[i + 1] = 0x01;
+        }
+
+        vm.run_frame().unwrap();
+
+        assert_eq!(vm.v[0x0], 10);
+        assert_eq!(vm.pc, 0x200 + 10 * 2);
+        assert_eq!(vm.delay_timer, 0x6);
+    }
+
+    #[test]
+    fn run_frame_fractional_clock_speed() {
+        let mut vm = VM::initialize(false);
+        vm.set_clock_speed(90);
+
+        for i in (0x200..0x400).step_by(2) {
+            vm.memory[i] = 0x70; // ADD V0, 1
+            vm.memory[i + 1] = 0x01;
+        }
+
+        vm.run_frame().unwrap();
+        assert_eq!(vm.v[0x0], 1);
+
+        vm.run_frame().unwrap();
+        assert_eq!(vm.v[0x0], 3);
+    }
+
+    #[test]
+    fn run_frame_huge_clock_speed() {
+        let mut vm = VM::initialize(false);
+        vm.set_clock_speed(u32::MAX);
+        vm.cycle_budget = TIMER_FREQUENCY;
+        let mut cycles = 0;
+
+        let stopped = vm
+            .run_frame_until(|_| {
+                cycles += 1;
+                cycles > 3
+            })
+            .unwrap();
+
+        assert!(stopped);
+    }
+
+    #[test]
+    fn high_low() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.gfx[0] = 1;
+        vm.high();
+
+        assert_eq!(vm.width(), 128);
+        assert_eq!(vm.height(), 64);
+        assert_eq!(vm.gfx[0], 0);
+        assert_eq!(vm.pc, 0x202);
+
+        vm.low();
+
+        assert_eq!(vm.width(), 64);
+        assert_eq!(vm.height(), 32);
+        assert_eq!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn scd_n() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.opcode = 0x00C2;
+        vm.gfx[5] = 1;
+        vm.scd_n();
+
+        assert_eq!(vm.gfx[5], 0);
+        assert_eq!(vm.gfx[2 * 64 + 5], 1);
+        assert!(vm.draw_flag);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn scr() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.opcode = 0x00FB;
+        vm.gfx[64 + 1] = 1;
+        vm.gfx[64 + 63] = 1;
+        vm.scr();
+
+        assert_eq!(&vm.gfx[64..70], [0, 0, 0, 0, 0, 1]);
+        assert_eq!(vm.gfx[128], 0);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn scl() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.opcode = 0x00FC;
+        vm.gfx[64 + 5] = 1;
+        vm.gfx[64 + 2] = 1;
+        vm.scl();
+
+        assert_eq!(&vm.gfx[64..70], [0, 1, 0, 0, 0, 0]);
+        assert_eq!(vm.gfx[63], 0);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn exit() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.memory[0x200] = 0x00;
+        vm.memory[0x201] = 0xFD;
+        vm.emulate_cycle().unwrap();
+        vm.emulate_cycle().unwrap();
+
+        assert!(vm.halted());
+        assert_eq!(vm.pc, 0x200);
+    }
+
+    #[test]
+    fn drw_vx_vy_0_large_sprite() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.opcode = 0xDAB0;
+        vm.v[0xA] = 0x0;
+        vm.v[0xB] = 0x0;
+        vm.i = 0x300;
+        vm.memory[0x300] = 0x80;
+        vm.memory[0x301] = 0x01;
+        vm.memory[0x31F] = 0xFF;
+        vm.high();
+        vm.drw_vx_vy_n().unwrap();
+
+        assert_eq!(vm.gfx[0], 1);
+        assert_eq!(vm.gfx[15], 1);
+        assert_eq!(&vm.gfx[15 * 128 + 8..15 * 128 + 16], [1; 8]);
+        assert_eq!(vm.v[0xF], 0);
+    }
+
+    #[test]
+    fn ld_hf_vx() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.opcode = 0xFA30;
+        vm.v[0xA] = 0x2;
+        vm.ld_hf_vx();
+
+        assert_eq!(vm.i, 0x50 + 20);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_r_vx_ld_vx_r() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.opcode = 0xF275;
+        vm.v[0x0] = 0x1;
+        vm.v[0x1] = 0x2;
+        vm.v[0x2] = 0x3;
+        vm.v[0x3] = 0x4;
+        vm.ld_r_vx();
+        vm.v = [0; 16];
+        vm.opcode = 0xF385;
+        vm.ld_vx_r();
+
+        assert_eq!(&vm.v[0..4], [0x1, 0x2, 0x3, 0x0]);
+        assert_eq!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn chip8_rejects_super_chip_opcodes() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0xF2;
+        vm.memory[0x201] = 0x75;
+
+        match vm.emulate_cycle() {
+            Err(VmError::UnknownOpcode { opcode, .. }) => assert_eq!(opcode, 0xF275),
+            _ => panic!("expected an unknown opcode"),
+        }
+    }
+
+    #[test]
+    fn xo_chip_memory() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0xF055;
+        vm.i = 0xFFFF;
+        vm.v[0x0] = 0x2A;
+        vm.ld_i_vx().unwrap();
+
+        assert_eq!(vm.memory[0xFFFF], 0x2A);
+        assert_eq!(vm.memory_size(), 0x10000);
+        assert_eq!(VM::initialize(false).memory_size(), 0x1000);
+    }
+
+    #[test]
+    fn ld_i_long() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.memory[0x200] = 0xF0;
+        vm.memory[0x201] = 0x00;
+        vm.memory[0x202] = 0xAB;
+        vm.memory[0x203] = 0xCD;
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(vm.i, 0xABCD);
+        assert_eq!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn skip_long_load() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0x3A00;
+        vm.memory[0x202] = 0xF0;
+        vm.memory[0x203] = 0x00;
+        vm.se_vx_byte();
+
+        assert_eq!(vm.pc, 0x206);
+    }
+
+    #[test]
+    fn pc_wraps_at_top_of_memory() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.pc = 0xFFFE;
+        vm.memory[0xFFFE] = 0x60; // LD V0, 5
+        vm.memory[0xFFFF] = 0x05;
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(vm.v[0x0], 5);
+        assert_eq!(vm.pc, 0x0000);
+
+        vm.pc = 0xFFFE;
+        vm.memory[0xFFFE] = 0x30; // SE V0, 5
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(vm.pc, 0x0002);
+    }
+
+    #[test]
+    fn ret_wraps_at_top_of_memory() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.pc = 0xFFFE;
+        vm.memory[0xFFFE] = 0x23; // CALL 0x300
+        vm.memory[0xFFFF] = 0x00;
+        vm.memory[0x300] = 0x00; // RET
+        vm.memory[0x301] = 0xEE;
+        vm.emulate_cycle().unwrap();
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(vm.sp, 0);
+        assert_eq!(vm.pc, 0x0000);
+    }
+
+    #[test]
+    fn ld_i_vx_vy() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0x5312;
+        vm.v[0x1] = 0x1;
+        vm.v[0x2] = 0x2;
+        vm.v[0x3] = 0x3;
+        vm.i = 0x400;
+        vm.ld_i_vx_vy().unwrap();
+
+        assert_eq!(&vm.memory[0x400..0x403], [0x3, 0x2, 0x1]);
+        assert_eq!(vm.i, 0x400);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn ld_vx_vy_i() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0x5133;
+        vm.memory[0x400] = 0x1;
+        vm.memory[0x401] = 0x2;
+        vm.memory[0x402] = 0x3;
+        vm.i = 0x400;
+        vm.ld_vx_vy_i().unwrap();
+
+        assert_eq!(&vm.v[0x1..0x4], [0x1, 0x2, 0x3]);
+        assert_eq!(vm.i, 0x400);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn drw_vx_vy_n_both_planes() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0xF301;
+        vm.plane_n();
+        vm.opcode = 0xDAB1;
+        vm.v[0xA] = 0x0;
+        vm.v[0xB] = 0x0;
+        vm.i = 0x300;
+        vm.memory[0x300] = 0xC0;
+        vm.memory[0x301] = 0x60;
+        vm.drw_vx_vy_n().unwrap();
+
+        assert_eq!(&vm.gfx[0..4], [1, 3, 2, 0]);
+        assert_eq!(vm.v[0xF], 0);
+    }
+
+    #[test]
+    fn cls_selected_plane() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0xF201;
+        vm.plane_n();
+        vm.gfx[0] = 3;
+        vm.gfx[1] = 1;
+        vm.cls();
+
+        assert_eq!(&vm.gfx[0..2], [1, 1]);
+    }
+
+    #[test]
+    fn scu_n() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0x00D1;
+        vm.gfx[64 + 5] = 1;
+        vm.scu_n();
+
+        assert_eq!(vm.gfx[5], 1);
+        assert_eq!(vm.gfx[64 + 5], 0);
+        assert_eq!(vm.pc, 0x202);
+    }
+
+    #[test]
+    fn audio_pitch() {
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.opcode = 0xF002;
+        vm.i = 0x300;
+        vm.memory[0x300] = 0xF0;
+        vm.memory[0x30F] = 0x0F;
+        vm.audio().unwrap();
+
+        assert_eq!(vm.audio_pattern()[0], 0xF0);
+        assert_eq!(vm.audio_pattern()[15], 0x0F);
+        assert_eq!(vm.playback_rate(), 4000.0);
+
+        vm.opcode = 0xFA3A;
+        vm.v[0xA] = 112;
+        vm.pitch_vx();
+
+        assert_eq!(vm.playback_rate(), 8000.0);
+        assert_eq!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn quirks_for_mode() {
+        assert_eq!(VM::initialize(false).quirks(), Quirks::default());
+        assert_eq!(
+            VM::with_mode(Mode::SuperChip, false).quirks(),
+            Quirks::SCHIP
+        );
+        assert_eq!(VM::with_mode(Mode::XoChip, false).quirks(), Quirks::XO_CHIP);
+    }
+
+    #[test]
+    fn shr_vx_vy_shift_uses_vy() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.opcode = 0x8AB6;
+        vm.v[0xA] = 0xFF;
+        vm.v[0xB] = 0x0E;
+        vm.shr_vx_vy();
+
+        assert_eq!(vm.v[0xF], 0);
+        assert_eq!(vm.v[0xA], 0x07);
+    }
+
+    #[test]
+    fn shl_vx_vy_shift_uses_vy() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.opcode = 0x8ABE;
+        vm.v[0xA] = 0x00;
+        vm.v[0xB] = 0x81;
+        vm.shl_vx_vy();
+
+        assert_eq!(vm.v[0xF], 1);
+        assert_eq!(vm.v[0xA], 0x02);
+    }
+
+    #[test]
+    fn ld_i_vx_increments_i() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.opcode = 0xF355;
+        vm.i = 0x400;
+        vm.ld_i_vx().unwrap();
+
+        assert_eq!(vm.i, 0x404);
+    }
+
+    #[test]
+    fn ld_vx_i_increments_i() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.opcode = 0xF265;
+        vm.i = 0x400;
+        vm.ld_vx_i().unwrap();
+
+        assert_eq!(vm.i, 0x403);
+    }
+
+    #[test]
+    fn chip48_load_store_adds_x_to_i() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::CHIP_48);
+        vm.opcode = 0xF355;
+        vm.i = 0x400;
+        vm.ld_i_vx().unwrap();
+
+        assert_eq!(vm.i, 0x403);
+
+        vm.opcode = 0xF265;
+        vm.ld_vx_i().unwrap();
+
+        assert_eq!(vm.i, 0x405);
+    }
+
+    #[test]
+    fn drw_vx_vy_n_wraps() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0xDAB2;
+        vm.v[0xA] = 62;
+        vm.v[0xB] = 31;
+        vm.i = 0x300;
+        vm.memory[0x300] = 0xF0;
+        vm.memory[0x301] = 0xF0;
+        vm.drw_vx_vy_n().unwrap();
+
+        assert_eq!(&vm.gfx[31 * 64 + 62..31 * 64 + 64], [1, 1]);
+        assert_eq!(&vm.gfx[31 * 64..31 * 64 + 2], [1, 1]);
+        assert_eq!(&vm.gfx[0..2], [1, 1]);
+        assert_eq!(&vm.gfx[62..64], [1, 1]);
+    }
+
+    #[test]
+    fn drw_vx_vy_n_clips() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.opcode = 0xDAB2;
+        vm.v[0xA] = 62;
+        vm.v[0xB] = 31;
+        vm.i = 0x300;
+        vm.memory[0x300] = 0xF0;
+        vm.memory[0x301] = 0xF0;
+        vm.drw_vx_vy_n().unwrap();
+
+        assert_eq!(&vm.gfx[31 * 64 + 62..31 * 64 + 64], [1, 1]);
+        assert_eq!(&vm.gfx[31 * 64..31 * 64 + 2], [0, 0]);
+        assert_eq!(&vm.gfx[0..2], [0, 0]);
+        assert_eq!(&vm.gfx[62..64], [0, 0]);
+    }
+
+    #[test]
+    fn drw_vx_vy_n_wraps_start_position() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.opcode = 0xDAB1;
+        vm.v[0xA] = 66;
+        vm.v[0xB] = 33;
+        vm.i = 0x300;
+        vm.memory[0x300] = 0x80;
+        vm.drw_vx_vy_n().unwrap();
+
+        assert_eq!(vm.gfx[64 + 2], 1);
+    }
+
+    #[test]
+    fn or_vx_vy_resets_vf() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.opcode = 0x8AB1;
+        vm.v[0xF] = 0x1;
+        vm.or_vx_vy();
+
+        assert_eq!(vm.v[0xF], 0);
+    }
+
+    #[test]
+    fn jp_v0_addr_jump_uses_vx() {
+        let mut vm = VM::initialize(false);
+        vm.set_quirks(Quirks::SCHIP);
+        vm.opcode = 0xB300;
+        vm.v[0x0] = 0x2A;
+        vm.v[0x3] = 0x10;
+        vm.jp_v0_addr();
+
+        assert_eq!(vm.pc, 0x310);
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let mut vm = VM::with_mode(Mode::SuperChip, false);
+        vm.set_quirks(Quirks::COSMAC_VIP);
+        vm.set_clock_speed(1000);
+        vm.memory[0x200] = 0x00;
+        vm.memory[0x201] = 0xFF;
+        vm.memory[0x202] = 0x2A;
+        vm.memory[0x203] = 0xBC;
+        vm.v[0x3] = 0x42;
+        vm.i = 0x123;
+        vm.delay_timer = 0x7;
+        vm.key[0xA] = true;
+        vm.emulate_cycle().unwrap();
+        vm.emulate_cycle().unwrap();
+        vm.gfx[100] = 1;
+
+        let state = vm.save_state();
+        let mut restored = VM::initialize(false);
+        restored.load_state(&state).unwrap();
+
+        assert_eq!(restored.mode(), Mode::SuperChip);
+        assert_eq!(restored.quirks(), Quirks::COSMAC_VIP);
+        assert_eq!(restored.clock_speed(), 1000);
+        assert_eq!(&restored.memory[..], &vm.memory[..]);
+        assert_eq!(restored.v, vm.v);
+        assert_eq!(restored.i, 0x123);
+        assert_eq!(restored.pc, 0xABC);
+        assert_eq!(restored.sp, 1);
+        assert_eq!(restored.stack[0], 0x202);
+        assert_eq!(restored.delay_timer, 0x7);
+        assert!(restored.key[0xA]);
+        assert!(restored.hires);
+        assert_eq!(restored.gfx[100], 1);
+        assert_eq!(restored.save_state(), state);
+
+        vm.opcode = 0xF00A;
+        vm.key[0x4] = false;
+        vm.ld_vx_k();
+        vm.key[0x4] = true;
+        vm.ld_vx_k();
+        restored.load_state(&vm.save_state()).unwrap();
+
+        assert_eq!(restored.key_wait, Some(KeyWait::Release(0x4)));
+    }
+
+    #[test]
+    fn save_state_keeps_random_sequence() {
+        let mut vm = VM::initialize(false);
+        vm.opcode = 0xC0FF;
+        let state = vm.save_state();
+        let values = |vm: &mut VM| {
+            (0..8)
+                .map(|_| {
+                    vm.rnd_vx_byte();
+                    vm.v[0x0]
+                })
+                .collect::<Vec<u8>>()
+        };
+        let expected = values(&mut vm);
+
+        vm.load_state(&state).unwrap();
+
+        assert_eq!(values(&mut vm), expected);
+    }
+
+    #[test]
+    fn load_state_keeps_random_source() {
+        let mut vm = VM::initialize(false);
+        vm.set_random_source(Box::new(Sequence::new(&[1, 2, 3])));
+        vm.opcode = 0xC0FF;
+        vm.rnd_vx_byte();
+        let state = vm.save_state();
+        vm.rnd_vx_byte();
+        vm.load_state(&state).unwrap();
+        vm.rnd_vx_byte();
+
+        assert_eq!(vm.v[0x0], 2);
+    }
+
+    #[test]
+    fn load_state_corrupted() {
+        let mut vm = VM::initialize(false);
+        vm.v[0x1] = 0x1;
+        let mut state = vm.save_state();
+        state[20] ^= 0xFF;
+
+        let mut restored = VM::initialize(false);
+        restored.v[0x1] = 0x2;
+
+        assert_eq!(
+            restored.load_state(&state).err(),
+            Some(StateError::ChecksumMismatch)
+        );
+        assert_eq!(restored.v[0x1], 0x2);
+    }
+
+    #[test]
+    fn load_state_out_of_range() {
+        let invalid = |change: &dyn Fn(&mut VM)| {
+            let mut vm = VM::initialize(false);
+            change(&mut vm);
+            let state = vm.save_state();
+
+            VM::initialize(false).load_state(&state).err()
+        };
+
+        assert_eq!(
+            invalid(&|vm| vm.stack[0] = 0xFFFF),
+            Some(StateError::InvalidValue("stack"))
+        );
+        assert_eq!(
+            invalid(&|vm| vm.pc = 0x1000),
+            Some(StateError::InvalidValue("program counter"))
+        );
+        assert_eq!(
+            invalid(&|vm| vm.i = 0x1000),
+            Some(StateError::InvalidValue("I"))
+        );
+        assert_eq!(
+            invalid(&|vm| vm.plane = 0x3),
+            Some(StateError::InvalidValue("plane"))
+        );
+
+        let mut vm = VM::with_mode(Mode::XoChip, false);
+        vm.pc = 0xFFFE;
+        vm.stack[0] = 0xFFFE;
+        vm.plane = 0x3;
+        let state = vm.save_state();
+
+        assert_eq!(VM::initialize(false).load_state(&state), Ok(()));
+    }
+
+    #[test]
+    fn memory_watchpoint() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0xA3; // LD I, 0x300
+        vm.memory[0x201] = 0x00;
+        vm.memory[0x202] = 0xF1; // LD [I], V1
+        vm.memory[0x203] = 0x55;
+        vm.add_watchpoint(Watchpoint::Memory {
+            address: 0x301,
+            length: 2,
+        });
+
+        assert!(vm.run_frame_until(|_| false).unwrap());
+        assert_eq!(
+            vm.watch_hit(),
+            Some(&WatchHit {
+                watchpoint: Watchpoint::Memory {
+                    address: 0x301,
+                    length: 2
+                },
+                access: Access::Write(0x301),
+                opcode: 0xF155,
+                pc: 0x202,
+            })
+        );
+        assert_eq!(vm.pc, 0x204);
+    }
+
+    #[test]
+    fn register_watchpoint() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0x61; // LD V1, 0x00
+        vm.memory[0x201] = 0x00;
+        vm.memory[0x202] = 0x61; // LD V1, 0x05
+        vm.memory[0x203] = 0x05;
+        vm.add_watchpoint(Watchpoint::Register(0x1));
+
+        vm.emulate_cycle().unwrap();
+        assert_eq!(vm.watch_hit(), None);
+
+        vm.emulate_cycle().unwrap();
+        assert_eq!(
+            vm.watch_hit().map(|hit| hit.access),
+            Some(Access::Change(0, 5))
+        );
+        assert_eq!(vm.watch_hit().map(|hit| hit.pc), Some(0x202));
+    }
+
+    #[test]
+    fn watchpoints_survive_load_state() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0x61; // LD V1, 0x05
+        vm.memory[0x201] = 0x05;
+        let state = vm.save_state();
+        vm.add_watchpoint(Watchpoint::Register(0x1));
+
+        vm.load_state(&state).unwrap();
+        vm.emulate_cycle().unwrap();
+
+        assert_eq!(vm.watchpoints(), &[Watchpoint::Register(0x1)]);
+        assert_eq!(
+            vm.watch_hit().map(|hit| hit.access),
+            Some(Access::Change(0, 5))
+        );
+    }
+
+    #[test]
+    fn instruction_fetch_does_not_trigger_watchpoints() {
+        let mut vm = VM::initialize(false);
+        vm.memory[0x200] = 0x12; // JP 0x200
+        vm.memory[0x201] = 0x00;
+        vm.add_watchpoint(Watchpoint::Memory {
+            address: 0x200,
+            length: 2,
+        });
+
+        assert!(!vm.run_frame_until(|_| false).unwrap());
+        assert!(vm.remove_watchpoint(Watchpoint::Memory {
+            address: 0x200,
+            length: 2,
+        }));
+        assert!(vm.watchpoints().is_empty());
+    }
 }
@@ -0,0 +1,73 @@
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Watchpoint {
+    Memory { address: usize, length: usize }, // Any read or write in the range
+    Register(usize),                          // Changes to a V register
+    I,                                        // Changes to the index register
+    DelayTimer,                               // Changes to the delay timer by an instruction
+    SoundTimer,                               // Changes to the sound timer by an instruction
+}
+
+impl Watchpoint {
+    pub fn contains(&self, target: usize) -> bool {
+        match *self {
+            Watchpoint::Memory { address, length } => {
+                target >= address && target - address < length
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Watchpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Watchpoint::Memory { address, length: 1 } => write!(f, "0x{:03X}", address),
+            Watchpoint::Memory { address, length } => {
+                write!(
+                    f,
+                    "0x{:03X}-0x{:03X}",
+                    address,
+                    address.saturating_add(length - 1)
+                )
+            }
+            Watchpoint::Register(x) => write!(f, "V{:X}", x),
+            Watchpoint::I => write!(f, "I"),
+            Watchpoint::DelayTimer => write!(f, "DT"),
+            Watchpoint::SoundTimer => write!(f, "ST"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Access {
+    Read(usize),      // Memory address read
+    Write(usize),     // Memory address written
+    Change(u16, u16), // Register value before and after the instruction
+}
+
+// First watchpoint triggered by the last executed instruction
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub access: Access,
+    pub opcode: u16,
+    pub pc: u16,
+}
+
+impl fmt::Display for WatchHit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.access {
+            Access::Read(address) => write!(f, "0x{:03X} read", address)?,
+            Access::Write(address) => write!(f, "0x{:03X} written", address)?,
+            Access::Change(old, new) => write!(
+                f,
+                "{} changed from 0x{:X} to 0x{:X}",
+                self.watchpoint, old, new
+            )?,
+        }
+
+        write!(f, " by {:04X} at 0x{:03X}", self.opcode, self.pc)
+    }
+}
@@ -1,31 +1,23 @@
 import { memory } from './emuchip_8_bg';
-import { Emulator } from './emuchip_8';
+import { Emulator, disassemble } from './emuchip_8';
 
-const HEIGHT = 32;
-const WIDTH = 64;
+const MAX_HEIGHT = 64;
+const MAX_WIDTH = 128;
+const SCALE = 10;
+const AUDIO_LATENCY = 0.05; // Seconds of sound queued ahead to absorb frame jitter
+const PALETTE = ['rgb(0,0,0)', 'rgb(255,255,255)', 'rgb(170,170,170)', 'rgb(85,85,85)'];
 const loadGame = document.querySelector('#load-game');
+const modeSelect = document.querySelector('#mode');
+const quirksSelect = document.querySelector('#quirks');
+const volumeInput = document.querySelector('#volume');
+const frequencyInput = document.querySelector('#frequency');
+const muteInput = document.querySelector('#mute');
+const keymapSelect = document.querySelector('#keymap');
 const canvas = document.querySelector('#screen');
+const status = document.querySelector('#status');
+const listing = document.querySelector('#listing');
 const ctx = canvas.getContext('2d');
 
-const keyboardMapping = {
-  '1': 0x1,
-  '2': 0x2,
-  '3': 0x3,
-  '4': 0xc,
-  q: 0x4,
-  w: 0x5,
-  e: 0x6,
-  r: 0xd,
-  a: 0x7,
-  s: 0x8,
-  d: 0x9,
-  f: 0xe,
-  z: 0xa,
-  x: 0x0,
-  c: 0xb,
-  v: 0xf
-};
-
 const gamesList = [
   '15PUZZLE',
   'BLINKY',
@@ -54,19 +46,20 @@ const gamesList = [
 
 const emu = Emulator.new();
 
-const sharedMemoryBuffer = new Uint8Array(
-  memory.buffer,
-  emu.get_memory(),
-  4096
-);
+// Growing the wasm memory (rewind history, audio samples) detaches views of the old buffer
+let sharedDisplayBuffer = null;
 
-const sharedDisplayBuffer = new Uint8Array(
-  memory.buffer,
-  emu.get_gfx(),
-  WIDTH * HEIGHT
-);
+const displayBuffer = () => {
+  if (!sharedDisplayBuffer || sharedDisplayBuffer.buffer !== memory.buffer) {
+    sharedDisplayBuffer = new Uint8Array(
+      memory.buffer,
+      emu.get_gfx(),
+      MAX_WIDTH * MAX_HEIGHT
+    );
+  }
 
-const sharedKeysBuffer = new Uint8Array(memory.buffer, emu.get_keys(), 16);
+  return sharedDisplayBuffer;
+};
 
 const loadGames = () => {
   gamesList.forEach(game => {
@@ -78,8 +71,8 @@ const loadGames = () => {
 };
 
 const initVM = () => {
-  canvas.height = HEIGHT * 10;
-  canvas.width = WIDTH * 10;
+  canvas.height = (MAX_HEIGHT / 2) * SCALE;
+  canvas.width = (MAX_WIDTH / 2) * SCALE;
   ctx.fillStyle = 'rgb(0, 0, 0)';
   ctx.fillRect(0, 0, canvas.width, canvas.height);
   emu.load_fontset();
@@ -89,50 +82,169 @@ const fetchGame = async game =>
   fetch(`roms/${game.toUpperCase()}`)
     .then(res => res.arrayBuffer())
     .then(buffer => {
-      const rom = new DataView(buffer, 0, buffer.byteLength);
-
-      for (let i = 0; i < buffer.byteLength; i++) {
-        sharedMemoryBuffer[0x200 + i] = rom.getUint8(i);
-      }
+      const rom = new Uint8Array(buffer);
+      emu.load_rom(rom);
+      listing.textContent = disassemble(rom);
     });
 
+// Keymaps use the same JSON format as the native frontends' --keymap file
+let keymapConfig = {};
+
+const fetchKeymap = async () =>
+  fetch('keymap.json')
+    .then(res => res.json())
+    .then(config => {
+      keymapConfig = config;
+    })
+    .catch(error => console.error(error));
+
+const updateKeymap = () => {
+  const config = keymapSelect.value
+    ? Object.assign({}, keymapConfig, { preset: keymapSelect.value })
+    : keymapConfig;
+
+  try {
+    emu.set_keymap(JSON.stringify(config), loadGame.value);
+  } catch (error) {
+    console.error(error);
+  }
+};
+
+// Quirks files use the same JSON format as the native frontends' --quirks file
+let quirksConfig = {};
+
+const fetchQuirks = async () =>
+  fetch('quirks.json')
+    .then(res => res.json())
+    .then(config => {
+      quirksConfig = config;
+    })
+    .catch(error => console.error(error));
+
+const updateQuirks = () => {
+  const config = quirksSelect.value
+    ? Object.assign({}, quirksConfig, { preset: quirksSelect.value })
+    : quirksConfig;
+
+  try {
+    emu.set_quirks_config(JSON.stringify(config), loadGame.value);
+  } catch (error) {
+    console.error(error);
+  }
+};
+
+// Save states are kept per game for the lifetime of the page
+const savedStates = {};
+let rewinding = false;
+
 const handleKeyDown = keyboard => {
-  if (keyboard.key in keyboardMapping) {
-    sharedKeysBuffer[keyboardMapping[keyboard.key]] = true;
+  if (keyboard.key === 'Backspace') {
+    keyboard.preventDefault();
+    rewinding = true;
+  }
+
+  if (keyboard.key === 'F5') {
+    keyboard.preventDefault();
+    savedStates[loadGame.value] = emu.save_state();
+  }
+
+  if (keyboard.key === 'F9' && savedStates[loadGame.value]) {
+    keyboard.preventDefault();
+    emu.load_state(savedStates[loadGame.value]);
+  }
+
+  // Bound keys do not scroll the page or type into the controls
+  if (emu.key_down(keyboard.key)) {
+    keyboard.preventDefault();
   }
 };
 
 const handleKeyUp = keyboard => {
-  if (keyboard.key in keyboardMapping) {
-    sharedKeysBuffer[keyboardMapping[keyboard.key]] = false;
+  if (keyboard.key === 'Backspace') {
+    rewinding = false;
+  }
+
+  if (emu.key_up(keyboard.key)) {
+    keyboard.preventDefault();
   }
 };
 
 const drawGraphic = () => {
-  for (let i = 0; i < WIDTH * HEIGHT; i++) {
-    const x = (i % 64) * 10;
-    const y = Math.floor(i / 64) * 10;
+  const width = emu.width();
+  const height = emu.height();
+  const scale = canvas.width / width;
+  const display = displayBuffer();
 
-    ctx.fillStyle = 'rgb(0,0,0)';
+  for (let i = 0; i < width * height; i++) {
+    const x = (i % width) * scale;
+    const y = Math.floor(i / width) * scale;
 
-    if (sharedDisplayBuffer[i] === 1) {
-      ctx.fillStyle = 'rgb(255,255,255)';
-    }
+    ctx.fillStyle = PALETTE[display[i] & 0x3];
+    ctx.fillRect(x, y, scale, scale);
+  }
+};
+
+// Browsers only allow audio after a user action, so this is created on the first game load
+let audioContext = null;
+let nextSoundTime = 0;
 
-    ctx.fillRect(x, y, 10, 10);
+const updateAudioSettings = () => {
+  emu.set_volume(volumeInput.value / 100);
+  emu.set_tone_frequency(Number(frequencyInput.value) || 440);
+  emu.set_muted(muteInput.checked);
+};
+
+const playSound = () => {
+  if (!audioContext || !emu.sound_active()) {
+    nextSoundTime = 0;
+    return;
+  }
+
+  const now = audioContext.currentTime;
+
+  // Drop the frame when the queue is already full, e.g. on displays faster than 60 Hz
+  if (nextSoundTime > now + 2 * AUDIO_LATENCY) {
+    return;
   }
+
+  const samples = emu.audio_frame(audioContext.sampleRate);
+  const buffer = audioContext.createBuffer(1, samples.length, audioContext.sampleRate);
+  const source = audioContext.createBufferSource();
+
+  buffer.copyToChannel(samples, 0);
+  source.buffer = buffer;
+  source.connect(audioContext.destination);
+
+  // Frames are queued back to back so the tone has no gaps
+  nextSoundTime = Math.max(nextSoundTime, now + AUDIO_LATENCY);
+  source.start(nextSoundTime);
+  nextSoundTime += buffer.duration;
 };
 
 let running = false;
 const runningLoop = () => {
   if (running) {
-    for (let i = 0; i < 8; i++) {
-      emu.tick();
+    try {
+      if (rewinding) {
+        emu.rewind();
+      } else {
+        emu.run_frame();
+      }
+    } catch (error) {
+      running = false;
+      console.error(error);
     }
 
     if (emu.draw_flag) {
       drawGraphic();
     }
+
+    playSound();
+    status.textContent = emu.waiting_for_key() ? 'Press a key to continue' : '';
+
+    if (emu.halted()) {
+      running = false;
+    }
   }
 
   requestAnimationFrame(runningLoop);
@@ -141,15 +253,37 @@ const runningLoop = () => {
 loadGame.addEventListener('change', async e => {
   e.target.blur();
   running = false;
-  emu.reset();
+  emu.set_mode(modeSelect.value);
+  updateQuirks();
+
+  if (!audioContext) {
+    audioContext = new AudioContext();
+  }
+
+  updateAudioSettings();
+  updateKeymap();
   await fetchGame(e.target.value);
   initVM();
   running = true;
 });
 
+keymapSelect.addEventListener('change', e => {
+  e.target.blur();
+  updateKeymap();
+});
+
+[volumeInput, frequencyInput, muteInput].forEach(input =>
+  input.addEventListener('change', e => {
+    e.target.blur();
+    updateAudioSettings();
+  })
+);
+
 document.addEventListener('keydown', e => handleKeyDown(e));
 document.addEventListener('keyup', e => handleKeyUp(e));
 
 loadGames();
+fetchKeymap().then(updateKeymap);
+fetchQuirks();
 initVM();
 requestAnimationFrame(runningLoop);